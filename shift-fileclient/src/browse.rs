@@ -0,0 +1,151 @@
+use super::metadata::{file_info, is_symlink};
+use anyhow::{anyhow, Result};
+use shift::api;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Most entries sent in one `DirectoryListing`, whatever the peer asks for
+pub const MAX_LISTING_ENTRIES: usize = 1000;
+
+fn outside(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is outside of the shared directory", path),
+    )
+}
+
+pub fn is_relative_and_down(path: &Path) -> bool {
+    path.components()
+        .all(|x| matches!(x, Component::Normal(_) | Component::CurDir))
+}
+
+/// Resolves a path the peer asked about, which must stay within `root`
+fn resolve(root: &Path, requested: &str) -> Result<PathBuf> {
+    let path = Path::new(requested);
+    if !is_relative_and_down(path) {
+        return Err(outside(requested).into());
+    }
+    let root = root.canonicalize()?;
+    let full_path = root.join(path);
+    // Links may point anywhere, so check where the parent really is. The entry itself is not
+    // resolved, links are reported as such.
+    let parent = match full_path.parent() {
+        Some(parent) if full_path != root => parent.canonicalize()?,
+        _ => return Ok(root),
+    };
+    if !parent.starts_with(&root) {
+        return Err(outside(requested).into());
+    }
+    Ok(full_path)
+}
+
+fn entry_info(name: String, path: &Path) -> Result<api::FileInfo> {
+    let mut info = file_info(name, &path.symlink_metadata()?);
+    if is_symlink(&info) {
+        info.link_target = std::fs::read_link(path)?.to_string_lossy().to_string();
+    }
+    Ok(info)
+}
+
+pub fn list_directory(root: &Path, request: &api::ListDirectory) -> Result<api::DirectoryListing> {
+    let path = resolve(root, &request.path)?;
+    // Unlike stat, listing follows a link to a directory, which must stay within `root` too
+    if !path.canonicalize()?.starts_with(root.canonicalize()?) {
+        return Err(outside(&request.path).into());
+    }
+    let mut names = path
+        .read_dir()?
+        .map(|x| Ok(x?.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    let limit = match request.limit as usize {
+        0 => MAX_LISTING_ENTRIES,
+        limit => limit.min(MAX_LISTING_ENTRIES),
+    };
+    let offset = request.offset.min(names.len() as u64) as usize;
+    let entries = names[offset..]
+        .iter()
+        .take(limit)
+        .map(|x| entry_info(x.to_string_lossy().to_string(), &path.join(x)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(api::DirectoryListing {
+        request_id: request.request_id,
+        offset: offset as u64,
+        more: offset + entries.len() < names.len(),
+        entries,
+    })
+}
+
+pub fn stat(root: &Path, request: &api::Stat) -> Result<api::StatResult> {
+    let path = resolve(root, &request.path)?;
+    Ok(api::StatResult {
+        request_id: request.request_id,
+        file_info: Some(entry_info(request.path.clone(), &path)?),
+    })
+}
+
+/// Resolves the paths and patterns of a `ReceiveRequest` to what they name within `root`, as
+/// sorted paths relative to it
+pub fn resolve_selection(root: &Path, request: &api::ReceiveRequest) -> Result<Vec<PathBuf>> {
+    let root = root.canonicalize()?;
+    let mut selected = BTreeSet::new();
+    for path in &request.paths {
+        let full_path = resolve(&root, path)?;
+        full_path.symlink_metadata()?;
+        selected.insert(full_path);
+    }
+    for pattern in &request.patterns {
+        if !is_relative_and_down(Path::new(pattern)) {
+            return Err(outside(pattern).into());
+        }
+        let root_pattern = glob::Pattern::escape(
+            root.to_str()
+                .ok_or_else(|| anyhow!("Shared directory is not valid UTF-8"))?,
+        );
+        let matches = glob::glob(&format!("{}/{}", root_pattern, pattern))
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x.to_string()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Nothing matches {}", pattern),
+            )
+            .into());
+        }
+        for path in matches {
+            let relative = path.strip_prefix(&root)?.to_string_lossy().to_string();
+            // The pattern may have gone through a link
+            selected.insert(resolve(&root, &relative)?);
+        }
+    }
+    Ok(selected
+        .into_iter()
+        .map(|x| x.strip_prefix(&root).map(Path::to_path_buf))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Resolves where the sender of a transfer suggested putting it, which must stay within `root`.
+/// Directories that do not exist yet are left for the transfer to create.
+pub fn resolve_destination(root: &Path, destination: &str) -> Result<PathBuf> {
+    let outside = || {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is outside of the receiving directory", destination),
+        )
+    };
+    if !is_relative_and_down(Path::new(destination)) {
+        return Err(outside().into());
+    }
+    let root = root.canonicalize()?;
+    let full_path = root.join(destination);
+    // Links on the way may point anywhere
+    let mut existing = full_path.as_path();
+    while !existing.exists() {
+        existing = existing.parent().ok_or_else(outside)?;
+    }
+    if !existing.canonicalize()?.starts_with(&root) {
+        return Err(outside().into());
+    }
+    Ok(full_path)
+}
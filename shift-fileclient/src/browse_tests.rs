@@ -0,0 +1,127 @@
+#[cfg(test)]
+use super::browse::{list_directory, resolve_destination, resolve_selection, stat};
+#[cfg(test)]
+use shift::api;
+#[cfg(test)]
+use std::path::PathBuf;
+
+#[test]
+fn test_list_directory_pages() {
+    let root = std::env::temp_dir().join(format!("shift-browse-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    for name in ["c", "a", "b"] {
+        std::fs::write(root.join("dir").join(name), name).unwrap();
+    }
+
+    let request = api::ListDirectory {
+        path: "dir".to_string(),
+        limit: 2,
+        ..Default::default()
+    };
+    let listing = list_directory(&root, &request).unwrap();
+    let names: Vec<_> = listing.entries.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, ["a", "b"]);
+    assert!(listing.more);
+
+    let listing = list_directory(
+        &root,
+        &api::ListDirectory {
+            offset: 2,
+            ..request
+        },
+    )
+    .unwrap();
+    assert_eq!(listing.entries.len(), 1);
+    assert_eq!(listing.entries[0].name, "c");
+    assert_eq!(listing.entries[0].size, 1);
+    assert!(!listing.more);
+
+    let top = list_directory(&root, &api::ListDirectory::default()).unwrap();
+    assert_eq!(top.entries.len(), 1);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_browsing_stays_in_root() {
+    let root = std::env::temp_dir().join(format!("shift-browse-root-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("shared")).unwrap();
+    std::fs::write(root.join("secret"), "").unwrap();
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::symlink("..", root.join("shared/up")).unwrap();
+    let shared = root.join("shared");
+
+    let request = |path: &str| api::Stat {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    assert!(stat(&shared, &request("../secret")).is_err());
+    assert!(stat(&shared, &request("/etc/passwd")).is_err());
+    assert!(stat(&shared, &request("missing")).is_err());
+    #[cfg(target_family = "unix")]
+    {
+        assert!(stat(&shared, &request("up")).is_ok());
+        assert!(stat(&shared, &request("up/secret")).is_err());
+        let listing = api::ListDirectory {
+            path: "up".to_string(),
+            ..Default::default()
+        };
+        assert!(list_directory(&shared, &listing).is_err());
+    }
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_resolve_selection() {
+    let root = std::env::temp_dir().join(format!("shift-select-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("logs/old")).unwrap();
+    for name in ["a.txt", "logs/b.log", "logs/c.log", "logs/old/d.log"] {
+        std::fs::write(root.join(name), name).unwrap();
+    }
+
+    let request = |paths: &[&str], patterns: &[&str]| api::ReceiveRequest {
+        paths: paths.iter().map(|x| x.to_string()).collect(),
+        patterns: patterns.iter().map(|x| x.to_string()).collect(),
+        ..Default::default()
+    };
+    let selected =
+        resolve_selection(&root, &request(&["a.txt"], &["logs/*.log", "*.txt"])).unwrap();
+    assert_eq!(
+        selected,
+        [
+            PathBuf::from("a.txt"),
+            PathBuf::from("logs/b.log"),
+            PathBuf::from("logs/c.log")
+        ]
+    );
+    assert!(resolve_selection(&root, &request(&["missing"], &[])).is_err());
+    assert!(resolve_selection(&root, &request(&[], &["*.missing"])).is_err());
+    assert!(resolve_selection(&root, &request(&[], &["../*"])).is_err());
+    assert!(resolve_selection(&root, &request(&["../a.txt"], &[])).is_err());
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_resolve_destination() {
+    let root = std::env::temp_dir().join(format!("shift-destination-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("projects")).unwrap();
+    let canonical = root.canonicalize().unwrap();
+
+    assert_eq!(resolve_destination(&root, "").unwrap(), canonical);
+    assert_eq!(
+        resolve_destination(&root, "projects/foo").unwrap(),
+        canonical.join("projects/foo")
+    );
+    assert!(resolve_destination(&root, "../foo").is_err());
+    assert!(resolve_destination(&root, "/tmp").is_err());
+
+    #[cfg(target_family = "unix")]
+    {
+        std::os::unix::fs::symlink(std::env::temp_dir(), root.join("link")).unwrap();
+        assert!(resolve_destination(&root, "link/foo").is_err());
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,349 @@
+#[cfg(test)]
+use super::{check_inbound_names, DeltaTarget, ShiftFileClient, ShiftFileClientDelegate};
+#[cfg(test)]
+use anyhow::Result;
+#[cfg(test)]
+use cancellation::CancellationTokenSource;
+#[cfg(test)]
+use path_clean::PathClean;
+#[cfg(test)]
+use shift::{api, DisconnectReason, Timeouts};
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::io::{self, Read, Write};
+#[cfg(test)]
+use std::os::unix::net::UnixStream;
+#[cfg(test)]
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+#[cfg(test)]
+use std::sync::{mpsc, Arc};
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Stops moving data in both directions once `budget` bytes were read, like a stalled link
+#[cfg(test)]
+#[derive(Clone)]
+struct Stall(Arc<AtomicI64>);
+
+#[cfg(test)]
+impl Stall {
+    fn after(budget: i64) -> Self {
+        Stall(Arc::new(AtomicI64::new(budget)))
+    }
+
+    fn wait(&self) {
+        while self.0.load(Ordering::Relaxed) <= 0 {
+            std::thread::park();
+        }
+    }
+}
+
+#[cfg(test)]
+struct StallingStream(UnixStream, Stall);
+
+#[cfg(test)]
+impl Read for StallingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.1.wait();
+        let length = self.0.read(buf)?;
+        self.1 .0.fetch_sub(length as i64, Ordering::Relaxed);
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+impl Write for StallingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.wait();
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Sends a single file once connected, then disconnects
+#[cfg(test)]
+struct SendingDelegate {
+    path: Option<PathBuf>,
+    sent: Arc<AtomicU64>,
+    disconnected: Option<DisconnectReason>,
+    warnings: Vec<String>,
+}
+
+#[cfg(test)]
+impl<'a> ShiftFileClientDelegate<'a> for SendingDelegate {
+    fn on_idle(&mut self, client: &mut ShiftFileClient<'a>) -> Result<()> {
+        match self.path.take() {
+            Some(path) => {
+                let sent = self.sent.clone();
+                client.send(
+                    &path,
+                    Box::new(move |_, bytes, _| sent.store(bytes, Ordering::Relaxed)),
+                )?;
+            }
+            None => client.disconnect()?,
+        }
+        Ok(())
+    }
+
+    fn on_warning(&mut self, message: &str) {
+        self.warnings.push(message.to_string());
+    }
+
+    fn on_disconnected(&mut self, reason: &DisconnectReason) {
+        self.disconnected = Some(reason.clone());
+    }
+}
+
+/// Accepts every transfer into `destination`
+#[cfg(test)]
+struct ReceivingDelegate {
+    destination: PathBuf,
+    transfers: HashMap<u32, String>,
+}
+
+#[cfg(test)]
+impl<'a> ShiftFileClientDelegate<'a> for ReceivingDelegate {
+    fn on_inbound_transfer_request(&mut self, request: &api::SendRequest) -> bool {
+        let name = request.file_info.as_ref().unwrap().name.clone();
+        self.transfers.insert(request.transfer_id, name);
+        true
+    }
+
+    fn on_inbound_transfer_file(&mut self, file: &api::OpenFile) -> Result<Option<PathBuf>> {
+        let name = &file.file_info.as_ref().unwrap().name;
+        Ok(Some(
+            self.destination
+                .join(&self.transfers[&file.transfer_id])
+                .join(name)
+                .clean(),
+        ))
+    }
+}
+
+/// How the sending side of [`send_file`] ended
+#[cfg(test)]
+struct Outcome {
+    result: Result<()>,
+    disconnected: Option<DisconnectReason>,
+    sent: u64,
+    warnings: Vec<String>,
+}
+
+/// Sends `path` from one client to another that receives into `destination`, over a link that
+/// stalls once `stall` runs out. Returns once both sides are done, or `None` if either hangs.
+#[cfg(test)]
+fn send_file(path: &Path, destination: &Path, stall: Stall, timeouts: Timeouts) -> Option<Outcome> {
+    let (a, b) = UnixStream::pair().unwrap();
+    let (receiver_tx, receiver_rx) = mpsc::channel();
+    std::thread::spawn({
+        let output = StallingStream(b.try_clone().unwrap(), stall.clone());
+        let mut delegate = ReceivingDelegate {
+            destination: destination.to_path_buf(),
+            transfers: HashMap::new(),
+        };
+        let timeouts = timeouts.clone();
+        move || {
+            let mut receiver = ShiftFileClient::new(Box::new(output), None);
+            receiver.set_timeouts(timeouts);
+            let token = CancellationTokenSource::new();
+            let input = StallingStream(b, stall);
+            let _ = receiver.run(false, input, &mut delegate, token.token());
+            let _ = receiver_tx.send(());
+        }
+    });
+
+    let (sender_tx, sender_rx) = mpsc::channel();
+    std::thread::spawn({
+        let mut delegate = SendingDelegate {
+            path: Some(path.to_path_buf()),
+            sent: Arc::new(AtomicU64::new(0)),
+            disconnected: None,
+            warnings: vec![],
+        };
+        move || {
+            let mut sender = ShiftFileClient::new(Box::new(a.try_clone().unwrap()), None);
+            sender.set_timeouts(timeouts);
+            let token = CancellationTokenSource::new();
+            let result = sender.run(true, a, &mut delegate, token.token());
+            let _ = sender_tx.send(Outcome {
+                result,
+                disconnected: delegate.disconnected,
+                sent: delegate.sent.load(Ordering::Relaxed),
+                warnings: delegate.warnings,
+            });
+        }
+    });
+
+    let timeout = Duration::from_secs(30);
+    let outcome = sender_rx.recv_timeout(timeout).ok()?;
+    receiver_rx.recv_timeout(timeout).ok()?;
+    Some(outcome)
+}
+
+/// Fills a file with `size` bytes that do not compress
+#[cfg(test)]
+fn noise_file(path: &Path, size: usize, seed: u64) {
+    let mut state = seed;
+    let data: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    std::fs::write(path, data).unwrap();
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("shift-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(root.join("in")).unwrap();
+    std::fs::create_dir_all(root.join("out")).unwrap();
+    root
+}
+
+#[test]
+fn test_run_returns_on_stalled_link() {
+    let root = test_dir("stall");
+    let path = root.join("in/file");
+    let size = 16 * 1024 * 1024;
+    noise_file(&path, size, 0x2545f4914f6cdd1d);
+
+    let timeouts = Timeouts {
+        keepalive_interval: Duration::from_millis(100),
+        idle: Some(Duration::from_secs(1)),
+        transfer: None,
+    };
+    let stall = Stall::after(2 * 1024 * 1024);
+    let outcome = send_file(&path, &root.join("out"), stall, timeouts).expect("run did not return");
+    assert!(outcome.result.is_ok());
+    assert_eq!(outcome.disconnected, Some(DisconnectReason::IdleTimeout));
+    assert!(
+        outcome.sent > 0 && outcome.sent < size as u64,
+        "{} bytes sent",
+        outcome.sent
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_resume_mismatch_restarts_file() {
+    let root = test_dir("restart");
+    let path = root.join("in/file");
+    noise_file(&path, 256 * 1024, 0x2545f4914f6cdd1d);
+    // Not a prefix of the file being sent, and too short for a delta transfer
+    noise_file(&root.join("out/file"), 1000, 0x9e3779b97f4a7c15);
+
+    let stall = Stall::after(i64::MAX);
+    let outcome = send_file(&path, &root.join("out"), stall, Timeouts::default())
+        .expect("run did not return");
+    assert!(outcome.result.is_ok());
+    assert_eq!(outcome.disconnected, Some(DisconnectReason::Local));
+    assert!(outcome
+        .warnings
+        .iter()
+        .any(|x| x.contains("differs at the destination")));
+    assert_eq!(
+        std::fs::read(root.join("out/file")).unwrap(),
+        std::fs::read(&path).unwrap()
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_received_file_keeps_times_and_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = test_dir("metadata");
+    let path = root.join("in/file");
+    noise_file(&path, 64 * 1024, 0x2545f4914f6cdd1d);
+    let modified = UNIX_EPOCH - Duration::from_millis(1500);
+    let accessed = UNIX_EPOCH + Duration::from_nanos(1_600_000_000_123_456_789);
+    let times = std::fs::FileTimes::new()
+        .set_modified(modified)
+        .set_accessed(accessed);
+    std::fs::File::open(&path)
+        .unwrap()
+        .set_times(times)
+        .unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+
+    let stall = Stall::after(i64::MAX);
+    let outcome = send_file(&path, &root.join("out"), stall, Timeouts::default())
+        .expect("run did not return");
+    assert!(outcome.result.is_ok());
+    assert_eq!(outcome.disconnected, Some(DisconnectReason::Local));
+
+    let metadata = std::fs::metadata(root.join("out/file")).unwrap();
+    assert_eq!(metadata.modified().unwrap(), modified);
+    assert_eq!(metadata.accessed().unwrap(), accessed);
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o444);
+    assert_eq!(
+        std::fs::read(root.join("out/file")).unwrap(),
+        std::fs::read(&path).unwrap()
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_inbound_names_stay_in_destination() {
+    let request = |name: &str| api::SendRequest {
+        file_info: Some(api::FileInfo {
+            name: name.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let file = |name: &str| api::OpenFile {
+        file_info: Some(api::FileInfo {
+            name: name.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert!(check_inbound_names(&request("dir"), &file("sub/file")).is_ok());
+    assert!(check_inbound_names(&request("../dir"), &file("file")).is_err());
+    assert!(check_inbound_names(&request("dir"), &file("../../file")).is_err());
+    assert!(check_inbound_names(&request("/etc"), &file("file")).is_err());
+    assert!(check_inbound_names(&request("dir"), &file("/etc/passwd")).is_err());
+}
+
+#[test]
+fn test_copied_blocks_stay_in_basis() {
+    let root = test_dir("copy");
+    let basis = root.join("in/file");
+    noise_file(&basis, 3 * 4096, 0x2545f4914f6cdd1d);
+    let mut delta = DeltaTarget {
+        basis: std::fs::File::open(&basis).unwrap(),
+        block_size: 4096,
+        temp_path: root.join("out/file"),
+    };
+    let mut file = std::fs::File::create(&delta.temp_path).unwrap();
+    let copy = |offset, block, count| api::CopyBlock {
+        offset,
+        block,
+        count,
+        ..Default::default()
+    };
+    assert_eq!(delta.copy_to(&mut file, &copy(0, 1, 2)).unwrap(), 2 * 4096);
+    assert!(delta.copy_to(&mut file, &copy(2 * 4096, 2, 2)).is_err());
+    // Out of range rather than overflowing
+    assert!(delta
+        .copy_to(&mut file, &copy(3 * 4096, u64::MAX, 1))
+        .is_err());
+    assert!(delta
+        .copy_to(&mut file, &copy(3 * 4096, 0, u64::MAX))
+        .is_err());
+
+    std::fs::remove_dir_all(root).unwrap();
+}
@@ -1,46 +1,246 @@
 use anyhow::{anyhow, Result};
 use cancellation::*;
 use shift::api;
-use shift::helpers::send_file;
+use shift::compression::chunk_length;
+use shift::delta::{block_size, signatures, SignatureIndex, SIGNATURES_PER_MESSAGE};
+use shift::hash::{hash_file, hash_prefix, to_hex, HashAlgorithm, Hasher};
+use shift::helpers::{send_delta, send_file, send_stream};
+use shift::secure::{SecureConfig, SecureSession};
 use shift::{
-    MessageOutput, MessageReader, MessageWriter, OpenFile, ShiftClient, ShiftClientEvent,
-    TransportWriter, TRANSPORT,
+    DisconnectReason, MessageOutput, MessageReader, MessageWriter, OpenFile, ShiftClient,
+    ShiftClientEvent, Timeouts, TransportConfig, TransportCounters, TransportWriter, FEATURE_DELTA,
+    FEATURE_DESTINATION_HINTS, FEATURE_ERRORS, FEATURE_MANIFEST, FEATURE_REJECT_FILE,
+    FEATURE_RESTART, FEATURE_SYMLINKS, FEATURE_XATTRS,
 };
-use std::fs::{File, Metadata, OpenOptions};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, Mutex, TryLockError};
+use std::time::{Duration, Instant};
 
-#[cfg(target_family = "unix")]
-use std::os::unix::fs::PermissionsExt;
+/// How often the event loop wakes up to let keepalive pings and timeouts happen
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+/// How often the event loop retries while a sender holds the client
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+mod browse;
+use browse::is_relative_and_down;
+pub use browse::{resolve_destination, resolve_selection};
+mod metadata;
+mod output;
+pub use metadata::AttributeFilter;
+use metadata::{
+    apply_file_info, check_link_target, create_symlink, file_info, is_directory, is_regular,
+    is_symlink, read_attributes,
+};
+mod preflight;
+pub use preflight::{preflight, Preflight};
 
+mod browse_tests;
 #[cfg(target_family = "unix")]
-fn get_file_mode(metadata: &Metadata) -> u32 {
-    metadata.permissions().mode()
+mod client_tests;
+mod metadata_tests;
+mod preflight_tests;
+
+fn write_chunk(file: &mut File, chunk: &api::Chunk) -> std::io::Result<()> {
+    if chunk.restart {
+        file.set_len(chunk.offset)?;
+        file.seek(SeekFrom::Start(chunk.offset))?;
+    }
+    if chunk.hole_length > 0 {
+        // Growing the file leaves a hole rather than allocating zeros
+        file.set_len(chunk.offset + chunk.hole_length)?;
+        file.seek(SeekFrom::End(0))?;
+        return Ok(());
+    }
+    file.write_all(&chunk.data)
+}
+
+/// Refuses names from the peer that would put a file outside of the destination
+fn check_inbound_names(request: &api::SendRequest, file: &api::OpenFile) -> Result<()> {
+    let infos = [&request.file_info, &file.file_info];
+    for name in infos.iter().filter_map(|x| x.as_ref()).map(|x| &x.name) {
+        if !is_relative_and_down(Path::new(name)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} is outside of the destination", name),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Where the data of an incoming regular file goes
+enum Sink {
+    File(File),
+    /// A stream such as stdout, which can only be appended to. The data is hashed on the way
+    /// since it cannot be read back.
+    Stream {
+        writer: Box<dyn Write + Send>,
+        position: u64,
+        hash: Option<(HashAlgorithm, Hasher)>,
+    },
+}
+
+impl Sink {
+    fn position(&mut self) -> std::io::Result<u64> {
+        match self {
+            Sink::File(file) => file.stream_position(),
+            Sink::Stream { position, .. } => Ok(*position),
+        }
+    }
+
+    fn write_chunk(&mut self, chunk: &api::Chunk) -> std::io::Result<()> {
+        let (writer, position, hash) = match self {
+            Sink::File(file) => return write_chunk(file, chunk),
+            Sink::Stream {
+                writer,
+                position,
+                hash,
+            } => (writer, position, hash),
+        };
+        if chunk.offset != *position {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Cannot restart a file written to a stream",
+            ));
+        }
+        if chunk.hole_length > 0 {
+            std::io::copy(&mut std::io::repeat(0).take(chunk.hole_length), writer)?;
+        } else {
+            writer.write_all(&chunk.data)?;
+        }
+        if let Some((_, hasher)) = hash {
+            match chunk.hole_length {
+                0 => hasher.update(&chunk.data),
+                length => hasher.update_zeros(length),
+            }
+        }
+        *position += chunk_length(chunk);
+        if chunk.end_of_stream {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do with symbolic links found while sending a directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Send links as links, if the peer supports it
+    Preserve,
+    /// Send whatever the links point to
+    Follow,
+    Skip,
 }
 
-#[cfg(target_family = "windows")]
-fn get_file_mode(metadata: &Metadata) -> u32 {
-    if metadata.is_dir() {
-        0o755
-    } else {
-        0o644
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "preserve" => Ok(SymlinkPolicy::Preserve),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(anyhow!("Unknown symlink policy: {}", s)),
+        }
     }
 }
 
 type ProgressCallback<'a> = Box<dyn FnMut(&OpenFile, u64, u64) + Send + 'a>;
 
-pub struct ShiftFileClient<'a> {
-    buffer_size: usize,
-    client: Arc<Mutex<ShiftClient<'a>>>,
-    current_transfer_path: Option<PathBuf>,
+struct OutboundTransfer<'a> {
+    path: PathBuf,
+    /// Data of unknown size sent instead of the file at `path`
+    stream: Option<Box<dyn Read + Send + 'a>>,
     current_file_path: Option<PathBuf>,
-    remaining_files_to_send: Option<Vec<PathBuf>>,
+    remaining_files_to_send: Vec<PathBuf>,
     total_bytes_sent: u64,
     total_bytes_to_send: u64,
+    manifest: api::TransferManifest,
+    progress_callback: Arc<Mutex<ProgressCallback<'a>>>,
+    /// Blocks the receiver already has of the current file
+    signatures: Option<SignatureIndex>,
+}
+
+/// A new copy of a file that already existed, assembled from blocks of the old one and new data
+struct DeltaTarget {
+    basis: File,
+    block_size: u64,
+    temp_path: PathBuf,
+}
+
+impl DeltaTarget {
+    /// Copies the blocks over and returns where the file ends now
+    fn copy_to(&mut self, file: &mut File, copy: &api::CopyBlock) -> Result<u64> {
+        if file.stream_position()? != copy.offset {
+            return Err(anyhow!("Copy offset does not match file position"));
+        }
+        let outside = || anyhow!("Copied blocks are outside of the existing file");
+        let start = copy
+            .block
+            .checked_mul(self.block_size)
+            .ok_or_else(outside)?;
+        let length = copy
+            .count
+            .checked_mul(self.block_size)
+            .ok_or_else(outside)?;
+        let end = copy.offset.checked_add(length).ok_or_else(outside)?;
+        self.basis.seek(SeekFrom::Start(start))?;
+        if std::io::copy(&mut (&mut self.basis).take(length), file)? != length {
+            return Err(outside());
+        }
+        Ok(end)
+    }
+}
+
+#[derive(Default)]
+struct InboundTransfer {
+    open_file: Option<Sink>,
+    open_file_path: Option<PathBuf>,
+    open_file_name: Option<String>,
+    received_directories: Vec<(PathBuf, api::FileInfo)>,
+    received_attributes: HashMap<PathBuf, Vec<api::ExtendedAttribute>>,
+    current_path: Option<PathBuf>,
+    delta: Option<DeltaTarget>,
+    /// Bytes of the files received completely, and how far the current one got
+    received_bytes: u64,
+    file_position: u64,
+}
+
+pub struct ShiftFileClient<'a> {
+    buffer_size: usize,
+    client: Arc<Mutex<ShiftClient<'a>>>,
+    outbound_transfers: HashMap<u32, OutboundTransfer<'a>>,
+    inbound_transfers: HashMap<u32, InboundTransfer>,
+    /// Set while the delegate answers a peer's `ReceiveRequest`, whose ID the response reuses
+    requested_transfer_id: Option<u32>,
+    /// Suggested to the receiver of every outbound transfer
+    destination: String,
+    tags: Vec<String>,
     output: Option<Box<dyn Write + Send>>,
-    open_file: Option<File>,
-    send_progress_callback: Option<Arc<Mutex<ProgressCallback<'a>>>>,
+    preserve_ownership: bool,
+    symlink_policy: SymlinkPolicy,
+    attribute_filter: AttributeFilter,
+    warnings: Vec<String>,
+    timeouts: Timeouts,
+    manifest: Option<Box<dyn Write + Send>>,
+    session: Option<SecureSession>,
+    transport: TransportConfig,
+    transport_counters: Arc<TransportCounters>,
+    output_queue: Arc<output::Queue>,
+}
+
+/// Wakes up the event loop
+enum Wakeup {
+    Received(MessageOutput),
+    /// A sender is done
+    Sent,
+    /// The input ended, or could not be read
+    InputClosed(Result<()>),
 }
 
 pub trait ShiftFileClientDelegate<'a> {
@@ -50,9 +250,23 @@ pub trait ShiftFileClientDelegate<'a> {
     fn on_inbound_transfer_request(&mut self, _request: &api::SendRequest) -> bool {
         false
     }
+    /// Where to write an incoming file. `None` skips it, or closes the whole transfer when the
+    /// peer cannot skip files.
     fn on_inbound_transfer_file(&mut self, _file: &api::OpenFile) -> Result<Option<PathBuf>> {
         Ok(None)
     }
+    /// Where to write an incoming regular file instead of a path, such as stdout. Asked before
+    /// `on_inbound_transfer_file`.
+    fn inbound_file_stream(&mut self, _file: &api::OpenFile) -> Option<Box<dyn Write + Send>> {
+        None
+    }
+    /// The sender listed what an inbound transfer holds before sending any of it. An error
+    /// refuses the transfer, see [`preflight`].
+    fn on_transfer_manifest(&mut self, _manifest: &api::TransferManifest) -> Result<()> {
+        Ok(())
+    }
+    /// Bytes received of an inbound transfer so far, including what the destination had already
+    fn on_inbound_progress(&mut self, _transfer_id: u32, _received_bytes: u64) {}
     fn on_outbound_transfer_request(
         &mut self,
         _request: &api::ReceiveRequest,
@@ -60,8 +274,32 @@ pub trait ShiftFileClientDelegate<'a> {
     ) -> Result<()> {
         Ok(())
     }
-    fn on_tick(&mut self) {}
-    fn on_transfer_closed(&mut self) {}
+    /// Whether to send a file again from the start when the destination already has a different
+    /// file by that name. Otherwise the transfer is closed.
+    fn on_resume_mismatch(&mut self, _file: &OpenFile) -> bool {
+        true
+    }
+    /// Directory the peer may list and stat, if any
+    fn browse_root(&mut self) -> Option<PathBuf> {
+        None
+    }
+    fn on_directory_listing(&mut self, _listing: &api::DirectoryListing) {}
+    fn on_stat_result(&mut self, _result: &api::StatResult) {}
+    fn on_file_verified(&mut self, _verification: &api::FileVerification) {}
+    /// The receiver skipped a file this side offered
+    fn on_file_rejected(&mut self, _file: &OpenFile) {}
+    fn on_peer_error(&mut self, _error: &api::Error) {}
+    fn on_warning(&mut self, _message: &str) {}
+    /// Called regularly, also while transfers are running
+    fn on_tick(&mut self, _client: &mut ShiftFileClient<'a>) -> Result<()> {
+        Ok(())
+    }
+    fn on_latency(&mut self, _latency: Duration) {}
+    fn on_transfer_paused(&mut self, _transfer_id: u32) {}
+    fn on_transfer_resumed(&mut self, _transfer_id: u32) {}
+    fn on_transfer_closed(&mut self, _transfer_id: u32) {}
+    /// Called when the session ends, before `run` returns
+    fn on_disconnected(&mut self, _reason: &DisconnectReason) {}
     fn on_disconnect(&mut self) -> Result<()> {
         Ok(())
     }
@@ -72,244 +310,732 @@ impl<'a> ShiftFileClient<'a> {
         data_stream_out: Box<dyn Write + Send>,
         output: Option<Box<dyn Write + Send>>,
     ) -> Self {
+        Self::with_security(data_stream_out, output, None)
+    }
+
+    /// Like [`ShiftFileClient::new`], but requires the peer to authenticate and encrypts the session
+    pub fn with_security(
+        data_stream_out: Box<dyn Write + Send>,
+        output: Option<Box<dyn Write + Send>>,
+        security: Option<SecureConfig>,
+    ) -> Self {
+        Self::with_transport(
+            data_stream_out,
+            output,
+            security,
+            TransportConfig::default(),
+        )
+    }
+
+    /// Like [`ShiftFileClient::with_security`], but frames messages as `transport` says, such as
+    /// wrapped for a terminal multiplexer
+    pub fn with_transport(
+        data_stream_out: Box<dyn Write + Send>,
+        output: Option<Box<dyn Write + Send>>,
+        security: Option<SecureConfig>,
+        transport: TransportConfig,
+    ) -> Self {
+        let session = security.map(SecureSession::new);
+        let queued_writer = output::QueuedWriter::new(data_stream_out);
+        let output_queue = queued_writer.queue();
+        let writer = TransportWriter::new(transport.clone(), Box::new(queued_writer));
+        let writer = match &session {
+            Some(session) => MessageWriter::with_session(writer, session.clone()),
+            None => MessageWriter::new(writer),
+        };
         Self {
             buffer_size: 1024 * 512,
-            client: Arc::new(Mutex::new(ShiftClient::new(MessageWriter::new(
-                TransportWriter::new(TRANSPORT, Box::new(data_stream_out)),
-            )))),
-            current_transfer_path: None,
-            current_file_path: None,
-            remaining_files_to_send: None,
-            total_bytes_sent: 0,
-            total_bytes_to_send: 0,
+            client: Arc::new(Mutex::new(ShiftClient::new(writer))),
+            outbound_transfers: HashMap::new(),
+            inbound_transfers: HashMap::new(),
+            requested_transfer_id: None,
+            destination: String::new(),
+            tags: vec![],
             output,
-            open_file: None,
-            send_progress_callback: None,
+            preserve_ownership: false,
+            symlink_policy: SymlinkPolicy::Preserve,
+            attribute_filter: AttributeFilter::default(),
+            warnings: vec![],
+            timeouts: Timeouts::default(),
+            manifest: None,
+            session,
+            transport,
+            transport_counters: Arc::default(),
+            output_queue,
         }
     }
 
+    /// Writes a `sha256sum`-style line for every received file that passed verification
+    pub fn set_manifest(&mut self, manifest: Box<dyn Write + Send>) {
+        self.manifest = Some(manifest);
+    }
+
+    /// Gives received files the sender's owner and group. Only effective when running as root.
+    pub fn set_preserve_ownership(&mut self, preserve_ownership: bool) {
+        self.preserve_ownership = preserve_ownership;
+    }
+
+    /// Suggests to the receivers of outbound transfers where to put them, relative to wherever
+    /// they keep transfers, and how to label them. They decide whether to follow it.
+    pub fn set_destination_hint(&mut self, destination: String, tags: Vec<String>) {
+        self.destination = destination;
+        self.tags = tags;
+    }
+
+    pub fn set_symlink_policy(&mut self, symlink_policy: SymlinkPolicy) {
+        self.symlink_policy = symlink_policy;
+    }
+
+    /// How many frames from the peer were read, and how many were given up on
+    pub fn transport_counters(&self) -> Arc<TransportCounters> {
+        self.transport_counters.clone()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.client.lock().unwrap().set_timeouts(timeouts.clone());
+        self.timeouts = timeouts;
+    }
+
+    /// Chooses which extended attributes are sent and applied, if the peer supports them
+    pub fn set_attribute_filter(&mut self, attribute_filter: AttributeFilter) {
+        self.attribute_filter = attribute_filter;
+    }
+
+    /// Runs the session until either side disconnects or the link times out. The input is read
+    /// on a thread of its own, which is left behind when the link stalls in the middle of a read.
     pub fn run<D, S>(
         &mut self,
         announce: bool,
-        input: &mut S,
+        mut input: S,
         delegate: &mut D,
         token: &CancellationToken,
     ) -> Result<()>
     where
         D: ShiftFileClientDelegate<'a> + Send,
-        S: Read + Send,
+        S: Read + Send + 'static,
     {
         if announce {
             self.client.lock().unwrap().start()?;
         }
 
         let stop = Arc::new(AtomicBool::new(false));
+        let reader_token_source = CancellationTokenSource::new();
+        let (tx, rx) = std::sync::mpsc::channel();
 
-        let loop_result = crossbeam::scope(|scope| -> Result<()> {
-            let (tx, rx) = std::sync::mpsc::channel();
-
-            let reader_thread = scope.spawn({
-                let client = self.client.clone();
-                let mut output = self.output.take();
-                let stop = stop.clone();
-                let tx = tx.clone();
-                move |_| -> Result<()> {
-                    tx.send(0)?;
-                    let mut reader = MessageReader::new(TRANSPORT);
-                    for msg in reader.feed_from(input, token) {
+        std::thread::spawn({
+            let mut output = self.output.take();
+            let stop = stop.clone();
+            let tx = tx.clone();
+            let token = reader_token_source.token().clone();
+            let session = self.session.clone();
+            let transport = self.transport.clone();
+            let counters = self.transport_counters.clone();
+            move || {
+                let result = (|| -> Result<()> {
+                    let mut reader = match session {
+                        Some(session) => MessageReader::with_session(transport, session),
+                        None => MessageReader::new(transport),
+                    };
+                    reader.set_counters(counters);
+                    for msg in reader.feed_from(&mut input, &token) {
                         if stop.load(Ordering::Relaxed) {
                             break;
                         }
                         match msg {
-                            MessageOutput::Message(msg) => {
-                                client.lock().unwrap().feed_message(msg)?;
-                                tx.send(0)?;
-                            }
                             MessageOutput::Passthrough(data) => {
                                 if let Some(ref mut output) = output {
                                     output.write_all(&data)?;
                                     output.flush()?;
                                 }
                             }
+                            msg => tx.send(Wakeup::Received(msg))?,
                         }
                     }
-                    stop.store(true, Ordering::Relaxed);
-                    tx.send(0)?;
                     Ok(())
-                }
-            });
+                })();
+                // Nobody listens anymore if the event loop ended first
+                let _ = tx.send(Wakeup::InputClosed(result));
+            }
+        });
 
-            (|| {
+        let loop_result = crossbeam::scope(|scope| -> Result<()> {
+            let result = (|| {
+                let mut last_tick = Instant::now();
+                let mut received = VecDeque::new();
+                let mut input_closed = false;
                 'event_loop: loop {
-                    let events = { self.client.lock().unwrap().take_events() };
-                    if events.is_empty() {
-                        rx.recv()?;
+                    if token.is_canceled() {
+                        break 'event_loop;
                     }
-                    {
-                        if stop.load(Ordering::Relaxed) {
-                            break 'event_loop;
+                    let events = match self.client.try_lock() {
+                        Ok(mut client) => {
+                            // Ticking first times acknowledgements by when they came in
+                            last_tick = Instant::now();
+                            client.tick(last_tick)?;
+                            for msg in received.drain(..) {
+                                match msg {
+                                    MessageOutput::Message(msg) => client.feed_message(msg)?,
+                                    MessageOutput::Unsupported(message_type) => {
+                                        client.reject_unsupported(message_type)?
+                                    }
+                                    MessageOutput::Passthrough(_) => {}
+                                }
+                            }
+                            client.take_events()
+                        }
+                        Err(TryLockError::WouldBlock) => {
+                            // A sender writing to a stalled peer blocks while holding the client
+                            if let Some(timeout) = self.timeouts.idle {
+                                if last_tick.elapsed() >= timeout {
+                                    delegate.on_disconnected(&DisconnectReason::IdleTimeout);
+                                    break 'event_loop;
+                                }
+                            }
+                            std::thread::sleep(LOCK_POLL_INTERVAL);
+                            continue;
+                        }
+                        Err(TryLockError::Poisoned(_)) => {
+                            return Err(anyhow!("Panic while holding the client"));
+                        }
+                    };
+                    if events.is_empty() {
+                        let wakeup = match rx.recv_timeout(TICK_INTERVAL) {
+                            Ok(wakeup) => Some(wakeup),
+                            Err(RecvTimeoutError::Timeout) => None,
+                            Err(error) => return Err(error.into()),
+                        };
+                        for wakeup in wakeup.into_iter().chain(rx.try_iter()) {
+                            match wakeup {
+                                Wakeup::Received(msg) => received.push_back(msg),
+                                Wakeup::Sent => {}
+                                Wakeup::InputClosed(result) => {
+                                    result?;
+                                    input_closed = true;
+                                }
+                            }
                         }
                     }
-                    delegate.on_tick();
+                    if input_closed && received.is_empty() {
+                        break 'event_loop;
+                    }
+                    delegate.on_tick(self)?;
                     for event in events {
                         match event {
                             ShiftClientEvent::Connected => {
                                 delegate.on_idle(self)?;
                             }
-                            ShiftClientEvent::Disconnected => {
+                            ShiftClientEvent::Disconnected(reason) => {
+                                delegate.on_disconnected(&reason);
                                 break 'event_loop;
                             }
+                            ShiftClientEvent::LatencyMeasured(latency) => {
+                                delegate.on_latency(latency);
+                            }
                             ShiftClientEvent::InboundTransferOffered(request) => {
+                                let transfer_id = request.transfer_id;
                                 if delegate.on_inbound_transfer_request(&request) {
-                                    self.client.lock().unwrap().accept_transfer()?;
+                                    self.inbound_transfers
+                                        .insert(transfer_id, InboundTransfer::default());
+                                    self.client.lock().unwrap().accept_transfer(transfer_id)?;
                                 } else {
-                                    self.client.lock().unwrap().reject_transfer()?;
+                                    self.client.lock().unwrap().reject_transfer(transfer_id)?;
                                 }
                             }
-                            ShiftClientEvent::InboundFileOpening(_, file) => {
-                                match delegate.on_inbound_transfer_file(&file) {
-                                    Ok(Some(path)) => {
-                                        std::fs::create_dir_all(path.parent().ok_or(anyhow!(
-                                            "Cannot operate on filesystem root"
-                                        ))?)?;
-                                        if file
-                                            .file_info
-                                            .ok_or(anyhow!("Missing file info in request"))?
-                                            .mode
-                                            & 0o40000
-                                            != 0
-                                        {
-                                            self.client.lock().unwrap().confirm_file_opened(
-                                                api::FileOpened { continue_from: 0 },
-                                            )?;
+                            ShiftClientEvent::InboundFileOpening(request, file) => {
+                                let transfer_id = file.transfer_id;
+                                // The transfer may have been refused on seeing its manifest
+                                if self
+                                    .client
+                                    .lock()
+                                    .unwrap()
+                                    .transfer_state(transfer_id)
+                                    .is_none()
+                                {
+                                    continue;
+                                }
+                                let name = file
+                                    .file_info
+                                    .as_ref()
+                                    .map(|x| x.name.clone())
+                                    .unwrap_or_default();
+                                let stream = file
+                                    .file_info
+                                    .as_ref()
+                                    .filter(|x| is_regular(x))
+                                    .and_then(|_| delegate.inbound_file_stream(&file));
+                                let opened = match stream {
+                                    Some(stream) => Ok(Some(self.open_inbound_stream(
+                                        transfer_id,
+                                        stream,
+                                        &file,
+                                    ))),
+                                    None => check_inbound_names(&request, &file)
+                                        .and_then(|_| delegate.on_inbound_transfer_file(&file))
+                                        .and_then(|path| match path {
+                                            Some(path) => self
+                                                .open_inbound_file(transfer_id, path, &file)
+                                                .map(Some),
+                                            None => Ok(None),
+                                        }),
+                                };
+                                match opened {
+                                    Ok(Some(opened)) => {
+                                        let transfer = self.inbound_transfer(transfer_id);
+                                        transfer.open_file_name = Some(name);
+                                        transfer.file_position = opened.continue_from;
+                                        self.client.lock().unwrap().confirm_file_opened(opened)?;
+                                        self.report_inbound_progress(delegate, transfer_id);
+                                    }
+                                    Ok(None) => {
+                                        self.inbound_transfer(transfer_id).current_path = None;
+                                        let mut client = self.client.lock().unwrap();
+                                        if client.has_feature(FEATURE_REJECT_FILE) {
+                                            client.reject_file(api::RejectFile {
+                                                transfer_id,
+                                                file_id: file.file_id,
+                                            })?;
                                         } else {
-                                            let mut file;
-                                            if path.exists() {
-                                                file = OpenOptions::new()
-                                                    .append(true)
-                                                    .open(path.clone())?;
-                                            } else {
-                                                file = File::create(path.clone())?;
-                                            }
-                                            let position = file.seek(SeekFrom::End(0))?;
-
-                                            self.client.lock().unwrap().confirm_file_opened(
-                                                api::FileOpened {
-                                                    continue_from: position,
-                                                },
-                                            )?;
-
-                                            self.open_file = Some(file);
+                                            client.close_transfer(transfer_id)?;
                                         }
                                     }
-                                    Ok(None) | Err(_) => {
-                                        self.client.lock().unwrap().close_transfer()?;
+                                    Err(error) => {
+                                        self.report_error(transfer_id, &error, &name)?;
                                     }
                                 }
                             }
                             ShiftClientEvent::OutboundTransferOffered(request) => {
-                                delegate.on_outbound_transfer_request(&request, self)?;
+                                self.requested_transfer_id = Some(request.transfer_id);
+                                let result = delegate.on_outbound_transfer_request(&request, self);
+                                self.requested_transfer_id = None;
+                                result?;
                             }
                             ShiftClientEvent::Chunk(chunk) => {
-                                println!(
-                                    "chynk len {:?} for {:?}",
-                                    chunk.data.len(),
-                                    self.open_file.is_some()
-                                );
-                                if let Some(file) = &mut self.open_file {
-                                    let position = file.seek(SeekFrom::Current(0))?;
-                                    if position != chunk.offset {
-                                        return Err(anyhow!(
-                                            "Chunk offset does not match file position"
-                                        ));
+                                let transfer_id = chunk.transfer_id;
+                                let transfer = self.inbound_transfer(transfer_id);
+                                if let Some(sink) = &mut transfer.open_file {
+                                    let position = sink.position()?;
+                                    // Only a restart may go back to data that was written
+                                    let result = if chunk.offset == position
+                                        || (chunk.restart && chunk.offset < position)
+                                    {
+                                        sink.write_chunk(&chunk).map_err(anyhow::Error::from)
+                                    } else {
+                                        Err(anyhow!(
+                                            "Chunk at offset {} does not follow on {} bytes written",
+                                            chunk.offset,
+                                            position
+                                        ))
+                                    };
+                                    if let Err(error) = result {
+                                        let name =
+                                            transfer.open_file_name.clone().unwrap_or_default();
+                                        self.report_error(transfer_id, &error, &name)?;
+                                        continue;
                                     }
-                                    file.write_all(&chunk.data)?;
-                                    self.client.lock().unwrap().acknolwedge_chunk()?;
-                                    println!("now at {:?}", position);
+                                    let offset = chunk.offset + chunk_length(&chunk);
+                                    transfer.file_position = offset;
+                                    self.client.lock().unwrap().acknowledge_chunk(
+                                        api::AcknowledgeChunk {
+                                            offset,
+                                            transfer_id,
+                                            file_id: chunk.file_id,
+                                        },
+                                    )?;
+                                    self.report_inbound_progress(delegate, transfer_id);
                                 }
                             }
-                            ShiftClientEvent::TransferAccepted() => {
-                                self.maybe_send_next_file()?;
+                            ShiftClientEvent::TransferAccepted(transfer_id) => {
+                                let mut client = self.client.lock().unwrap();
+                                if client.has_feature(FEATURE_MANIFEST) {
+                                    if let Some(transfer) =
+                                        self.outbound_transfers.get(&transfer_id)
+                                    {
+                                        client.send_transfer_manifest(transfer.manifest.clone())?;
+                                    }
+                                }
+                                drop(client);
+                                self.maybe_send_next_file(transfer_id)?;
+                            }
+                            ShiftClientEvent::TransferManifest(manifest) => {
+                                if let Err(error) = delegate.on_transfer_manifest(&manifest) {
+                                    self.report_error(manifest.transfer_id, &error, "")?;
+                                }
                             }
                             ShiftClientEvent::FileTransferStarted(open_file, response) => {
-                                let full_path = std::fs::canonicalize(
-                                    self.current_transfer_path
-                                        .clone()
-                                        .ok_or(anyhow!("Missing transfer path"))?
-                                        .join(
-                                            self.current_file_path
-                                                .clone()
-                                                .ok_or(anyhow!("No current file"))?,
-                                        ),
+                                let transfer = self
+                                    .outbound_transfers
+                                    .get_mut(&open_file.transfer_id)
+                                    .ok_or(anyhow!("Unknown transfer"))?;
+                                let hash = HashAlgorithm::negotiated(
+                                    self.client.lock().unwrap().negotiated_features(),
+                                );
+                                let callback = transfer.progress_callback.clone();
+                                if let Some(mut stream) = transfer.stream.take() {
+                                    // There is nothing to resume from, the receiver always takes
+                                    // a stream from the start
+                                    scope.spawn({
+                                        let client = self.client.clone();
+                                        let tx = tx.clone();
+                                        let buffer_size = self.buffer_size;
+                                        move |_| -> Result<()> {
+                                            send_stream(
+                                                client,
+                                                &open_file,
+                                                &mut stream,
+                                                buffer_size,
+                                                hash,
+                                                &mut |sent| {
+                                                    callback.lock().unwrap()(&open_file, sent, 0)
+                                                },
+                                            )?;
+                                            tx.send(Wakeup::Sent)?;
+                                            Ok(())
+                                        }
+                                    });
+                                    continue;
+                                }
+                                let signatures = transfer.signatures.take();
+                                let full_path = resolve_path(
+                                    &transfer.path,
+                                    transfer
+                                        .current_file_path
+                                        .as_ref()
+                                        .ok_or(anyhow!("No current file"))?,
                                 )?;
 
-                                if full_path.is_dir() {
-                                    self.client.lock().unwrap().close_file()?;
+                                let metadata = full_path.symlink_metadata()?;
+                                if metadata.is_dir() || metadata.file_type().is_symlink() {
+                                    self.client.lock().unwrap().close_file(api::CloseFile {
+                                        transfer_id: open_file.transfer_id,
+                                        file_id: open_file.file_id,
+                                        ..Default::default()
+                                    })?;
                                     continue;
                                 }
 
-                                let callback = self
-                                    .send_progress_callback
-                                    .clone()
-                                    .ok_or(anyhow!("Missing callback"))?;
+                                let mut position = response.continue_from;
+                                if signatures.is_none()
+                                    && position > 0
+                                    && !response.prefix_hash.is_empty()
+                                {
+                                    let prefix_hash =
+                                        HashAlgorithm::from_name(&response.prefix_hash_algorithm)
+                                            .map(|x| hash_prefix(&full_path, position, x))
+                                            .transpose()?
+                                            .flatten();
+                                    if prefix_hash.as_ref() != Some(&response.prefix_hash) {
+                                        let restart = self
+                                            .client
+                                            .lock()
+                                            .unwrap()
+                                            .has_feature(FEATURE_RESTART);
+                                        if !restart {
+                                            self.warnings.push(format!(
+                                                "{} differs at the destination, which cannot restart it",
+                                                open_file.info.name
+                                            ));
+                                        }
+                                        if !restart || !delegate.on_resume_mismatch(&open_file) {
+                                            self.client
+                                                .lock()
+                                                .unwrap()
+                                                .close_transfer(open_file.transfer_id)?;
+                                            continue;
+                                        }
+                                        self.warnings.push(format!(
+                                            "{} differs at the destination, sending it again",
+                                            open_file.info.name
+                                        ));
+                                        position = 0;
+                                        self.client.lock().unwrap().send_chunk(api::Chunk {
+                                            transfer_id: open_file.transfer_id,
+                                            file_id: open_file.file_id,
+                                            restart: true,
+                                            ..Default::default()
+                                        })?;
+                                    }
+                                }
+
                                 scope.spawn({
                                     let client = self.client.clone();
                                     let tx = tx.clone();
                                     let buffer_size = self.buffer_size;
                                     let open_file = open_file.clone();
-                                    let total_bytes_sent = self.total_bytes_sent;
-                                    let total_bytes_to_send = self.total_bytes_to_send;
+                                    let total_bytes_sent = transfer.total_bytes_sent;
+                                    let total_bytes_to_send = transfer.total_bytes_to_send;
                                     move |_| -> Result<()> {
-                                        send_file(
-                                            client,
-                                            response.continue_from,
-                                            &full_path,
-                                            buffer_size,
-                                            &mut |sent, _| {
-                                                callback.lock().unwrap()(
-                                                    &open_file,
-                                                    total_bytes_sent + sent,
-                                                    total_bytes_to_send,
-                                                );
-                                            },
-                                        )?;
-                                        tx.send(0)?;
+                                        let mut progress = |sent, _| {
+                                            callback.lock().unwrap()(
+                                                &open_file,
+                                                total_bytes_sent + sent,
+                                                total_bytes_to_send,
+                                            );
+                                        };
+                                        match signatures {
+                                            Some(signatures) => send_delta(
+                                                client,
+                                                &open_file,
+                                                &full_path,
+                                                &signatures,
+                                                buffer_size,
+                                                hash,
+                                                &mut progress,
+                                            )?,
+                                            None => send_file(
+                                                client,
+                                                &open_file,
+                                                position,
+                                                &full_path,
+                                                buffer_size,
+                                                hash,
+                                                &mut progress,
+                                            )?,
+                                        }
+                                        tx.send(Wakeup::Sent)?;
                                         Ok(())
                                     }
                                 });
                             }
-                            ShiftClientEvent::FileClosed(f) => {
-                                if let Some(file) = &mut self.open_file {
-                                    // file.flush()?;
-                                    // file.sync_all()?;
-                                    println!("now at {:?}", file.seek(SeekFrom::Current(0)));
+                            ShiftClientEvent::FileRejected(file) => {
+                                delegate.on_file_rejected(&file);
+                                if let Some(transfer) =
+                                    self.outbound_transfers.get_mut(&file.transfer_id)
+                                {
+                                    if is_regular(&file.info) {
+                                        transfer.total_bytes_sent += file.info.size;
+                                    }
                                 }
-                                self.open_file = None;
-                                self.total_bytes_sent += f.info.size;
-                                self.maybe_send_next_file()?;
+                                self.maybe_send_next_file(file.transfer_id)?;
                             }
-                            ShiftClientEvent::TransferClosed => {
-                                self.remaining_files_to_send = None;
-                                delegate.on_transfer_closed();
-                                delegate.on_idle(self)?;
+                            ShiftClientEvent::TransferPaused(transfer_id) => {
+                                delegate.on_transfer_paused(transfer_id);
+                            }
+                            ShiftClientEvent::TransferResumed(transfer_id) => {
+                                delegate.on_transfer_resumed(transfer_id);
+                            }
+                            ShiftClientEvent::FileClosed(f, close) => {
+                                let transfer_id = f.transfer_id;
+                                if let Some(transfer) =
+                                    self.outbound_transfers.get_mut(&transfer_id)
+                                {
+                                    if is_regular(&f.info) {
+                                        transfer.total_bytes_sent += f.info.size;
+                                    }
+                                    // With a hash, wait for the receiver's verdict first
+                                    if close.hash.is_empty() {
+                                        self.maybe_send_next_file(transfer_id)?;
+                                    }
+                                } else {
+                                    let transfer = self.inbound_transfer(transfer_id);
+                                    let sink = transfer.open_file.take();
+                                    transfer.open_file_name = None;
+                                    if is_regular(&f.info) {
+                                        transfer.received_bytes += f.info.size;
+                                    }
+                                    transfer.file_position = 0;
+                                    if let Some(Sink::Stream {
+                                        mut writer, hash, ..
+                                    }) = sink
+                                    {
+                                        if let Err(error) = writer.flush() {
+                                            self.report_error(
+                                                transfer_id,
+                                                &error.into(),
+                                                &f.info.name,
+                                            )?;
+                                            continue;
+                                        }
+                                        if let (false, Some((algorithm, hasher))) =
+                                            (close.hash.is_empty(), hash)
+                                        {
+                                            let hash =
+                                                match algorithm.name() == close.hash_algorithm {
+                                                    true => hasher.finalize(),
+                                                    false => vec![],
+                                                };
+                                            let verification =
+                                                self.send_verification(&f, &close, hash, "-")?;
+                                            delegate.on_file_verified(&verification);
+                                        }
+                                        continue;
+                                    }
+                                    let delta = transfer.delta.take();
+                                    if let Some(path) = transfer.open_file_path.take() {
+                                        if let Some(delta) = delta {
+                                            if let Err(error) =
+                                                std::fs::rename(&delta.temp_path, &path)
+                                            {
+                                                self.report_error(
+                                                    transfer_id,
+                                                    &error.into(),
+                                                    &f.info.name,
+                                                )?;
+                                                continue;
+                                            }
+                                        }
+                                        if !close.hash.is_empty() {
+                                            let verification =
+                                                self.verify_received_file(&path, &f, &close)?;
+                                            delegate.on_file_verified(&verification);
+                                        }
+                                        if let Err(error) =
+                                            self.apply_received_info(transfer_id, &path, &f.info)
+                                        {
+                                            self.report_error(transfer_id, &error, &f.info.name)?;
+                                        }
+                                    }
+                                }
+                            }
+                            ShiftClientEvent::FileVerified(verification) => {
+                                delegate.on_file_verified(&verification);
+                                self.maybe_send_next_file(verification.transfer_id)?;
+                            }
+                            ShiftClientEvent::BlockSignatures(signatures) => {
+                                let algorithm =
+                                    HashAlgorithm::from_name(&signatures.hash_algorithm);
+                                if let (Some(transfer), Some(algorithm)) = (
+                                    self.outbound_transfers.get_mut(&signatures.transfer_id),
+                                    algorithm,
+                                ) {
+                                    transfer
+                                        .signatures
+                                        .get_or_insert_with(|| {
+                                            SignatureIndex::new(signatures.block_size, algorithm)
+                                        })
+                                        .add(&signatures);
+                                }
+                            }
+                            ShiftClientEvent::CopyBlock(copy) => {
+                                let transfer_id = copy.transfer_id;
+                                let transfer = self.inbound_transfer(transfer_id);
+                                let result = match (&mut transfer.open_file, &mut transfer.delta) {
+                                    (Some(Sink::File(file)), Some(delta)) => delta
+                                        .copy_to(file, &copy)
+                                        .map(|end| transfer.file_position = end),
+                                    _ => Err(anyhow!("No existing file to copy from")),
+                                };
+                                match result {
+                                    Ok(()) => self.report_inbound_progress(delegate, transfer_id),
+                                    Err(error) => {
+                                        let name =
+                                            transfer.open_file_name.clone().unwrap_or_default();
+                                        self.report_error(transfer_id, &error, &name)?;
+                                    }
+                                }
+                            }
+                            ShiftClientEvent::FileAttributes(attributes) => {
+                                let transfer = self.inbound_transfer(attributes.transfer_id);
+                                if let Some(path) = &transfer.current_path {
+                                    transfer
+                                        .received_attributes
+                                        .insert(path.clone(), attributes.attributes);
+                                }
+                            }
+                            ShiftClientEvent::PeerError(error) => {
+                                delegate.on_peer_error(&error);
+                                // Unlike accepted transfers, a failed request is not closed
+                                // afterwards
+                                let transfer_id = error.transfer_id;
+                                if error.request_id != 0
+                                    || !(self.inbound_transfers.contains_key(&transfer_id)
+                                        || self.outbound_transfers.contains_key(&transfer_id))
+                                {
+                                    self.maybe_idle(delegate)?;
+                                }
+                            }
+                            ShiftClientEvent::ListDirectoryRequested(request) => {
+                                let listing = delegate
+                                    .browse_root()
+                                    .ok_or_else(not_shared)
+                                    .and_then(|root| browse::list_directory(&root, &request));
+                                match listing {
+                                    Ok(listing) => self
+                                        .client
+                                        .lock()
+                                        .unwrap()
+                                        .send_directory_listing(listing)?,
+                                    Err(error) => self.report_request_error(
+                                        request.request_id,
+                                        &error,
+                                        &request.path,
+                                        |client| {
+                                            client.send_directory_listing(api::DirectoryListing {
+                                                request_id: request.request_id,
+                                                ..Default::default()
+                                            })
+                                        },
+                                    )?,
+                                }
+                            }
+                            ShiftClientEvent::StatRequested(request) => {
+                                let result = delegate
+                                    .browse_root()
+                                    .ok_or_else(not_shared)
+                                    .and_then(|root| browse::stat(&root, &request));
+                                match result {
+                                    Ok(result) => {
+                                        self.client.lock().unwrap().send_stat_result(result)?
+                                    }
+                                    Err(error) => self.report_request_error(
+                                        request.request_id,
+                                        &error,
+                                        &request.path,
+                                        |client| {
+                                            client.send_stat_result(api::StatResult {
+                                                request_id: request.request_id,
+                                                file_info: None,
+                                            })
+                                        },
+                                    )?,
+                                }
+                            }
+                            ShiftClientEvent::DirectoryListing(listing) => {
+                                delegate.on_directory_listing(&listing);
+                                self.maybe_idle(delegate)?;
+                            }
+                            ShiftClientEvent::StatResult(result) => {
+                                delegate.on_stat_result(&result);
+                                self.maybe_idle(delegate)?;
+                            }
+                            // Whatever needed the message fails on its own, the session goes on
+                            ShiftClientEvent::Unsupported(unsupported) => {
+                                self.warnings.push(format!(
+                                    "The peer does not support message type {}",
+                                    unsupported.message_type
+                                ));
+                            }
+                            ShiftClientEvent::TransferClosed(transfer_id) => {
+                                self.outbound_transfers.remove(&transfer_id);
+                                if let Some(transfer) = self.inbound_transfers.remove(&transfer_id)
+                                {
+                                    self.apply_directory_info(transfer)?;
+                                }
+                                delegate.on_transfer_closed(transfer_id);
+                                self.maybe_idle(delegate)?;
                             }
                             other => {
                                 return Err(anyhow!("Unknown event: {:?}", other));
                             }
                         };
-                        delegate.on_tick();
+                        for warning in self.warnings.drain(..) {
+                            delegate.on_warning(&warning);
+                        }
+                        delegate.on_tick(self)?;
                     }
                 }
 
                 Ok(())
-            })()
-            .map_err(|e| {
-                stop.store(true, Ordering::Relaxed);
-                e
-            })?;
+            })();
 
-            reader_thread.join().expect("Failure in reader thread")?;
-            Ok(())
+            // A reader blocked on a stalled link is left behind, senders waiting for room in the
+            // output or for their turn to send fail once the session is gone
+            stop.store(true, Ordering::Relaxed);
+            reader_token_source.cancel();
+            self.output_queue.close();
+            if let Ok(mut client) = self.client.lock() {
+                client.abandon()?;
+            }
+            result
         });
 
         loop_result.map_err(|_| anyhow!("Panic in a service thread"))??;
@@ -317,114 +1043,653 @@ impl<'a> ShiftFileClient<'a> {
         Ok(())
     }
 
-    fn maybe_send_next_file(&mut self) -> Result<()> {
-        if let Some(remaining_files_to_send) = &mut self.remaining_files_to_send {
-            self.current_file_path = remaining_files_to_send.pop();
-
-            match &self.current_file_path {
-                Some(path) => {
-                    let full_path = std::fs::canonicalize(
-                        self.current_transfer_path
-                            .clone()
-                            .ok_or(anyhow!("Missing transfer path"))?
-                            .join(path.clone()),
-                    )?;
-                    let meta = std::fs::metadata(&full_path)?;
-                    self.client.lock().unwrap().open_file(api::OpenFile {
-                        file_info: Some(api::FileInfo {
-                            name: path.to_string_lossy().to_string(),
-                            size: meta.len(),
-                            mode: get_file_mode(&meta),
-                        }),
-                    })?;
+    fn maybe_idle<D: ShiftFileClientDelegate<'a>>(&mut self, delegate: &mut D) -> Result<()> {
+        if self.client.lock().unwrap().is_idle() {
+            delegate.on_idle(self)?;
+        }
+        Ok(())
+    }
+
+    fn inbound_transfer(&mut self, transfer_id: u32) -> &mut InboundTransfer {
+        self.inbound_transfers.entry(transfer_id).or_default()
+    }
+
+    fn report_inbound_progress<D: ShiftFileClientDelegate<'a>>(
+        &mut self,
+        delegate: &mut D,
+        transfer_id: u32,
+    ) {
+        let transfer = self.inbound_transfer(transfer_id);
+        delegate.on_inbound_progress(
+            transfer_id,
+            transfer.received_bytes + transfer.file_position,
+        );
+    }
+
+    /// Prepares `path` for an incoming file and tells the sender where to resume from
+    fn open_inbound_file(
+        &mut self,
+        transfer_id: u32,
+        path: PathBuf,
+        file: &api::OpenFile,
+    ) -> Result<api::FileOpened> {
+        let mut opened = api::FileOpened {
+            transfer_id,
+            file_id: file.file_id,
+            ..Default::default()
+        };
+        let (algorithm, delta) = {
+            let client = self.client.lock().unwrap();
+            (
+                HashAlgorithm::negotiated(client.negotiated_features()),
+                client.has_feature(FEATURE_DELTA),
+            )
+        };
+        let transfer = self.inbound_transfer(transfer_id);
+        transfer.current_path = Some(path.clone());
+        std::fs::create_dir_all(
+            path.parent()
+                .ok_or(anyhow!("Cannot operate on filesystem root"))?,
+        )?;
+        let info = file
+            .file_info
+            .as_ref()
+            .ok_or(anyhow!("Missing file info in request"))?;
+        if is_symlink(info) {
+            check_link_target(&path, &info.name, &info.link_target)?;
+            if path
+                .symlink_metadata()
+                .is_ok_and(|x| x.file_type().is_symlink())
+            {
+                std::fs::remove_file(&path)?;
+            }
+            create_symlink(&info.link_target, &path)?;
+            return Ok(opened);
+        }
+        if is_directory(info) {
+            std::fs::create_dir_all(&path)?;
+            transfer.received_directories.push((path, info.clone()));
+            return Ok(opened);
+        }
+        if info.size_unknown {
+            // A stream cannot be resumed, so start over
+            let transfer = self.inbound_transfer(transfer_id);
+            transfer.open_file = Some(Sink::File(File::create(&path)?));
+            transfer.open_file_path = Some(path);
+            return Ok(opened);
+        }
+        if let (true, Some(algorithm), true) = (delta, algorithm, path.is_file()) {
+            if let Some(target) = self.open_delta_target(file, &path, algorithm)? {
+                let transfer = self.inbound_transfer(transfer_id);
+                transfer.open_file = Some(Sink::File(File::create(&target.temp_path)?));
+                transfer.open_file_path = Some(path);
+                transfer.delta = Some(target);
+                return Ok(opened);
+            }
+        }
+        let mut file = if path.exists() {
+            OpenOptions::new().write(true).open(path.clone())?
+        } else {
+            File::create(path.clone())?
+        };
+        opened.continue_from = file.seek(SeekFrom::End(0))?;
+        if let (true, Some(algorithm)) = (opened.continue_from > 0, algorithm) {
+            opened.prefix_hash_algorithm = algorithm.name().to_string();
+            opened.prefix_hash =
+                hash_prefix(&path, opened.continue_from, algorithm)?.unwrap_or_default();
+        }
+        let transfer = self.inbound_transfer(transfer_id);
+        transfer.open_file = Some(Sink::File(file));
+        transfer.open_file_path = Some(path);
+        Ok(opened)
+    }
+
+    /// Prepares `stream` for an incoming file, which is always received from the start
+    fn open_inbound_stream(
+        &mut self,
+        transfer_id: u32,
+        stream: Box<dyn Write + Send>,
+        file: &api::OpenFile,
+    ) -> api::FileOpened {
+        let algorithm =
+            HashAlgorithm::negotiated(self.client.lock().unwrap().negotiated_features());
+        let transfer = self.inbound_transfer(transfer_id);
+        transfer.current_path = None;
+        transfer.open_file = Some(Sink::Stream {
+            writer: stream,
+            position: 0,
+            hash: algorithm.map(|x| (x, x.hasher())),
+        });
+        api::FileOpened {
+            transfer_id,
+            file_id: file.file_id,
+            ..Default::default()
+        }
+    }
+
+    /// Offers the blocks of an existing file at `path` to the sender, unless it is too small
+    fn open_delta_target(
+        &mut self,
+        file: &api::OpenFile,
+        path: &Path,
+        algorithm: HashAlgorithm,
+    ) -> Result<Option<DeltaTarget>> {
+        let mut basis = File::open(path)?;
+        let block_size = block_size(basis.metadata()?.len());
+        let blocks = signatures(&mut basis, block_size, algorithm)?;
+        if blocks.is_empty() {
+            return Ok(None);
+        }
+        let mut client = self.client.lock().unwrap();
+        for (i, batch) in blocks.chunks(SIGNATURES_PER_MESSAGE).enumerate() {
+            client.send_block_signatures(api::BlockSignatures {
+                transfer_id: file.transfer_id,
+                file_id: file.file_id,
+                block_size,
+                hash_algorithm: algorithm.name().to_string(),
+                first_block: (i * SIGNATURES_PER_MESSAGE) as u64,
+                blocks: batch.to_vec(),
+            })?;
+        }
+        let name = path
+            .file_name()
+            .ok_or(anyhow!("Missing file name"))?
+            .to_string_lossy();
+        Ok(Some(DeltaTarget {
+            basis,
+            block_size,
+            temp_path: path.with_file_name(format!(".{}.shift-delta", name)),
+        }))
+    }
+
+    /// Directory times and modes are applied once the whole transfer is through, deepest first,
+    /// since writing their contents would change them again
+    fn apply_directory_info(&mut self, mut transfer: InboundTransfer) -> Result<()> {
+        let mut directories = std::mem::take(&mut transfer.received_directories);
+        directories.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, info) in directories {
+            let attributes = transfer
+                .received_attributes
+                .remove(&path)
+                .unwrap_or_default();
+            self.apply_attributes(&path, &info, &attributes)?;
+        }
+        Ok(())
+    }
+
+    fn apply_received_info(
+        &mut self,
+        transfer_id: u32,
+        path: &Path,
+        info: &api::FileInfo,
+    ) -> Result<()> {
+        let attributes = self
+            .inbound_transfer(transfer_id)
+            .received_attributes
+            .remove(path)
+            .unwrap_or_default();
+        self.apply_attributes(path, info, &attributes)
+    }
+
+    fn apply_attributes(
+        &mut self,
+        path: &Path,
+        info: &api::FileInfo,
+        attributes: &[api::ExtendedAttribute],
+    ) -> Result<()> {
+        let warnings = apply_file_info(
+            path,
+            info,
+            attributes,
+            &self.attribute_filter,
+            self.preserve_ownership,
+        )?;
+        self.warnings.extend(warnings);
+        Ok(())
+    }
+
+    /// Tells the peer why an inbound transfer is being abandoned
+    fn report_error(&mut self, transfer_id: u32, error: &anyhow::Error, file: &str) -> Result<()> {
+        let transfer = self.inbound_transfer(transfer_id);
+        transfer.open_file = None;
+        transfer.open_file_path = None;
+        transfer.open_file_name = None;
+        let mut client = self.client.lock().unwrap();
+        if client.has_feature(FEATURE_ERRORS) {
+            client.send_error(api::Error {
+                transfer_id,
+                ..api::Error::from_error(error, file)
+            })
+        } else {
+            client.close_transfer(transfer_id)
+        }
+    }
+
+    /// Answers a browse request that failed. Without error reporting, an empty answer is the
+    /// closest thing.
+    fn report_request_error(
+        &mut self,
+        request_id: u32,
+        error: &anyhow::Error,
+        path: &str,
+        send_empty: impl FnOnce(&mut ShiftClient<'a>) -> Result<()>,
+    ) -> Result<()> {
+        let mut client = self.client.lock().unwrap();
+        if client.has_feature(FEATURE_ERRORS) {
+            client.send_error(api::Error {
+                request_id,
+                ..api::Error::from_error(error, path)
+            })
+        } else {
+            send_empty(&mut client)
+        }
+    }
+
+    fn verify_received_file(
+        &mut self,
+        path: &Path,
+        file: &OpenFile,
+        close: &api::CloseFile,
+    ) -> Result<api::FileVerification> {
+        let algorithm = HashAlgorithm::from_name(&close.hash_algorithm)
+            .ok_or(anyhow!("Unknown hash algorithm: {}", close.hash_algorithm))?;
+        let hash = hash_file(path, algorithm)?;
+        self.send_verification(file, close, hash, &path.display().to_string())
+    }
+
+    /// Tells the sender whether `hash` of what was received, listed as `name` in the manifest,
+    /// is what it sent
+    fn send_verification(
+        &mut self,
+        file: &OpenFile,
+        close: &api::CloseFile,
+        hash: Vec<u8>,
+        name: &str,
+    ) -> Result<api::FileVerification> {
+        let verified = hash == close.hash;
+        if verified {
+            if let Some(manifest) = &mut self.manifest {
+                writeln!(manifest, "{}  {}", to_hex(&hash), name)?;
+                manifest.flush()?;
+            }
+        }
+        let verification = api::FileVerification {
+            name: file.info.name.clone(),
+            verified,
+            hash_algorithm: close.hash_algorithm.clone(),
+            hash,
+            transfer_id: file.transfer_id,
+            file_id: file.file_id,
+        };
+        self.client
+            .lock()
+            .unwrap()
+            .send_file_verification(verification.clone())?;
+        Ok(verification)
+    }
+
+    fn maybe_send_next_file(&mut self, transfer_id: u32) -> Result<()> {
+        let transfer = match self.outbound_transfers.get_mut(&transfer_id) {
+            Some(transfer) => transfer,
+            None => return Ok(()),
+        };
+        transfer.current_file_path = transfer.remaining_files_to_send.pop();
+
+        match &transfer.current_file_path {
+            Some(path) => {
+                let full_path = match transfer.stream {
+                    Some(_) => None,
+                    None => Some(resolve_path(&transfer.path, path)?),
+                };
+                let info = match &full_path {
+                    Some(full_path) => outbound_file_info(full_path, path, self.symlink_policy)?,
+                    None => stream_info(path),
+                };
+                let symlink = is_symlink(&info);
+                let mut client = self.client.lock().unwrap();
+                // The peer may have refused the transfer in the meantime
+                if client.transfer_state(transfer_id).is_none() {
+                    return Ok(());
                 }
-                None => {
-                    self.client.lock().unwrap().close_transfer()?;
+                let file_id = client.open_file(api::OpenFile {
+                    file_info: Some(info),
+                    transfer_id,
+                    file_id: 0,
+                })?;
+                let attributes_path =
+                    full_path.filter(|_| client.has_feature(FEATURE_XATTRS) && !symlink);
+                if let Some(full_path) = attributes_path {
+                    match read_attributes(&full_path, &self.attribute_filter) {
+                        Ok(attributes) if attributes.is_empty() => {}
+                        Ok(attributes) => client.send_file_attributes(api::FileAttributes {
+                            attributes,
+                            transfer_id,
+                            file_id,
+                        })?,
+                        Err(error) => self.warnings.push(format!(
+                            "Could not read attributes of {}: {}",
+                            full_path.display(),
+                            error
+                        )),
+                    }
                 }
             }
+            None => {
+                self.client.lock().unwrap().close_transfer(transfer_id)?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn receive(&mut self) -> Result<()> {
+    /// Asks the peer for files and returns the ID of the new transfer
+    pub fn receive(&mut self) -> Result<u32> {
+        self.receive_selected(vec![], vec![])
+    }
+
+    /// Asks the peer for the given paths and everything matching the given glob patterns, and
+    /// returns the ID of the new transfer
+    pub fn receive_selected(&mut self, paths: Vec<String>, patterns: Vec<String>) -> Result<u32> {
         self.client
             .lock()
             .unwrap()
             .request_inbound_transfer(api::ReceiveRequest {
                 allow_directories: false,
                 allow_multiple: true,
+                transfer_id: 0,
+                paths,
+                patterns,
             })
     }
 
-    fn send_file(&mut self, path: &Path, callback: ProgressCallback<'a>) -> Result<()> {
+    /// Offers the peer a transfer of `file_info`, or answers its request for one
+    fn request_outbound_transfer(&mut self, file_info: api::FileInfo) -> Result<u32> {
+        let mut client = self.client.lock().unwrap();
+        let mut request = api::SendRequest {
+            file_info: Some(file_info),
+            transfer_id: self.requested_transfer_id.take().unwrap_or(0),
+            ..Default::default()
+        };
+        if !self.destination.is_empty() || !self.tags.is_empty() {
+            if client.has_feature(FEATURE_DESTINATION_HINTS) {
+                request.destination = self.destination.clone();
+                request.tags = self.tags.clone();
+            } else {
+                self.warnings.push(
+                    "The peer does not support destination hints, it decides where files go"
+                        .to_string(),
+                );
+            }
+        }
+        client.request_outbound_transfer(request)
+    }
+
+    fn send_file(
+        &mut self,
+        path: &Path,
+        name: String,
+        remaining_files_to_send: Vec<PathBuf>,
+        callback: ProgressCallback<'a>,
+    ) -> Result<u32> {
+        #[allow(clippy::needless_borrows_for_generic_args)]
         let meta = std::fs::metadata(&path)?;
-        let mode = get_file_mode(&meta);
+        // Files are sent from the back of the list
+        let entries = remaining_files_to_send
+            .iter()
+            .rev()
+            .map(|x| outbound_file_info(&resolve_path(path, x)?, x, self.symlink_policy))
+            .collect::<Result<Vec<_>>>()?;
+        let regular_files = entries.iter().filter(|x| is_regular(x));
+        let mut manifest = api::TransferManifest {
+            file_count: regular_files.clone().count() as u64,
+            total_bytes: regular_files.map(|x| x.size).sum(),
+            entries,
+            ..Default::default()
+        };
+
+        let transfer_id = self.request_outbound_transfer(file_info(name, &meta))?;
+        manifest.transfer_id = transfer_id;
+
+        self.outbound_transfers.insert(
+            transfer_id,
+            OutboundTransfer {
+                path: PathBuf::from(path),
+                stream: None,
+                current_file_path: None,
+                remaining_files_to_send,
+                total_bytes_sent: 0,
+                total_bytes_to_send: manifest.total_bytes,
+                manifest,
+                progress_callback: Arc::new(Mutex::new(callback)),
+                signatures: None,
+            },
+        );
+        Ok(transfer_id)
+    }
+
+    /// Offers data of unknown size, such as a pipe, as a file called `name` and returns the ID
+    /// of the new transfer. The progress callback gets 0 as the total.
+    pub fn send_stream(
+        &mut self,
+        name: &str,
+        stream: Box<dyn Read + Send + 'a>,
+        callback: ProgressCallback<'a>,
+    ) -> Result<u32> {
+        let transfer_id = self.request_outbound_transfer(stream_info(Path::new(name)))?;
+        let entry = stream_info(Path::new("."));
+        self.outbound_transfers.insert(
+            transfer_id,
+            OutboundTransfer {
+                path: PathBuf::new(),
+                stream: Some(stream),
+                current_file_path: None,
+                remaining_files_to_send: vec![PathBuf::from(&entry.name)],
+                total_bytes_sent: 0,
+                total_bytes_to_send: 0,
+                manifest: api::TransferManifest {
+                    transfer_id,
+                    file_count: 1,
+                    total_bytes: 0,
+                    entries: vec![entry],
+                },
+                progress_callback: Arc::new(Mutex::new(callback)),
+                signatures: None,
+            },
+        );
+        Ok(transfer_id)
+    }
+
+    /// Answers a peer's `ReceiveRequest` that cannot be served. Without error reporting, the
+    /// only way to tell the peer is to disconnect.
+    pub fn refuse(&mut self, request: &api::ReceiveRequest, error: &anyhow::Error) -> Result<()> {
+        let mut client = self.client.lock().unwrap();
+        if client.has_feature(FEATURE_ERRORS) {
+            client.send_error(api::Error {
+                transfer_id: request.transfer_id,
+                ..api::Error::from_error(error, "")
+            })
+        } else {
+            client.disconnect()
+        }
+    }
+
+    /// Offers a file or directory to the peer and returns the ID of the new transfer
+    pub fn send(&mut self, path: &Path, callback: ProgressCallback<'a>) -> Result<u32> {
+        let name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_string())
+            .ok_or(anyhow!("Could not determine file name"))?;
+        if !path.is_dir() {
+            return self.send_file(path, name, vec![PathBuf::from(".")], callback);
+        }
 
-        self.send_progress_callback = Some(Arc::new(Mutex::new(callback)));
+        let entries = self.walk(path)?;
+        let remaining_files_to_send = entries
+            .iter()
+            .try_fold(vec![], |mut acc, entry| -> Result<_> {
+                acc.push(
+                    pathdiff::diff_paths(entry.path(), path)
+                        .ok_or(anyhow!("Could not determine relative path"))?,
+                );
+                Ok(acc)
+            })?
+            .into_iter()
+            .filter(|p| !p.eq(&PathBuf::from("")))
+            .collect();
 
+        self.send_file(path, name, remaining_files_to_send, callback)
+    }
+
+    /// Offers `items`, given relative to the directory `root`, as one transfer that keeps
+    /// their paths, and returns the ID of the new transfer
+    pub fn send_selection(
+        &mut self,
+        root: &Path,
+        items: &[PathBuf],
+        callback: ProgressCallback<'a>,
+    ) -> Result<u32> {
+        let mut remaining_files_to_send = BTreeSet::new();
+        for item in items {
+            for entry in self.walk(&root.join(item))? {
+                let path = pathdiff::diff_paths(entry.path(), root)
+                    .ok_or(anyhow!("Could not determine relative path"))?;
+                if path != Path::new("") {
+                    remaining_files_to_send.insert(path);
+                }
+            }
+        }
+        self.send_file(
+            root,
+            ".".to_string(),
+            remaining_files_to_send.into_iter().collect(),
+            callback,
+        )
+    }
+
+    /// Everything in and below `path` that can be sent, including itself
+    fn walk(&mut self, path: &Path) -> Result<Vec<walkdir::DirEntry>> {
+        let preserve_symlinks = self.symlink_policy == SymlinkPolicy::Preserve
+            && self.client.lock().unwrap().has_feature(FEATURE_SYMLINKS);
+        walkdir::WalkDir::new(path)
+            .follow_links(self.symlink_policy == SymlinkPolicy::Follow)
+            .into_iter()
+            .try_fold(vec![], |mut acc, entry| -> Result<_> {
+                let entry = entry?;
+                let file_type = entry.file_type();
+                if file_type.is_symlink() && !preserve_symlinks {
+                    if self.symlink_policy == SymlinkPolicy::Preserve {
+                        self.warnings.push(format!(
+                            "Skipping link {}, the peer does not support links",
+                            entry.path().display()
+                        ));
+                    }
+                } else if !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink() {
+                    self.warnings
+                        .push(format!("Skipping special file {}", entry.path().display()));
+                } else {
+                    acc.push(entry);
+                }
+                Ok(acc)
+            })
+    }
+
+    /// Asks the peer for a page of the entries of a directory it offers and returns the ID of
+    /// the request
+    pub fn list_directory(&mut self, path: &str, offset: u64) -> Result<u32> {
         self.client
             .lock()
             .unwrap()
-            .request_outbound_transfer(api::SendRequest {
-                file_info: Some(api::FileInfo {
-                    name: path
-                        .file_name()
-                        .and_then(|x| x.to_str())
-                        .map(|x| x.to_string())
-                        .ok_or(anyhow!("Could not determine file name"))?,
-                    size: meta.len(),
-                    mode,
-                }),
-            })?;
+            .list_directory(api::ListDirectory {
+                path: path.to_string(),
+                offset,
+                ..Default::default()
+            })
+    }
 
-        self.current_transfer_path = Some(PathBuf::from(path));
-        self.total_bytes_sent = 0;
-        self.total_bytes_to_send = meta.len();
-        Ok(())
+    /// Asks the peer about a path it offers and returns the ID of the request
+    pub fn stat(&mut self, path: &str) -> Result<u32> {
+        self.client.lock().unwrap().stat(api::Stat {
+            path: path.to_string(),
+            ..Default::default()
+        })
     }
 
-    pub fn send(&mut self, path: &Path, callback: ProgressCallback<'a>) -> Result<()> {
-        self.send_file(path, callback)?;
+    pub fn pause_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.client.lock().unwrap().pause_transfer(transfer_id)
+    }
 
-        if path.is_dir() {
-            self.current_transfer_path = Some(PathBuf::from(path));
+    pub fn resume_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.client.lock().unwrap().resume_transfer(transfer_id)
+    }
 
-            let entries = walkdir::WalkDir::new(path).into_iter().try_fold(
-                vec![],
-                |mut acc, entry| -> Result<_> {
-                    acc.push(entry?);
-                    Ok(acc)
-                },
-            )?;
-
-            self.remaining_files_to_send = Some(
-                entries
-                    .iter()
-                    .try_fold(vec![], |mut acc, entry| -> Result<_> {
-                        acc.push(
-                            pathdiff::diff_paths(entry.path(), path)
-                                .ok_or(anyhow!("Could not determine relative path"))?,
-                        );
-                        Ok(acc)
-                    })?
-                    .into_iter()
-                    .filter(|p| !p.eq(&PathBuf::from("")))
-                    .collect(),
-            );
-
-            self.total_bytes_sent = 0;
-            self.total_bytes_to_send = entries
-                .iter()
-                .try_fold(0, |acc, p| -> Result<u64> { Ok(acc + p.metadata()?.len()) })?;
-        } else {
-            self.remaining_files_to_send = Some(vec![PathBuf::from(".")]);
-        }
+    pub fn has_active_transfers(&self) -> bool {
+        !self.client.lock().unwrap().active_transfers().is_empty()
+    }
 
-        Ok(())
+    /// Pauses all active transfers, or resumes them if they are all paused already. Returns
+    /// whether they are paused now.
+    pub fn toggle_pause(&mut self) -> Result<bool> {
+        let mut client = self.client.lock().unwrap();
+        let transfers = client.active_transfers();
+        let pause = transfers.iter().any(|x| !client.is_paused(*x));
+        for transfer_id in transfers {
+            if pause {
+                client.pause_transfer(transfer_id)?;
+            } else {
+                client.resume_transfer(transfer_id)?;
+            }
+        }
+        Ok(pause)
     }
 
     pub fn disconnect(&mut self) -> Result<()> {
         self.client.lock().unwrap().disconnect()
     }
 }
+
+fn not_shared() -> anyhow::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "Nothing is shared for browsing",
+    )
+    .into()
+}
+
+/// Describes the entry at `full_path`, named `path` within its transfer, as it will be sent
+fn outbound_file_info(
+    full_path: &Path,
+    path: &Path,
+    symlink_policy: SymlinkPolicy,
+) -> Result<api::FileInfo> {
+    let meta = match symlink_policy {
+        SymlinkPolicy::Follow => std::fs::metadata(full_path)?,
+        _ => std::fs::symlink_metadata(full_path)?,
+    };
+    let mut info = file_info(path.to_string_lossy().to_string(), &meta);
+    if is_symlink(&info) {
+        info.link_target = std::fs::read_link(full_path)?
+            .to_str()
+            .ok_or(anyhow!("Link target is not valid UTF-8"))?
+            .to_string();
+    }
+    Ok(info)
+}
+
+/// Describes data of unknown size, which gets the receiver's default mode and times
+fn stream_info(path: &Path) -> api::FileInfo {
+    api::FileInfo {
+        name: path.to_string_lossy().to_string(),
+        size_unknown: true,
+        ..Default::default()
+    }
+}
+
+/// Resolves a path relative to an outbound transfer, where "." is the transfer itself
+fn resolve_path(transfer_path: &Path, path: &Path) -> Result<PathBuf> {
+    if path == Path::new(".") {
+        return Ok(std::fs::canonicalize(transfer_path)?);
+    }
+    // Leave the entry itself unresolved, it may be a link that is sent as such
+    Ok(std::fs::canonicalize(transfer_path)?.join(path))
+}
@@ -0,0 +1,312 @@
+use anyhow::{anyhow, bail, Result};
+use path_clean::PathClean;
+use shift::api::{self, file_info::FileType};
+use std::fs::{File, FileTimes, Metadata};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+#[cfg(target_family = "unix")]
+fn get_file_mode(metadata: &Metadata) -> u32 {
+    metadata.permissions().mode()
+}
+
+#[cfg(target_family = "windows")]
+fn get_file_mode(metadata: &Metadata) -> u32 {
+    if metadata.is_dir() {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn get_ownership(metadata: &Metadata) -> Option<api::Ownership> {
+    Some(api::Ownership {
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    })
+}
+
+#[cfg(target_family = "windows")]
+fn get_ownership(_metadata: &Metadata) -> Option<api::Ownership> {
+    None
+}
+
+pub fn to_nanos(time: io::Result<SystemTime>) -> i64 {
+    match time.map(|x| x.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(duration)) => duration.as_nanos() as i64,
+        Ok(Err(before_epoch)) => -(before_epoch.duration().as_nanos() as i64),
+        Err(_) => 0,
+    }
+}
+
+pub fn from_nanos(nanos: i64) -> SystemTime {
+    if nanos >= 0 {
+        UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_nanos(nanos.unsigned_abs())
+    }
+}
+
+pub fn file_info(name: String, metadata: &Metadata) -> api::FileInfo {
+    let mut info = api::FileInfo {
+        name,
+        size: metadata.len(),
+        mode: get_file_mode(metadata),
+        mtime: to_nanos(metadata.modified()),
+        atime: to_nanos(metadata.accessed()),
+        owner: get_ownership(metadata),
+        ..Default::default()
+    };
+    if metadata.is_dir() {
+        info.set_file_type(FileType::Directory);
+    } else if metadata.file_type().is_symlink() {
+        info.set_file_type(FileType::Symlink);
+        info.size = 0;
+    }
+    info
+}
+
+pub fn is_directory(info: &api::FileInfo) -> bool {
+    // Peers predating file types only mark directories in the mode bits
+    info.file_type() == FileType::Directory || info.mode & 0o40000 != 0
+}
+
+pub fn is_symlink(info: &api::FileInfo) -> bool {
+    info.file_type() == FileType::Symlink
+}
+
+/// Only regular files carry data, the size of a directory means nothing at the destination
+pub fn is_regular(info: &api::FileInfo) -> bool {
+    !is_directory(info) && !is_symlink(info)
+}
+
+/// Makes sure a received link at `path`, named `name` within the transfer, cannot point outside the
+/// transfer's destination directory
+pub fn check_link_target(path: &Path, name: &str, target: &str) -> Result<()> {
+    let depth = Path::new(name)
+        .components()
+        .filter(|x| matches!(x, Component::Normal(_)))
+        .count();
+    // Relative paths run out of ancestors at "", which is the current directory
+    let canonicalize = |x: &Path| match x.as_os_str().is_empty() {
+        true => Path::new(".").canonicalize(),
+        false => x.canonicalize(),
+    };
+    let root = canonicalize(
+        path.ancestors()
+            .nth(std::cmp::max(depth, 1))
+            .ok_or(anyhow!("Cannot determine destination directory"))?,
+    )?;
+    let parent = canonicalize(
+        path.parent()
+            .ok_or(anyhow!("Cannot operate on filesystem root"))?,
+    )?;
+
+    // `..` after a named component could walk back out through another link, so only allow it up front
+    let mut seen_name = false;
+    for component in Path::new(target).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => {
+                bail!("Refusing absolute link target {}", target)
+            }
+            Component::ParentDir if seen_name => {
+                bail!("Refusing link target {} with an inner ..", target)
+            }
+            Component::Normal(_) => seen_name = true,
+            _ => {}
+        }
+    }
+
+    let resolved: PathBuf = parent.join(target).clean();
+    if !resolved.starts_with(&root) {
+        bail!("Refusing link target {} outside of the destination", target);
+    }
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+pub fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(target_family = "windows")]
+pub fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+#[cfg(target_family = "unix")]
+fn apply_ownership(path: &Path, owner: &api::Ownership) -> Result<()> {
+    match std::os::unix::fs::chown(path, Some(owner.uid), Some(owner.gid)) {
+        // Only root may give files away, so this is best effort for everyone else
+        Err(error) if error.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn apply_ownership(_path: &Path, _owner: &api::Ownership) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+fn apply_mode(path: &Path, mode: u32) -> Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+fn apply_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+#[cfg(target_family = "windows")]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    // FILE_FLAG_BACKUP_SEMANTICS is needed to open directories
+    std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(0x02000000)
+        .open(path)
+}
+
+/// Applies the sender's ownership, attributes, timestamps and mode to a received file, returning
+/// warnings for attributes that could not be set. Changing the owner may drop attributes, and the
+/// mode goes last so that a read-only file can still be updated.
+pub fn apply_file_info(
+    path: &Path,
+    info: &api::FileInfo,
+    attributes: &[api::ExtendedAttribute],
+    filter: &AttributeFilter,
+    preserve_ownership: bool,
+) -> Result<Vec<String>> {
+    if preserve_ownership {
+        if let Some(owner) = &info.owner {
+            apply_ownership(path, owner)?;
+        }
+    }
+    let warnings = apply_attributes(path, attributes, filter);
+    if info.mtime != 0 {
+        let mut times = FileTimes::new().set_modified(from_nanos(info.mtime));
+        if info.atime != 0 {
+            times = times.set_accessed(from_nanos(info.atime));
+        }
+        open_for_times(path)?.set_times(times)?;
+    }
+    if info.mode != 0 {
+        apply_mode(path, info.mode)?;
+    }
+    Ok(warnings)
+}
+
+/// Which extended attributes to send and apply, as exact names or namespace patterns like
+/// `security.*`. Denied patterns take precedence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl Default for AttributeFilter {
+    fn default() -> Self {
+        Self {
+            allow: vec![
+                "user.*".to_string(),
+                "system.posix_acl_access".to_string(),
+                "system.posix_acl_default".to_string(),
+            ],
+            deny: vec![],
+        }
+    }
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+impl AttributeFilter {
+    /// Builds a filter from command line style options, keeping the default allow list if none is given
+    pub fn from_options(allow: Vec<String>, deny: Vec<String>) -> Self {
+        let mut filter = Self::default();
+        if !allow.is_empty() {
+            filter.allow = allow;
+        }
+        filter.deny = deny;
+        filter
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        self.allow.iter().any(|x| matches_pattern(x, name))
+            && !self.deny.iter().any(|x| matches_pattern(x, name))
+    }
+}
+
+#[cfg(target_family = "unix")]
+pub fn read_attributes(
+    path: &Path,
+    filter: &AttributeFilter,
+) -> Result<Vec<api::ExtendedAttribute>> {
+    let mut attributes = vec![];
+    for name in xattr::list(path)? {
+        let name = match name.to_str() {
+            Some(name) if filter.allows(name) => name.to_string(),
+            _ => continue,
+        };
+        if let Some(value) = xattr::get(path, &name)? {
+            attributes.push(api::ExtendedAttribute { name, value });
+        }
+    }
+    Ok(attributes)
+}
+
+#[cfg(target_family = "windows")]
+pub fn read_attributes(
+    _path: &Path,
+    _filter: &AttributeFilter,
+) -> Result<Vec<api::ExtendedAttribute>> {
+    Ok(vec![])
+}
+
+/// Applies the allowed attributes, returning a warning for each one that could not be set
+#[cfg(target_family = "unix")]
+pub fn apply_attributes(
+    path: &Path,
+    attributes: &[api::ExtendedAttribute],
+    filter: &AttributeFilter,
+) -> Vec<String> {
+    attributes
+        .iter()
+        .filter(|x| filter.allows(&x.name))
+        .filter_map(|x| {
+            xattr::set(path, &x.name, &x.value).err().map(|error| {
+                format!(
+                    "Could not set attribute {} on {}: {}",
+                    x.name,
+                    path.display(),
+                    error
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_family = "windows")]
+pub fn apply_attributes(
+    _path: &Path,
+    _attributes: &[api::ExtendedAttribute],
+    _filter: &AttributeFilter,
+) -> Vec<String> {
+    vec![]
+}
@@ -0,0 +1,99 @@
+#[cfg(test)]
+use super::metadata::{apply_file_info, check_link_target, from_nanos, to_nanos, AttributeFilter};
+#[cfg(test)]
+use shift::api;
+#[cfg(test)]
+use std::io;
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn test_link_targets_stay_in_destination() {
+    let root = std::env::temp_dir().join(format!("shift-links-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("transfer/sub")).unwrap();
+    let link = root.join("transfer/sub/link");
+
+    assert!(check_link_target(&link, "sub/link", "file").is_ok());
+    assert!(check_link_target(&link, "sub/link", "../file").is_ok());
+    assert!(check_link_target(&link, "sub/link", "./other/file").is_ok());
+    assert!(check_link_target(&link, "sub/link", "../../file").is_err());
+    assert!(check_link_target(&link, "sub/link", "/etc/passwd").is_err());
+    assert!(check_link_target(&link, "sub/link", "other/../../file").is_err());
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_attribute_filter() {
+    let filter = AttributeFilter::default();
+    assert!(filter.allows("user.tag"));
+    assert!(filter.allows("system.posix_acl_access"));
+    assert!(!filter.allows("security.selinux"));
+
+    let filter = AttributeFilter::from_options(
+        vec!["security.*".to_string(), "user.*".to_string()],
+        vec!["user.private".to_string()],
+    );
+    assert!(filter.allows("security.selinux"));
+    assert!(filter.allows("user.tag"));
+    assert!(!filter.allows("user.private"));
+    assert!(!filter.allows("trusted.tag"));
+}
+
+#[test]
+fn test_nanos_round_trip() {
+    for nanos in [0, 1, 1_600_000_000_123_456_789, -1, -1_500_000_000] {
+        assert_eq!(to_nanos(Ok(from_nanos(nanos))), nanos);
+    }
+    assert_eq!(
+        from_nanos(-1_500_000_000),
+        UNIX_EPOCH - Duration::from_millis(1500)
+    );
+    assert_eq!(to_nanos(Err(io::ErrorKind::Unsupported.into())), 0);
+}
+
+#[cfg(target_family = "unix")]
+#[test]
+fn test_apply_file_info() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = std::env::temp_dir().join(format!("shift-apply-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let path = root.join("file");
+    std::fs::write(&path, b"data").unwrap();
+
+    let info = api::FileInfo {
+        name: "file".to_string(),
+        mtime: -1_500_000_000,
+        atime: 1_600_000_000_123_456_789,
+        mode: 0o100444,
+        ..Default::default()
+    };
+    let attributes = [api::ExtendedAttribute {
+        name: "user.tag".to_string(),
+        value: b"value".to_vec(),
+    }];
+    let warnings = apply_file_info(
+        &path,
+        &info,
+        &attributes,
+        &AttributeFilter::default(),
+        false,
+    )
+    .unwrap();
+    // Attributes go on before the mode makes the file read-only
+    if xattr::SUPPORTED_PLATFORM && !warnings.iter().any(|x| x.contains("not supported")) {
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(
+            xattr::get(&path, "user.tag").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.modified().unwrap(), from_nanos(info.mtime));
+    assert_eq!(metadata.accessed().unwrap(), from_nanos(info.atime));
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o444);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
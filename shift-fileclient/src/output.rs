@@ -0,0 +1,89 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Writes at least this large wait while the queue is full
+const LARGE_WRITE: usize = 4096;
+const MAX_QUEUED: usize = 4 * 1024 * 1024;
+
+#[derive(Default)]
+struct QueueState {
+    queued: usize,
+    closed: bool,
+}
+
+/// Bytes handed to a [`QueuedWriter`] that were not written yet
+#[derive(Default)]
+pub struct Queue {
+    state: Mutex<QueueState>,
+    condvar: Condvar,
+}
+
+impl Queue {
+    /// Fails all further writes, including those waiting for room in the queue.
+    /// What was queued before is still written.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.condvar.notify_all();
+    }
+}
+
+/// Hands writes to a thread of their own, so that a stalled peer cannot block whoever is
+/// writing, which usually holds the client. Only large writes such as chunk data wait for
+/// room in the queue, small messages like pings never do.
+pub struct QueuedWriter {
+    tx: Sender<Vec<u8>>,
+    queue: Arc<Queue>,
+}
+
+impl QueuedWriter {
+    pub fn new(mut stream: Box<dyn Write + Send>) -> Self {
+        let (tx, rx) = channel::<Vec<u8>>();
+        let queue = Arc::new(Queue::default());
+        std::thread::spawn({
+            let queue = queue.clone();
+            move || -> io::Result<()> {
+                for data in rx {
+                    stream.write_all(&data)?;
+                    stream.flush()?;
+                    queue.state.lock().unwrap().queued -= data.len();
+                    queue.condvar.notify_all();
+                }
+                Ok(())
+            }
+        });
+        QueuedWriter { tx, queue }
+    }
+
+    pub fn queue(&self) -> Arc<Queue> {
+        self.queue.clone()
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Output is closed")
+}
+
+impl Write for QueuedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.queue.state.lock().unwrap();
+        if buf.len() >= LARGE_WRITE {
+            state = self
+                .queue
+                .condvar
+                .wait_while(state, |x| x.queued > MAX_QUEUED && !x.closed)
+                .unwrap();
+        }
+        if state.closed {
+            return Err(closed_error());
+        }
+        state.queued += buf.len();
+        drop(state);
+        self.tx.send(buf.to_vec()).map_err(|_| closed_error())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use super::browse::is_relative_and_down;
+use super::metadata::{is_directory, is_regular};
+use anyhow::Result;
+use shift::api;
+use std::io;
+use std::path::Path;
+
+/// What receiving a transfer into a directory runs into, as far as can be told up front
+#[derive(Debug, Default, PartialEq)]
+pub struct Preflight {
+    /// Regular files that exist already and will be resumed or updated
+    pub existing: Vec<String>,
+    /// Bytes that still have to be written
+    pub needed_bytes: u64,
+    /// Free space at the destination, if the platform can tell
+    pub available_bytes: Option<u64>,
+}
+
+#[cfg(target_family = "unix")]
+fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain old data, for which all zeroes is a valid value
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid NUL-terminated CString and `stat` is a properly sized out-parameter,
+    // both of which outlive the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(target_family = "windows")]
+fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Checks the entries of `manifest` against `destination`, where the transfer would be written,
+/// and fails if they collide with what is there or do not fit
+pub fn preflight(destination: &Path, manifest: &api::TransferManifest) -> Result<Preflight> {
+    let mut result = Preflight::default();
+    for entry in &manifest.entries {
+        if !is_relative_and_down(Path::new(&entry.name)) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is outside of the destination", entry.name),
+            )
+            .into());
+        }
+        let path = destination.join(&entry.name);
+        let existing = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotADirectory => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} cannot be created, a file is in the way", entry.name),
+                )
+                .into());
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                if is_regular(entry) {
+                    result.needed_bytes += entry.size;
+                }
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        // Links are replaced or written through
+        if !existing.file_type().is_symlink() && is_directory(entry) != existing.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                match is_directory(entry) {
+                    true => format!("{} exists and is not a directory", entry.name),
+                    false => format!("{} exists and is a directory", entry.name),
+                },
+            )
+            .into());
+        }
+        if existing.is_file() && is_regular(entry) {
+            result.existing.push(entry.name.clone());
+            result.needed_bytes += entry.size.saturating_sub(existing.len());
+        }
+    }
+
+    let mut existing_ancestor = destination;
+    while !existing_ancestor.exists() {
+        existing_ancestor = match existing_ancestor.parent() {
+            Some(parent) => parent,
+            None => return Ok(result),
+        };
+    }
+    result.available_bytes = available_space(existing_ancestor)?;
+    if let Some(available) = result.available_bytes {
+        if result.needed_bytes > available {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "Not enough space: {} bytes needed, {} available",
+                    result.needed_bytes, available
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,57 @@
+#[cfg(test)]
+use super::preflight::preflight;
+#[cfg(test)]
+use shift::api::{self, file_info::FileType};
+
+#[cfg(test)]
+fn entry(name: &str, size: u64, file_type: FileType) -> api::FileInfo {
+    let mut info = api::FileInfo {
+        name: name.to_string(),
+        size,
+        ..Default::default()
+    };
+    info.set_file_type(file_type);
+    info
+}
+
+#[test]
+fn test_preflight() {
+    let root = std::env::temp_dir().join(format!("shift-preflight-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("dir")).unwrap();
+    std::fs::write(root.join("dir/partial"), "1234").unwrap();
+
+    let mut manifest = api::TransferManifest {
+        entries: vec![
+            entry("dir", 4096, FileType::Directory),
+            entry("dir/partial", 10, FileType::Regular),
+            entry("dir/new", 5, FileType::Regular),
+        ],
+        ..Default::default()
+    };
+    let result = preflight(&root, &manifest).unwrap();
+    assert_eq!(result.existing, ["dir/partial"]);
+    assert_eq!(result.needed_bytes, 6 + 5);
+    if cfg!(target_family = "unix") {
+        assert!(result.available_bytes.is_some());
+    }
+
+    manifest.entries[1].set_file_type(FileType::Directory);
+    assert!(preflight(&root, &manifest).is_err());
+
+    manifest.entries = vec![entry("dir/huge", u64::MAX, FileType::Regular)];
+    let error = preflight(&root, &manifest).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<std::io::Error>().unwrap().kind(),
+        std::io::ErrorKind::StorageFull
+    );
+
+    manifest.entries = vec![entry("../outside", 1, FileType::Regular)];
+    assert!(preflight(&root, &manifest).is_err());
+
+    // Nothing needs to exist yet
+    manifest.entries = vec![entry("a", 1, FileType::Regular)];
+    let result = preflight(&root.join("not/yet"), &manifest).unwrap();
+    assert_eq!(result.needed_bytes, 1);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
+
+use super::api::{self, chunk::Compression};
+use super::constants::{FEATURE_COMPRESSION_DEFLATE, MAX_WINDOW_SIZE};
+
+impl Compression {
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Deflate => Some(FEATURE_COMPRESSION_DEFLATE),
+        }
+    }
+
+    /// Picks the first compression algorithm from a list of negotiated features
+    pub fn negotiated(features: &[String]) -> Option<Self> {
+        features.iter().find_map(|feature| match feature.as_str() {
+            FEATURE_COMPRESSION_DEFLATE => Some(Compression::Deflate),
+            _ => None,
+        })
+    }
+}
+
+/// Number of file bytes a chunk covers, regardless of how it is encoded on the wire
+pub fn chunk_length(chunk: &api::Chunk) -> u64 {
+    match chunk.compression() {
+        Compression::None if chunk.hole_length > 0 => chunk.hole_length,
+        Compression::None => chunk.data.len() as u64,
+        _ => chunk.uncompressed_length,
+    }
+}
+
+/// Compresses the chunk data, falling back to sending it as-is if it doesn't get any smaller
+pub fn compress_chunk(offset: u64, data: &[u8], compression: Compression) -> Result<api::Chunk> {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(data)?;
+            Some(encoder.finish()?)
+        }
+    };
+
+    let mut chunk = api::Chunk {
+        offset,
+        data: vec![],
+        compression: Compression::None as i32,
+        uncompressed_length: 0,
+        hole_length: 0,
+        transfer_id: 0,
+        file_id: 0,
+        end_of_stream: false,
+        restart: false,
+    };
+    match compressed {
+        Some(compressed) if compressed.len() < data.len() => {
+            chunk.data = compressed;
+            chunk.set_compression(compression);
+            chunk.uncompressed_length = data.len() as u64;
+        }
+        _ => chunk.data = data.to_vec(),
+    }
+    Ok(chunk)
+}
+
+pub fn decompress_chunk(chunk: api::Chunk) -> Result<api::Chunk> {
+    let data = match Compression::from_i32(chunk.compression) {
+        Some(Compression::None) => return Ok(chunk),
+        // The peer declares the length, so a small payload could otherwise claim gigabytes
+        Some(_) if chunk.uncompressed_length > MAX_WINDOW_SIZE => {
+            return Err(anyhow!(
+                "Compressed chunk of {} bytes is larger than the receive window",
+                chunk.uncompressed_length
+            ))
+        }
+        Some(Compression::Deflate) => {
+            let mut data = vec![];
+            // Read one byte past the declared length so oversized payloads are caught without inflating them fully
+            DeflateDecoder::new(&chunk.data[..])
+                .take(chunk.uncompressed_length + 1)
+                .read_to_end(&mut data)?;
+            data
+        }
+        None => return Err(anyhow!("Unknown chunk compression {}", chunk.compression)),
+    };
+    if data.len() as u64 != chunk.uncompressed_length {
+        return Err(anyhow!("Decompressed chunk length does not match"));
+    }
+    Ok(api::Chunk {
+        offset: chunk.offset,
+        data,
+        compression: Compression::None as i32,
+        uncompressed_length: 0,
+        hole_length: 0,
+        transfer_id: chunk.transfer_id,
+        file_id: chunk.file_id,
+        end_of_stream: chunk.end_of_stream,
+        restart: chunk.restart,
+    })
+}
@@ -1,6 +1,56 @@
-use super::transport::TransportConfig;
+/// OSC number of the default framing, `ESC ]1337;SHIFT; ... BEL`
+pub const DEFAULT_OSC: u32 = 1337;
+/// Longest frame payload read by default, well above what a chunk or a large manifest takes
+pub const MAX_PAYLOAD_LENGTH: usize = 16 * 1024 * 1024;
 
-pub const TRANSPORT: TransportConfig = TransportConfig {
-    prefix: "\x1b]1337;SHIFT;".as_bytes(),
-    suffix: "\x07".as_bytes(),
-};
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+pub const FEATURE_HASH_SHA256: &str = "hash-sha256";
+pub const FEATURE_HASH_BLAKE3: &str = "hash-blake3";
+pub const FEATURE_FLOW_CONTROL: &str = "flow-control";
+pub const FEATURE_COMPRESSION_DEFLATE: &str = "compression-deflate";
+pub const FEATURE_ERRORS: &str = "errors";
+pub const FEATURE_SYMLINKS: &str = "symlinks";
+pub const FEATURE_SPARSE: &str = "sparse";
+pub const FEATURE_XATTRS: &str = "xattrs";
+pub const FEATURE_MULTIPLEX: &str = "multiplex";
+pub const FEATURE_DELTA: &str = "delta";
+pub const FEATURE_BROWSE: &str = "browse";
+pub const FEATURE_KEEPALIVE: &str = "keepalive";
+pub const FEATURE_REJECT_FILE: &str = "reject-file";
+pub const FEATURE_PAUSE: &str = "pause";
+pub const FEATURE_MANIFEST: &str = "manifest";
+pub const FEATURE_STREAMS: &str = "streams";
+pub const FEATURE_DESTINATION_HINTS: &str = "destination-hints";
+pub const FEATURE_ENCODING_YENC: &str = "encoding-yenc";
+pub const FEATURE_ENCODING_Z85: &str = "encoding-z85";
+pub const FEATURE_RESTART: &str = "restart";
+
+/// Optional protocol extensions this implementation can negotiate during `Init`, in order of preference
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_HASH_SHA256,
+    FEATURE_HASH_BLAKE3,
+    FEATURE_FLOW_CONTROL,
+    FEATURE_COMPRESSION_DEFLATE,
+    FEATURE_ERRORS,
+    FEATURE_SYMLINKS,
+    FEATURE_SPARSE,
+    FEATURE_XATTRS,
+    FEATURE_MULTIPLEX,
+    FEATURE_DELTA,
+    FEATURE_BROWSE,
+    FEATURE_KEEPALIVE,
+    FEATURE_REJECT_FILE,
+    FEATURE_PAUSE,
+    FEATURE_MANIFEST,
+    FEATURE_STREAMS,
+    FEATURE_DESTINATION_HINTS,
+    FEATURE_ENCODING_YENC,
+    FEATURE_ENCODING_Z85,
+    FEATURE_RESTART,
+];
+
+/// Largest amount of unacknowledged chunk data we're willing to receive, advertised in `Init`
+pub const MAX_WINDOW_SIZE: u64 = 4 * 1024 * 1024;
+pub const INITIAL_WINDOW_SIZE: u64 = 1024 * 1024;
+pub const MIN_WINDOW_SIZE: u64 = 64 * 1024;
@@ -0,0 +1,144 @@
+use super::api;
+use super::hash::HashAlgorithm;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Read;
+
+/// Smallest block size a file is split into, and that signatures from a peer may use
+pub const MIN_BLOCK_SIZE: u64 = 4 * 1024;
+/// Largest block size a file is split into, and that signatures from a peer may use
+pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// How many block signatures go into one `BlockSignatures` message
+pub const SIGNATURES_PER_MESSAGE: usize = 4096;
+
+/// Picks a block size around the square root of the file size, like rsync does
+pub fn block_size(size: u64) -> u64 {
+    ((size as f64).sqrt() as u64).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE) / 1024 * 1024
+}
+
+/// rsync's weak checksum, which can be moved along the data one byte at a time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(data: &[u8]) -> Self {
+        let mut checksum = RollingChecksum {
+            length: data.len() as u32,
+            ..Default::default()
+        };
+        for (i, x) in data.iter().enumerate() {
+            checksum.a = checksum.a.wrapping_add(*x as u32);
+            checksum.b = checksum
+                .b
+                .wrapping_add(((data.len() - i) as u32).wrapping_mul(*x as u32));
+        }
+        checksum
+    }
+
+    /// Drops `old` from the front of the window and appends `new`
+    pub fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.length.wrapping_mul(old as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(algorithm: HashAlgorithm, data: &[u8]) -> Vec<u8> {
+    let mut hasher = algorithm.hasher();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Signs every full block of `reader`. A shorter tail is left out and always sent as data.
+pub fn signatures(
+    reader: &mut dyn Read,
+    block_size: u64,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<api::BlockSignature>> {
+    let mut buffer = vec![0; block_size as usize];
+    let mut signatures = vec![];
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let size = reader.read(&mut buffer[filled..])?;
+            if size == 0 {
+                break;
+            }
+            filled += size;
+        }
+        if filled < buffer.len() {
+            return Ok(signatures);
+        }
+        signatures.push(api::BlockSignature {
+            weak: RollingChecksum::new(&buffer).digest(),
+            strong: strong_hash(algorithm, &buffer),
+        });
+    }
+}
+
+/// Looks up blocks of the receiver's copy by their signatures
+pub struct SignatureIndex {
+    block_size: u64,
+    algorithm: HashAlgorithm,
+    blocks: HashMap<u32, Vec<(u64, Vec<u8>)>>,
+    /// Cheap first check for the weak checksum, since it is looked up at every byte
+    tags: Vec<bool>,
+}
+
+fn tag(digest: u32) -> usize {
+    ((digest ^ (digest >> 16)) & 0xffff) as usize
+}
+
+impl SignatureIndex {
+    pub fn new(block_size: u64, algorithm: HashAlgorithm) -> Self {
+        SignatureIndex {
+            block_size,
+            algorithm,
+            blocks: HashMap::new(),
+            tags: vec![false; 1 << 16],
+        }
+    }
+
+    pub fn add(&mut self, signatures: &api::BlockSignatures) {
+        for (i, block) in signatures.blocks.iter().enumerate() {
+            self.tags[tag(block.weak)] = true;
+            self.blocks
+                .entry(block.weak)
+                .or_default()
+                .push((signatures.first_block + i as u64, block.strong.clone()));
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the index of a block with the same contents as `data`
+    pub fn find(&self, checksum: &RollingChecksum, data: &[u8]) -> Option<u64> {
+        let digest = checksum.digest();
+        if !self.tags[tag(digest)] {
+            return None;
+        }
+        let candidates = self.blocks.get(&digest)?;
+        let strong = strong_hash(self.algorithm, data);
+        candidates
+            .iter()
+            .find(|(_, x)| x == &strong)
+            .map(|(index, _)| *index)
+    }
+}
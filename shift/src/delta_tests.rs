@@ -0,0 +1,35 @@
+#[cfg(test)]
+use super::api;
+#[cfg(test)]
+use super::delta::*;
+#[cfg(test)]
+use super::hash::HashAlgorithm;
+
+#[test]
+fn test_rolling_checksum() {
+    let data: Vec<u8> = (0..200u32).map(|x| (x * 7 + 3) as u8).collect();
+    let mut checksum = RollingChecksum::new(&data[..64]);
+    for i in 0..(data.len() - 64) {
+        checksum.roll(data[i], data[i + 64]);
+        assert_eq!(checksum, RollingChecksum::new(&data[i + 1..i + 65]));
+    }
+}
+
+#[test]
+fn test_signature_index() {
+    let block_size = block_size(0);
+    let data: Vec<u8> = (0..block_size * 3 + 10).map(|x| (x % 251) as u8).collect();
+    let signatures = signatures(&mut &data[..], block_size, HashAlgorithm::Blake3).unwrap();
+    // The partial last block is not signed
+    assert_eq!(signatures.len(), 3);
+
+    let mut index = SignatureIndex::new(block_size, HashAlgorithm::Blake3);
+    index.add(&api::BlockSignatures {
+        blocks: signatures,
+        ..Default::default()
+    });
+    let block = &data[block_size as usize..2 * block_size as usize];
+    assert_eq!(index.find(&RollingChecksum::new(block), block), Some(1));
+    let shifted = &data[1..block_size as usize + 1];
+    assert_eq!(index.find(&RollingChecksum::new(shifted), shifted), None);
+}
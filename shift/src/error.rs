@@ -0,0 +1,50 @@
+use std::io;
+
+use super::api::{self, error::Code};
+
+impl Code {
+    pub fn from_io_error_kind(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => Code::NotFound,
+            io::ErrorKind::PermissionDenied => Code::PermissionDenied,
+            io::ErrorKind::AlreadyExists => Code::AlreadyExists,
+            io::ErrorKind::StorageFull => Code::NoSpace,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Code::InvalidRequest,
+            _ => Code::IoError,
+        }
+    }
+}
+
+impl api::Error {
+    pub fn new(code: Code, reason: &str, file: &str) -> Self {
+        let mut error = api::Error {
+            code: 0,
+            reason: reason.to_string(),
+            file: file.to_string(),
+            transfer_id: 0,
+            request_id: 0,
+        };
+        error.set_code(code);
+        error
+    }
+
+    /// Describes a local failure to the peer, deriving the code from an underlying I/O error if there is one
+    pub fn from_error(error: &anyhow::Error, file: &str) -> Self {
+        let code = error
+            .chain()
+            .find_map(|x| x.downcast_ref::<io::Error>())
+            .map(|x| Code::from_io_error_kind(x.kind()))
+            .unwrap_or(Code::Unknown);
+        Self::new(code, &error.to_string(), file)
+    }
+}
+
+impl std::fmt::Display for api::Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)?;
+        if !self.file.is_empty() {
+            write!(f, " ({})", self.file)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::constants::{INITIAL_WINDOW_SIZE, MIN_WINDOW_SIZE};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Stream {
+    sent: u64,
+    acknowledged: u64,
+    /// End offsets of the chunks in flight and when they were sent, if known
+    pending: VecDeque<(u64, Option<Instant>)>,
+}
+
+impl Stream {
+    fn in_flight(&self) -> u64 {
+        self.sent.saturating_sub(self.acknowledged)
+    }
+}
+
+/// Sender-side sliding window over the byte offsets of the files being sent, shared by all
+/// concurrent transfers since they go through the same terminal.
+///
+/// The window grows while acknowledgements come back close to the lowest observed round-trip
+/// time, and halves (at most once per round trip) when they start queueing up behind the
+/// terminal's buffers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowControl {
+    max_window: u64,
+    window: u64,
+    streams: HashMap<u32, Stream>,
+    min_rtt: Option<Duration>,
+    smoothed_rtt: Option<Duration>,
+    last_decrease: Option<Instant>,
+}
+
+impl FlowControl {
+    pub fn new(max_window: u64) -> Self {
+        Self {
+            max_window,
+            window: std::cmp::min(INITIAL_WINDOW_SIZE, max_window),
+            streams: HashMap::new(),
+            min_rtt: None,
+            smoothed_rtt: None,
+            last_decrease: None,
+        }
+    }
+
+    /// Starts tracking a new file of a transfer at `offset`, keeping the learned window and RTT
+    pub fn reset(&mut self, transfer_id: u32, offset: u64) {
+        self.streams.insert(
+            transfer_id,
+            Stream {
+                sent: offset,
+                acknowledged: offset,
+                pending: VecDeque::new(),
+            },
+        );
+    }
+
+    /// Starts over at `offset` if a transfer goes back to data it already sent
+    pub fn rewind(&mut self, transfer_id: u32, offset: u64) {
+        let sent = self.streams.get(&transfer_id).map(|x| x.sent);
+        if sent.is_some_and(|x| offset < x) {
+            self.reset(transfer_id, offset);
+        }
+    }
+
+    pub fn remove(&mut self, transfer_id: u32) {
+        self.streams.remove(&transfer_id);
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.streams.values().map(|x| x.in_flight()).sum()
+    }
+
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    /// A chunk may always be sent when nothing is in flight, so chunks larger than the window still make progress
+    pub fn can_send(&self, length: u64) -> bool {
+        self.in_flight() == 0 || self.in_flight() + length <= self.window
+    }
+
+    /// Records a chunk sent at `now`, a chunk sent at an unknown time only takes up the window
+    pub fn on_send(&mut self, transfer_id: u32, end_offset: u64, now: Option<Instant>) {
+        let stream = self.streams.entry(transfer_id).or_default();
+        stream.sent = std::cmp::max(stream.sent, end_offset);
+        stream.pending.push_back((end_offset, now));
+    }
+
+    pub fn on_ack(&mut self, transfer_id: u32, offset: u64, now: Option<Instant>) {
+        let stream = match self.streams.get_mut(&transfer_id) {
+            Some(stream) if offset > stream.acknowledged => stream,
+            _ => return,
+        };
+        let newly_acknowledged = offset - stream.acknowledged;
+        stream.acknowledged = offset;
+
+        let mut sent_at = None;
+        while let Some((end_offset, time)) = stream.pending.front() {
+            if *end_offset > offset {
+                break;
+            }
+            sent_at = *time;
+            stream.pending.pop_front();
+        }
+
+        if let (Some(sent_at), Some(now)) = (sent_at, now) {
+            self.adapt(
+                now.saturating_duration_since(sent_at),
+                newly_acknowledged,
+                now,
+            );
+        }
+    }
+
+    fn adapt(&mut self, sample: Duration, acknowledged: u64, now: Instant) {
+        let min_rtt = std::cmp::min(self.min_rtt.unwrap_or(sample), sample);
+        self.min_rtt = Some(min_rtt);
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+
+        // Small absolute slack so that sub-millisecond local links don't count as congested
+        let threshold = std::cmp::max(min_rtt * 2, min_rtt + Duration::from_millis(10));
+        if sample <= threshold {
+            self.window = std::cmp::min(self.max_window, self.window + acknowledged);
+        } else if self
+            .last_decrease
+            .map(|t| now.saturating_duration_since(t) >= self.smoothed_rtt.unwrap_or(sample))
+            .unwrap_or(true)
+        {
+            self.window = std::cmp::min(
+                self.max_window,
+                std::cmp::max(MIN_WINDOW_SIZE, self.window / 2),
+            );
+            self.last_decrease = Some(now);
+        }
+    }
+}
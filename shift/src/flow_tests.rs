@@ -0,0 +1,127 @@
+#[cfg(test)]
+use super::constants::{INITIAL_WINDOW_SIZE, MIN_WINDOW_SIZE};
+#[cfg(test)]
+use super::flow::FlowControl;
+#[cfg(test)]
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_window_blocks_when_full() {
+    let now = Instant::now();
+    let mut flow = FlowControl::new(INITIAL_WINDOW_SIZE);
+    assert!(flow.can_send(INITIAL_WINDOW_SIZE));
+    flow.on_send(0, INITIAL_WINDOW_SIZE / 2, Some(now));
+    assert!(flow.can_send(INITIAL_WINDOW_SIZE / 2));
+    flow.on_send(0, INITIAL_WINDOW_SIZE, Some(now));
+    assert!(!flow.can_send(1));
+
+    flow.on_ack(
+        0,
+        INITIAL_WINDOW_SIZE / 2,
+        Some(now + Duration::from_millis(5)),
+    );
+    assert_eq!(flow.in_flight(), INITIAL_WINDOW_SIZE / 2);
+    assert!(flow.can_send(INITIAL_WINDOW_SIZE / 2));
+    assert!(!flow.can_send(INITIAL_WINDOW_SIZE / 2 + 1));
+}
+
+#[test]
+fn test_oversized_chunk_allowed_when_idle() {
+    let mut flow = FlowControl::new(MIN_WINDOW_SIZE);
+    assert!(flow.can_send(MIN_WINDOW_SIZE * 4));
+    flow.on_send(0, MIN_WINDOW_SIZE * 4, Some(Instant::now()));
+    assert!(!flow.can_send(1));
+}
+
+#[test]
+fn test_untimed_chunks_take_no_samples() {
+    let now = Instant::now();
+    let mut flow = FlowControl::new(INITIAL_WINDOW_SIZE);
+    flow.on_send(0, MIN_WINDOW_SIZE, None);
+    flow.on_ack(0, MIN_WINDOW_SIZE, Some(now));
+    assert_eq!(flow.in_flight(), 0);
+    assert_eq!(flow.round_trip_time(), None);
+
+    flow.on_send(0, 2 * MIN_WINDOW_SIZE, Some(now));
+    flow.on_ack(0, 2 * MIN_WINDOW_SIZE, None);
+    assert_eq!(flow.in_flight(), 0);
+    assert_eq!(flow.round_trip_time(), None);
+}
+
+#[test]
+fn test_window_grows_on_stable_rtt() {
+    let start = Instant::now();
+    let mut flow = FlowControl::new(INITIAL_WINDOW_SIZE * 4);
+    let chunk = 64 * 1024;
+    for i in 0..16 {
+        let sent_at = start + Duration::from_millis(i * 50);
+        flow.on_send(0, (i + 1) * chunk, Some(sent_at));
+        flow.on_ack(
+            0,
+            (i + 1) * chunk,
+            Some(sent_at + Duration::from_millis(20)),
+        );
+    }
+    assert_eq!(flow.window(), INITIAL_WINDOW_SIZE + 16 * chunk);
+    assert_eq!(flow.round_trip_time(), Some(Duration::from_millis(20)));
+}
+
+#[test]
+fn test_window_shrinks_on_growing_rtt() {
+    let start = Instant::now();
+    let mut flow = FlowControl::new(INITIAL_WINDOW_SIZE);
+    let chunk = 64 * 1024;
+    flow.on_send(0, chunk, Some(start));
+    flow.on_ack(0, chunk, Some(start + Duration::from_millis(20)));
+    let window = flow.window();
+
+    // Acks now queue up behind a full buffer
+    flow.on_send(0, 2 * chunk, Some(start + Duration::from_millis(100)));
+    flow.on_ack(0, 2 * chunk, Some(start + Duration::from_millis(600)));
+    assert_eq!(flow.window(), window / 2);
+
+    // Only one decrease per round trip
+    flow.on_send(0, 3 * chunk, Some(start + Duration::from_millis(600)));
+    flow.on_ack(0, 3 * chunk, Some(start + Duration::from_millis(650)));
+    assert_eq!(flow.window(), window / 2);
+
+    flow.reset(0, 0);
+    assert_eq!(flow.in_flight(), 0);
+    assert_eq!(flow.window(), window / 2);
+}
+
+#[test]
+fn test_window_shared_between_transfers() {
+    let now = Instant::now();
+    let mut flow = FlowControl::new(INITIAL_WINDOW_SIZE);
+    flow.reset(1, 0);
+    flow.reset(2, 1000);
+    flow.on_send(1, INITIAL_WINDOW_SIZE / 2, Some(now));
+    flow.on_send(2, 1000 + INITIAL_WINDOW_SIZE / 2, Some(now));
+    assert_eq!(flow.in_flight(), INITIAL_WINDOW_SIZE);
+    assert!(!flow.can_send(1));
+
+    flow.on_ack(
+        2,
+        1000 + INITIAL_WINDOW_SIZE / 2,
+        Some(now + Duration::from_millis(5)),
+    );
+    assert_eq!(flow.in_flight(), INITIAL_WINDOW_SIZE / 2);
+    flow.remove(1);
+    assert_eq!(flow.in_flight(), 0);
+}
+
+#[test]
+fn test_rewind_restarts_stream() {
+    let now = Instant::now();
+    let mut flow = FlowControl::new(INITIAL_WINDOW_SIZE);
+    flow.reset(0, INITIAL_WINDOW_SIZE * 4);
+    flow.rewind(0, INITIAL_WINDOW_SIZE * 4);
+    flow.on_send(0, INITIAL_WINDOW_SIZE * 5, Some(now));
+    assert_eq!(flow.in_flight(), INITIAL_WINDOW_SIZE);
+
+    flow.rewind(0, 0);
+    assert_eq!(flow.in_flight(), 0);
+    flow.on_send(0, INITIAL_WINDOW_SIZE, Some(now));
+    assert!(!flow.can_send(1));
+}
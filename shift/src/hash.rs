@@ -0,0 +1,131 @@
+use anyhow::Result;
+use sha2::Digest;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::constants::{FEATURE_HASH_BLAKE3, FEATURE_HASH_SHA256};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn feature(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => FEATURE_HASH_SHA256,
+            HashAlgorithm::Blake3 => FEATURE_HASH_BLAKE3,
+        }
+    }
+
+    /// Picks the first hash algorithm from a list of negotiated features
+    pub fn negotiated(features: &[String]) -> Option<Self> {
+        features.iter().find_map(|feature| match feature.as_str() {
+            FEATURE_HASH_SHA256 => Some(HashAlgorithm::Sha256),
+            FEATURE_HASH_BLAKE3 => Some(HashAlgorithm::Blake3),
+            _ => None,
+        })
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Feeds the first `length` bytes of `reader` into the hasher
+    pub fn update_from(&mut self, reader: &mut dyn Read, mut length: u64) -> Result<()> {
+        let mut buffer = vec![0; 1024 * 64];
+        while length > 0 {
+            let limit = std::cmp::min(buffer.len() as u64, length) as usize;
+            let size = reader.read(&mut buffer[..limit])?;
+            if size == 0 {
+                break;
+            }
+            self.update(&buffer[..size]);
+            length -= size as u64;
+        }
+        Ok(())
+    }
+
+    pub fn update_zeros(&mut self, mut length: u64) {
+        let zeros = [0; 1024 * 64];
+        while length > 0 {
+            let size = std::cmp::min(zeros.len() as u64, length) as usize;
+            self.update(&zeros[..size]);
+            length -= size as u64;
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    hasher.update_from(&mut File::open(path)?, u64::MAX)?;
+    Ok(hasher.finalize())
+}
+
+/// Hashes the first `length` bytes of a file, or returns None if it is shorter than that
+pub fn hash_prefix(path: &Path, length: u64, algorithm: HashAlgorithm) -> Result<Option<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() < length {
+        return Ok(None);
+    }
+    let mut hasher = algorithm.hasher();
+    hasher.update_from(&mut file, length)?;
+    Ok(Some(hasher.finalize()))
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
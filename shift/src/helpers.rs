@@ -1,38 +1,342 @@
-use super::api;
-use super::machine::ShiftClient;
-use anyhow::Result;
+use super::api::{self, chunk::Compression};
+use super::compression::compress_chunk;
+use super::constants::FEATURE_SPARSE;
+use super::delta::{RollingChecksum, SignatureIndex, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use super::hash::{HashAlgorithm, Hasher};
+use super::machine::{OpenFile, ShiftClient};
+use super::sparse::next_data;
+use anyhow::{bail, Result};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// Locks the client once the transfer is not paused
+fn wait_while_paused<'a, 'b>(
+    client: &'a Mutex<ShiftClient<'b>>,
+    transfer_id: u32,
+) -> MutexGuard<'a, ShiftClient<'b>> {
+    let mut client = client.lock().unwrap();
+    let changed = client.changed();
+    while client.is_paused(transfer_id) {
+        client = changed.wait(client).unwrap();
+    }
+    client
+}
+
+fn send_chunk(
+    client: &Arc<Mutex<ShiftClient>>,
+    file: &OpenFile,
+    mut chunk: api::Chunk,
+    length: u64,
+) -> Result<()> {
+    chunk.transfer_id = file.transfer_id;
+    chunk.file_id = file.file_id;
+    let mut client = client.lock().unwrap();
+    let changed = client.changed();
+    // Not sending while paused, while other transfers have their turn or the window is full
+    while !client.can_send_chunk(file.transfer_id, length)? {
+        client = changed.wait(client).unwrap();
+    }
+    client.tick(Instant::now())?;
+    client.send_chunk(chunk)
+}
+
+fn close_message(
+    open_file: &OpenFile,
+    hash: Option<HashAlgorithm>,
+    hasher: Option<Hasher>,
+) -> api::CloseFile {
+    match (hash, hasher) {
+        (Some(algorithm), Some(hasher)) => api::CloseFile {
+            hash_algorithm: algorithm.name().to_string(),
+            hash: hasher.finalize(),
+            transfer_id: open_file.transfer_id,
+            file_id: open_file.file_id,
+        },
+        _ => api::CloseFile {
+            transfer_id: open_file.transfer_id,
+            file_id: open_file.file_id,
+            ..Default::default()
+        },
+    }
+}
 
 pub fn send_file(
     client: Arc<Mutex<ShiftClient>>,
+    open_file: &OpenFile,
     mut position: u64,
     path: &Path,
     buffer_size: usize,
+    hash: Option<HashAlgorithm>,
     progress: &mut dyn FnMut(u64, u64),
 ) -> Result<()> {
     let mut file = File::open(path)?;
     let size = file.seek(SeekFrom::End(0))?;
-    file.seek(SeekFrom::Start(position))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = hash.map(|x| x.hasher());
+    if let Some(ref mut hasher) = hasher {
+        // The digest covers the whole file, including a prefix the receiver already has
+        hasher.update_from(&mut file, position)?;
+    }
+    let (compression, sparse) = {
+        let client = client.lock().unwrap();
+        (
+            Compression::negotiated(client.negotiated_features()).unwrap_or(Compression::None),
+            client.has_feature(FEATURE_SPARSE),
+        )
+    };
     let mut reader = BufReader::with_capacity(buffer_size, file);
-    loop {
-        progress(position, size);
-        let length = {
+    while position < size {
+        let data_end = if sparse {
+            let (start, end) = next_data(reader.get_ref(), position, size)?.unwrap_or((size, size));
+            if start > position {
+                let hole_length = start - position;
+                let chunk = api::Chunk {
+                    offset: position,
+                    hole_length,
+                    ..Default::default()
+                };
+                send_chunk(&client, open_file, chunk, hole_length)?;
+                if let Some(ref mut hasher) = hasher {
+                    hasher.update_zeros(hole_length);
+                }
+                position = start;
+            }
+            end
+        } else {
+            size
+        };
+        reader.seek(SeekFrom::Start(position))?;
+
+        while position < data_end {
+            progress(position, size);
             let buffer = reader.fill_buf()?;
             if buffer.is_empty() {
                 break;
             }
-            client.lock().unwrap().send_chunk(api::Chunk {
-                offset: position,
-                data: buffer.to_vec(),
-            })?;
-            position += buffer.len() as u64;
-            buffer.len()
+            // Never send a chunk larger than the whole window
+            let length = std::cmp::min(
+                std::cmp::min(buffer.len() as u64, data_end - position),
+                client.lock().unwrap().send_window().unwrap_or(u64::MAX),
+            ) as usize;
+            let chunk = compress_chunk(position, &buffer[..length], compression)?;
+            send_chunk(&client, open_file, chunk, length as u64)?;
+            if let Some(ref mut hasher) = hasher {
+                hasher.update(&buffer[..length]);
+            }
+            position += length as u64;
+            reader.consume(length);
+        }
+        if position < data_end {
+            // The file shrank while we were sending it
+            break;
+        }
+    }
+    progress(position, size);
+    client
+        .lock()
+        .unwrap()
+        .close_file(close_message(open_file, hash, hasher))?;
+    Ok(())
+}
+
+/// Sends whatever `stream` yields until it ends, for files of unknown size. `progress` gets
+/// the number of bytes sent so far.
+pub fn send_stream(
+    client: Arc<Mutex<ShiftClient>>,
+    open_file: &OpenFile,
+    stream: &mut dyn Read,
+    buffer_size: usize,
+    hash: Option<HashAlgorithm>,
+    progress: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let mut hasher = hash.map(|x| x.hasher());
+    let compression = Compression::negotiated(client.lock().unwrap().negotiated_features())
+        .unwrap_or(Compression::None);
+    let mut buffer = vec![0; buffer_size];
+    let mut position = 0;
+    loop {
+        progress(position);
+        let window = client.lock().unwrap().send_window().unwrap_or(u64::MAX);
+        let length = std::cmp::min(buffer_size as u64, window) as usize;
+        let length = match stream.read(&mut buffer[..length]) {
+            Ok(length) => length,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error.into()),
         };
-        reader.consume(length);
+        // An empty chunk tells the receiver that there is no more
+        let mut chunk = compress_chunk(position, &buffer[..length], compression)?;
+        chunk.end_of_stream = length == 0;
+        send_chunk(&client, open_file, chunk, length as u64)?;
+        if length == 0 {
+            break;
+        }
+        if let Some(ref mut hasher) = hasher {
+            hasher.update(&buffer[..length]);
+        }
+        position += length as u64;
+    }
+    client
+        .lock()
+        .unwrap()
+        .close_file(close_message(open_file, hash, hasher))?;
+    Ok(())
+}
+
+/// Sends unmatched data from a delta transfer as regular chunks
+fn send_literal(
+    client: &Arc<Mutex<ShiftClient>>,
+    open_file: &OpenFile,
+    mut offset: u64,
+    data: &[u8],
+    compression: Compression,
+    hasher: &mut Option<Hasher>,
+) -> Result<()> {
+    let mut sent = 0;
+    while sent < data.len() {
+        let window = client.lock().unwrap().send_window().unwrap_or(u64::MAX);
+        let length = std::cmp::min((data.len() - sent) as u64, window) as usize;
+        let chunk = compress_chunk(offset, &data[sent..sent + length], compression)?;
+        send_chunk(client, open_file, chunk, length as u64)?;
+        sent += length;
+        offset += length as u64;
+    }
+    if let Some(ref mut hasher) = hasher {
+        hasher.update(data);
+    }
+    Ok(())
+}
+
+/// Sends a file as instructions to copy the blocks the receiver already has, and data for the rest
+pub fn send_delta(
+    client: Arc<Mutex<ShiftClient>>,
+    open_file: &OpenFile,
+    path: &Path,
+    index: &SignatureIndex,
+    buffer_size: usize,
+    hash: Option<HashAlgorithm>,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<()> {
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&index.block_size()) {
+        bail!("Block size {} is out of range", index.block_size());
     }
-    client.lock().unwrap().close_file()?;
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let block_size = index.block_size() as usize;
+    let mut hasher = hash.map(|x| x.hasher());
+    let compression = Compression::negotiated(client.lock().unwrap().negotiated_features())
+        .unwrap_or(Compression::None);
+
+    // data[start..] has been read but not sent yet, data[position..] is the window being matched
+    let mut data = vec![];
+    let mut offset = 0;
+    let mut start = 0;
+    let mut position = 0;
+    let mut checksum: Option<RollingChecksum> = None;
+    let mut copy: Option<api::CopyBlock> = None;
+    let mut read_buffer = vec![0; buffer_size];
+    let mut eof = false;
+
+    let send_copy = |copy: &mut Option<api::CopyBlock>| -> Result<()> {
+        if let Some(copy) = copy.take() {
+            wait_while_paused(&client, open_file.transfer_id).send_copy_block(copy)?;
+        }
+        Ok(())
+    };
+
+    loop {
+        // Keep a full window and the byte after it
+        while !eof && data.len() < position + block_size + 1 {
+            let size = file.read(&mut read_buffer)?;
+            eof = size == 0;
+            data.extend_from_slice(&read_buffer[..size]);
+        }
+        if data.len() < position + block_size {
+            break;
+        }
+
+        let window = &data[position..position + block_size];
+        let current = *checksum.get_or_insert_with(|| RollingChecksum::new(window));
+        if let Some(block) = index.find(&current, window) {
+            if position > start {
+                send_copy(&mut copy)?;
+                send_literal(
+                    &client,
+                    open_file,
+                    offset,
+                    &data[start..position],
+                    compression,
+                    &mut hasher,
+                )?;
+                offset += (position - start) as u64;
+            }
+            if let Some(ref mut hasher) = hasher {
+                hasher.update(window);
+            }
+            match &mut copy {
+                Some(copy) if copy.block + copy.count == block => copy.count += 1,
+                _ => {
+                    send_copy(&mut copy)?;
+                    copy = Some(api::CopyBlock {
+                        transfer_id: open_file.transfer_id,
+                        file_id: open_file.file_id,
+                        offset,
+                        block,
+                        count: 1,
+                    });
+                }
+            }
+            offset += block_size as u64;
+            position += block_size;
+            start = position;
+            checksum = None;
+        } else if data.len() > position + block_size {
+            let mut next = current;
+            next.roll(data[position], data[position + block_size]);
+            checksum = Some(next);
+            position += 1;
+            if position - start >= buffer_size {
+                send_copy(&mut copy)?;
+                send_literal(
+                    &client,
+                    open_file,
+                    offset,
+                    &data[start..position],
+                    compression,
+                    &mut hasher,
+                )?;
+                offset += (position - start) as u64;
+                start = position;
+            }
+        } else {
+            break;
+        }
+
+        if start >= buffer_size {
+            data.drain(..start);
+            position -= start;
+            start = 0;
+            progress(offset, size);
+        }
+    }
+
+    // Whatever is left could not be matched
+    send_copy(&mut copy)?;
+    send_literal(
+        &client,
+        open_file,
+        offset,
+        &data[start..],
+        compression,
+        &mut hasher,
+    )?;
+    offset += (data.len() - start) as u64;
+    progress(offset, size);
+    client
+        .lock()
+        .unwrap()
+        .close_file(close_message(open_file, hash, hasher))?;
     Ok(())
 }
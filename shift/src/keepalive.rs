@@ -0,0 +1,153 @@
+use super::machine::DisconnectReason;
+use std::time::{Duration, Instant};
+
+/// When to ping the peer and when to give up on it. Nothing happens on its own, the owner
+/// of the [`super::ShiftClient`] drives all of it through `tick`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeouts {
+    /// How often to ping a peer that supports keepalive
+    pub keepalive_interval: Duration,
+    /// Disconnect when nothing was heard from a peer that supports keepalive for this long
+    pub idle: Option<Duration>,
+    /// Disconnect when no active transfer made progress for this long
+    pub transfer: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            keepalive_interval: Duration::from_secs(5),
+            idle: Some(Duration::from_secs(30)),
+            transfer: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Builds timeouts from command line options in seconds, where an idle timeout of 0 means
+    /// never giving up on a silent peer
+    pub fn from_options(idle: u64, transfer: Option<u64>) -> Self {
+        Timeouts {
+            idle: Some(idle).filter(|x| *x > 0).map(Duration::from_secs),
+            transfer: transfer.map(Duration::from_secs),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TickOutcome {
+    /// ID of a ping to send now
+    pub ping: Option<u64>,
+    /// Round trip time of a ping answered since the last tick
+    pub latency: Option<Duration>,
+    pub timeout: Option<DisconnectReason>,
+}
+
+struct PendingPing {
+    id: u64,
+    sent: Instant,
+    answered: bool,
+}
+
+/// Tracks activity between ticks. Times are only ever taken from ticks, so the same inputs
+/// always have the same outcome.
+pub struct Keepalive {
+    timeouts: Timeouts,
+    heard: bool,
+    last_heard: Option<Instant>,
+    progressed: bool,
+    transferring: bool,
+    last_progress: Option<Instant>,
+    next_ping_id: u64,
+    ping: Option<PendingPing>,
+    last_ping: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+impl Keepalive {
+    pub fn new(timeouts: Timeouts) -> Self {
+        Keepalive {
+            timeouts,
+            heard: false,
+            last_heard: None,
+            progressed: false,
+            transferring: false,
+            last_progress: None,
+            next_ping_id: 0,
+            ping: None,
+            last_ping: None,
+            latency: None,
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn on_heard(&mut self) {
+        self.heard = true;
+    }
+
+    pub fn on_progress(&mut self) {
+        self.progressed = true;
+    }
+
+    pub fn on_pong(&mut self, id: u64) {
+        if let Some(ping) = &mut self.ping {
+            if ping.id == id {
+                ping.answered = true;
+            }
+        }
+    }
+
+    /// `pinging` is whether the peer supports keepalive, `transferring` whether any transfer
+    /// is active
+    pub fn tick(&mut self, now: Instant, pinging: bool, transferring: bool) -> TickOutcome {
+        let mut outcome = TickOutcome::default();
+        if std::mem::take(&mut self.heard) || self.last_heard.is_none() {
+            self.last_heard = Some(now);
+        }
+        // The transfer timer only runs while there is something to make progress on
+        let was_transferring = std::mem::replace(&mut self.transferring, transferring);
+        if std::mem::take(&mut self.progressed) || !was_transferring {
+            self.last_progress = Some(now);
+        }
+
+        if let Some(ping) = self.ping.take_if(|x| x.answered) {
+            let latency = now.saturating_duration_since(ping.sent);
+            self.latency = Some(latency);
+            outcome.latency = Some(latency);
+        }
+        let ping_due = self
+            .last_ping
+            .map(|x| now.saturating_duration_since(x) >= self.timeouts.keepalive_interval)
+            .unwrap_or(true);
+        if pinging && self.ping.is_none() && ping_due {
+            self.next_ping_id += 1;
+            self.ping = Some(PendingPing {
+                id: self.next_ping_id,
+                sent: now,
+                answered: false,
+            });
+            self.last_ping = Some(now);
+            outcome.ping = Some(self.next_ping_id);
+        }
+
+        let expired = |since: Option<Instant>, timeout: Option<Duration>| match (since, timeout) {
+            (Some(since), Some(timeout)) => now.saturating_duration_since(since) >= timeout,
+            _ => false,
+        };
+        if pinging && expired(self.last_heard, self.timeouts.idle) {
+            outcome.timeout = Some(DisconnectReason::IdleTimeout);
+        } else if transferring && expired(self.last_progress, self.timeouts.transfer) {
+            outcome.timeout = Some(DisconnectReason::TransferTimeout);
+        }
+        outcome
+    }
+
+    /// Round trip time of the last answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+}
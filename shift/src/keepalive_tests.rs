@@ -0,0 +1,66 @@
+#[cfg(test)]
+use super::keepalive::{Keepalive, Timeouts};
+#[cfg(test)]
+use super::machine::DisconnectReason;
+#[cfg(test)]
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_ping_measures_latency() {
+    let start = Instant::now();
+    let mut keepalive = Keepalive::new(Timeouts::default());
+    let outcome = keepalive.tick(start, true, false);
+    assert_eq!(outcome.ping, Some(1));
+
+    // No second ping while the first is unanswered
+    let outcome = keepalive.tick(start + Duration::from_secs(6), true, false);
+    assert_eq!(outcome.ping, None);
+
+    keepalive.on_heard();
+    keepalive.on_pong(1);
+    let outcome = keepalive.tick(start + Duration::from_secs(7), true, false);
+    assert_eq!(outcome.latency, Some(Duration::from_secs(7)));
+    assert_eq!(outcome.ping, Some(2));
+    assert_eq!(keepalive.latency(), Some(Duration::from_secs(7)));
+
+    let mut keepalive = Keepalive::new(Timeouts::default());
+    assert_eq!(keepalive.tick(start, false, false).ping, None);
+}
+
+#[test]
+fn test_idle_timeout() {
+    let start = Instant::now();
+    let mut keepalive = Keepalive::new(Timeouts::default());
+    keepalive.tick(start, true, false);
+    keepalive.on_heard();
+    let outcome = keepalive.tick(start + Duration::from_secs(20), true, false);
+    assert_eq!(outcome.timeout, None);
+    let outcome = keepalive.tick(start + Duration::from_secs(49), true, false);
+    assert_eq!(outcome.timeout, None);
+    let outcome = keepalive.tick(start + Duration::from_secs(50), true, false);
+    assert_eq!(outcome.timeout, Some(DisconnectReason::IdleTimeout));
+
+    // A silent peer is only a problem if it is supposed to ping
+    let mut keepalive = Keepalive::new(Timeouts::default());
+    keepalive.tick(start, false, false);
+    let outcome = keepalive.tick(start + Duration::from_secs(3600), false, false);
+    assert_eq!(outcome.timeout, None);
+}
+
+#[test]
+fn test_transfer_timeout() {
+    let start = Instant::now();
+    let mut keepalive = Keepalive::new(Timeouts {
+        transfer: Some(Duration::from_secs(10)),
+        ..Default::default()
+    });
+    keepalive.tick(start, false, false);
+    // The timer starts when a transfer does
+    let outcome = keepalive.tick(start + Duration::from_secs(60), false, true);
+    assert_eq!(outcome.timeout, None);
+    keepalive.on_progress();
+    let outcome = keepalive.tick(start + Duration::from_secs(65), false, true);
+    assert_eq!(outcome.timeout, None);
+    let outcome = keepalive.tick(start + Duration::from_secs(75), false, true);
+    assert_eq!(outcome.timeout, Some(DisconnectReason::TransferTimeout));
+}
@@ -1,14 +1,31 @@
 pub mod api;
+pub mod compression;
 mod constants;
+pub mod delta;
+pub mod error;
+mod flow;
+pub mod hash;
 pub mod helpers;
+mod keepalive;
 mod machine;
 mod message;
 pub mod pty;
+pub mod secure;
+mod sparse;
 mod transport;
 
-pub use self::constants::TRANSPORT;
-pub use self::machine::{OpenFile, ShiftClient, ShiftClientEvent};
+pub use self::constants::*;
+pub use self::keepalive::Timeouts;
+pub use self::machine::{DisconnectReason, OpenFile, ShiftClient, ShiftClientEvent, TransferState};
 pub use self::message::{MessageOutput, MessageReader, MessageWriter};
-pub use self::transport::{TransportConfig, TransportOutput, TransportReader, TransportWriter};
+pub use self::transport::{
+    negotiated_encoding, Base64, Encoding, Terminator, TransportConfig, TransportCounters,
+    TransportOutput, TransportReader, TransportWriter, Wrapping, YEnc, ENCODINGS, Z85,
+};
 
+mod delta_tests;
+mod flow_tests;
+mod keepalive_tests;
+mod machine_tests;
+mod secure_tests;
 mod transport_tests;
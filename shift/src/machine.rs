@@ -1,10 +1,28 @@
 use super::api::{self, message::Content};
+use super::compression::{chunk_length, decompress_chunk};
+use super::constants::{
+    FEATURE_BROWSE, FEATURE_DELTA, FEATURE_DESTINATION_HINTS, FEATURE_ERRORS, FEATURE_FLOW_CONTROL,
+    FEATURE_KEEPALIVE, FEATURE_MANIFEST, FEATURE_MULTIPLEX, FEATURE_PAUSE, FEATURE_REJECT_FILE,
+    FEATURE_RESTART, FEATURE_SPARSE, FEATURE_STREAMS, FEATURE_XATTRS, MAX_WINDOW_SIZE,
+    SUPPORTED_FEATURES, SUPPORTED_VERSIONS,
+};
+use super::delta::{MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use super::flow::FlowControl;
+use super::hash::HashAlgorithm;
+use super::keepalive::{Keepalive, Timeouts};
 use super::message::MessageWriter;
-use anyhow::{bail, Result};
+use super::transport::negotiated_encoding;
+use anyhow::{anyhow, bail, Result};
+use rand_core::{OsRng, RngCore};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Condvar};
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct OpenFile {
     pub info: api::FileInfo,
+    pub transfer_id: u32,
+    pub file_id: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -12,23 +30,67 @@ pub enum Input {
     Start,
     IncomingMessage(Content),
     RequestInboundTransfer(api::ReceiveRequest),
-    AcceptTransfer,
-    RejectTransfer,
+    AcceptTransfer(u32),
+    RejectTransfer(u32),
     RequestOutboundTransfer(api::SendRequest),
+    SendTransferManifest(api::TransferManifest),
     OpenFile(api::OpenFile),
     ConfirmFileOpened(api::FileOpened),
+    RejectFile(api::RejectFile),
+    SendFileAttributes(api::FileAttributes),
+    SendBlockSignatures(api::BlockSignatures),
     SendChunk(api::Chunk),
-    AcknowledgeChunk,
-    CloseFile,
-    CloseTransfer,
+    SendCopyBlock(api::CopyBlock),
+    AcknowledgeChunk(api::AcknowledgeChunk),
+    CloseFile(api::CloseFile),
+    SendFileVerification(api::FileVerification),
+    PauseTransfer(u32),
+    ResumeTransfer(u32),
+    CloseTransfer(u32),
     Disconnect,
+    Abandon,
+    RejectUnsupported(u32),
+    SendError(api::Error),
+    ListDirectory(api::ListDirectory),
+    SendDirectoryListing(api::DirectoryListing),
+    Stat(api::Stat),
+    SendStatResult(api::StatResult),
+    Tick(Instant),
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum State {
     Initial,
+    Securing,
     Connecting,
-    Idle,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// This side disconnected
+    Local,
+    Peer,
+    /// Nothing was heard from the peer for too long
+    IdleTimeout,
+    /// No transfer made progress for too long
+    TransferTimeout,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Local => write!(f, "Disconnected"),
+            DisconnectReason::Peer => write!(f, "Peer disconnected"),
+            DisconnectReason::IdleTimeout => write!(f, "Peer stopped responding"),
+            DisconnectReason::TransferTimeout => write!(f, "Transfer stalled"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferState {
     InboundTransferRequested(api::ReceiveRequest),
     InboundTransferOffered(api::SendRequest),
     InboundTransfer(api::SendRequest, Option<api::OpenFile>),
@@ -36,51 +98,348 @@ pub enum State {
     OutboundTransferRequested(api::SendRequest),
     OutboundTransfer(api::SendRequest, Option<api::OpenFile>),
     OutboundFileTransfer(api::SendRequest, Option<OpenFile>),
-    Disconnected,
+}
+
+impl TransferState {
+    /// Whether the transfer was accepted and has not been closed yet
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            TransferState::InboundTransfer(_, _)
+                | TransferState::InboundFileTransfer(_, _)
+                | TransferState::OutboundTransfer(_, _)
+                | TransferState::OutboundFileTransfer(_, _)
+        )
+    }
+}
+
+/// The transfer a message belongs to, if it belongs to one. Errors are handled by the session.
+fn message_transfer_id(content: &Content) -> Option<u32> {
+    match content {
+        Content::ReceiveRequest(x) => Some(x.transfer_id),
+        Content::SendRequest(x) => Some(x.transfer_id),
+        Content::AcceptTransfer(x) => Some(x.transfer_id),
+        Content::RejectTransfer(x) => Some(x.transfer_id),
+        Content::TransferManifest(x) => Some(x.transfer_id),
+        Content::OpenFile(x) => Some(x.transfer_id),
+        Content::FileOpened(x) => Some(x.transfer_id),
+        Content::RejectFile(x) => Some(x.transfer_id),
+        Content::FileAttributes(x) => Some(x.transfer_id),
+        Content::BlockSignatures(x) => Some(x.transfer_id),
+        Content::Chunk(x) => Some(x.transfer_id),
+        Content::CopyBlock(x) => Some(x.transfer_id),
+        Content::AcknowledgeChunk(x) => Some(x.transfer_id),
+        Content::CloseFile(x) => Some(x.transfer_id),
+        Content::FileVerification(x) => Some(x.transfer_id),
+        Content::PauseTransfer(x) => Some(x.transfer_id),
+        Content::ResumeTransfer(x) => Some(x.transfer_id),
+        Content::CloseTransfer(x) => Some(x.transfer_id),
+        _ => None,
+    }
+}
+
+impl Input {
+    /// The transfer this input belongs to, if it belongs to one
+    fn transfer_id(&self) -> Option<u32> {
+        match self {
+            Input::RequestInboundTransfer(x) => Some(x.transfer_id),
+            Input::AcceptTransfer(x)
+            | Input::RejectTransfer(x)
+            | Input::PauseTransfer(x)
+            | Input::ResumeTransfer(x)
+            | Input::CloseTransfer(x) => Some(*x),
+            Input::RequestOutboundTransfer(x) => Some(x.transfer_id),
+            Input::SendTransferManifest(x) => Some(x.transfer_id),
+            Input::OpenFile(x) => Some(x.transfer_id),
+            Input::ConfirmFileOpened(x) => Some(x.transfer_id),
+            Input::RejectFile(x) => Some(x.transfer_id),
+            Input::SendFileAttributes(x) => Some(x.transfer_id),
+            Input::SendBlockSignatures(x) => Some(x.transfer_id),
+            Input::SendChunk(x) => Some(x.transfer_id),
+            Input::SendCopyBlock(x) => Some(x.transfer_id),
+            Input::AcknowledgeChunk(x) => Some(x.transfer_id),
+            Input::CloseFile(x) => Some(x.transfer_id),
+            Input::SendFileVerification(x) => Some(x.transfer_id),
+            Input::IncomingMessage(content) => message_transfer_id(content),
+            _ => None,
+        }
+    }
+
+    /// Inputs that may still arrive after their transfer was closed or aborted by an error, or
+    /// after their file was rejected
+    fn is_straggler(&self) -> bool {
+        matches!(
+            self,
+            Input::CloseTransfer(_)
+                | Input::CloseFile(_)
+                | Input::IncomingMessage(Content::CloseTransfer(_))
+                | Input::IncomingMessage(Content::OpenFile(_))
+                | Input::IncomingMessage(Content::FileAttributes(_))
+                | Input::IncomingMessage(Content::PauseTransfer(_))
+                | Input::IncomingMessage(Content::ResumeTransfer(_))
+                | Input::IncomingMessage(Content::CloseFile(_))
+                | Input::IncomingMessage(Content::Chunk(_))
+                | Input::IncomingMessage(Content::CopyBlock(_))
+                | Input::IncomingMessage(Content::AcknowledgeChunk(_))
+        )
+    }
 }
 
 #[derive(Debug)]
 pub enum ShiftClientEvent {
     Connected,
-    Disconnected,
+    Disconnected(DisconnectReason),
     InboundTransferOffered(api::SendRequest),
     OutboundTransferOffered(api::ReceiveRequest),
-    TransferAccepted(),
-    TransferRejected(),
+    TransferAccepted(u32),
+    TransferRejected(u32),
+    TransferManifest(api::TransferManifest),
     InboundFileOpening(api::SendRequest, api::OpenFile),
     FileTransferStarted(OpenFile, api::FileOpened),
+    FileRejected(OpenFile),
+    FileAttributes(api::FileAttributes),
+    BlockSignatures(api::BlockSignatures),
     Chunk(api::Chunk),
-    FileClosed(OpenFile),
-    TransferClosed,
+    CopyBlock(api::CopyBlock),
+    FileClosed(OpenFile, api::CloseFile),
+    FileVerified(api::FileVerification),
+    TransferPaused(u32),
+    TransferResumed(u32),
+    TransferClosed(u32),
+    Unsupported(api::Unsupported),
+    PeerError(api::Error),
+    ListDirectoryRequested(api::ListDirectory),
+    DirectoryListing(api::DirectoryListing),
+    StatRequested(api::Stat),
+    StatResult(api::StatResult),
+    LatencyMeasured(Duration),
 }
 
 pub struct ShiftClient<'a> {
     events: Vec<ShiftClientEvent>,
     state: State,
+    transfers: BTreeMap<u32, TransferState>,
+    /// Outbound transfers waiting for their turn to send a chunk
+    send_queue: VecDeque<u32>,
+    /// IDs of the transfers that either side paused
+    paused: BTreeSet<u32>,
+    next_transfer_id: u32,
+    /// Browse requests waiting for an answer from the peer
+    requests: BTreeSet<u32>,
+    next_request_id: u32,
+    next_file_id: u32,
+    /// Whether this side sent the first Init, as opposed to answering one
+    initiator: bool,
     writer: MessageWriter<'a>,
+    features: Vec<String>,
+    negotiated_version: Option<u32>,
+    negotiated_features: Vec<String>,
+    flow: Option<FlowControl>,
+    keepalive: Keepalive,
+    /// Time of the last tick, which flow control takes as the time chunks go out and
+    /// acknowledgements come in
+    clock: Option<Instant>,
+    /// Signalled on every input, so that senders waiting for their turn, an acknowledgement
+    /// or a resume check again
+    changed: Arc<Condvar>,
 }
 
 #[derive(thiserror::Error, Debug)]
-#[error("Invalid transition")]
 pub enum ClientError {
-    InvalidTransitionError { state: State, input: Input },
+    #[error("Invalid transition")]
+    InvalidTransitionError { state: Box<State>, input: Input },
+    #[error("Invalid transition in transfer {transfer_id}")]
+    InvalidTransferTransitionError {
+        transfer_id: u32,
+        state: Option<Box<TransferState>>,
+        input: Input,
+    },
+    #[error("Invalid state: {0}")]
     InvalidStateError(&'static str),
+    #[error("No common protocol version (local: {local:?}, remote: {remote:?})")]
+    IncompatibleVersion { local: Vec<u32>, remote: Vec<u32> },
 }
 
 impl<'a> ShiftClient<'a> {
     pub fn new(writer: MessageWriter<'a>) -> Self {
+        let features = SUPPORTED_FEATURES
+            .iter()
+            .filter(|x| writer.supports_feature(x))
+            .map(|x| x.to_string())
+            .collect();
+        Self::with_features(writer, features)
+    }
+
+    pub fn with_features(writer: MessageWriter<'a>, features: Vec<String>) -> Self {
         ShiftClient {
             events: vec![],
             state: State::Initial,
+            transfers: BTreeMap::new(),
+            send_queue: VecDeque::new(),
+            paused: BTreeSet::new(),
+            next_transfer_id: OsRng.next_u32(),
+            requests: BTreeSet::new(),
+            next_request_id: OsRng.next_u32(),
+            next_file_id: 0,
+            initiator: false,
             writer,
+            features,
+            negotiated_version: None,
+            negotiated_features: vec![],
+            flow: None,
+            keepalive: Keepalive::new(Timeouts::default()),
+            clock: None,
+            changed: Arc::new(Condvar::new()),
+        }
+    }
+
+    fn init_message(&self) -> api::Init {
+        api::Init {
+            version: *SUPPORTED_VERSIONS.iter().max().unwrap_or(&1),
+            features: self.features.clone(),
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+            window_size: MAX_WINDOW_SIZE,
+        }
+    }
+
+    fn negotiate(&mut self, init: api::Init) -> Result<()> {
+        // Peers predating negotiation only announce a single version
+        let remote_versions = if init.supported_versions.is_empty() {
+            vec![init.version]
+        } else {
+            init.supported_versions.clone()
+        };
+        let version = SUPPORTED_VERSIONS
+            .iter()
+            .filter(|v| remote_versions.contains(v))
+            .max()
+            .copied();
+        match version {
+            Some(version) => {
+                self.negotiated_version = Some(version);
+                self.negotiated_features = self
+                    .features
+                    .iter()
+                    .filter(|f| init.features.contains(f))
+                    .cloned()
+                    .collect();
+                self.writer
+                    .set_encoding(negotiated_encoding(&self.negotiated_features));
+                if self.has_feature(FEATURE_FLOW_CONTROL)
+                    && init.window_size > 0
+                    && self.flow.is_none()
+                {
+                    self.flow = Some(FlowControl::new(std::cmp::min(
+                        MAX_WINDOW_SIZE,
+                        init.window_size,
+                    )));
+                }
+                Ok(())
+            }
+            None => {
+                self.writer.write(Content::Disconnect(api::Disconnect {}))?;
+                self.transition(State::Disconnected);
+                bail!(ClientError::IncompatibleVersion {
+                    local: SUPPORTED_VERSIONS.to_vec(),
+                    remote: remote_versions,
+                });
+            }
         }
     }
 
     fn transition(&mut self, state: State) {
+        if state == State::Disconnected {
+            // Whatever was in flight is lost with the session
+            self.transfers.clear();
+            self.paused.clear();
+            self.send_queue.clear();
+            self.requests.clear();
+            self.flow = None;
+        }
         self.state = state;
         // println!("machine now in {:?}", self.state);
     }
 
+    fn transition_transfer(&mut self, transfer_id: u32, state: TransferState) {
+        self.transfers.insert(transfer_id, state);
+        // println!("transfer {} now in {:?}", transfer_id, self.transfers[&transfer_id]);
+    }
+
+    fn end_transfer(&mut self, transfer_id: u32) {
+        self.transfers.remove(&transfer_id);
+        self.send_queue.retain(|x| *x != transfer_id);
+        self.paused.remove(&transfer_id);
+        if let Some(flow) = &mut self.flow {
+            flow.remove(transfer_id);
+        }
+    }
+
+    /// An error ends the transfer it refers to, as if it had been closed
+    fn abort_transfer(&mut self, transfer_id: u32) {
+        if let Some(state) = self.transfers.get(&transfer_id) {
+            let active = state.is_active();
+            self.end_transfer(transfer_id);
+            if active {
+                self.push_event(ShiftClientEvent::TransferClosed(transfer_id));
+            }
+        }
+    }
+
+    /// Transfer IDs are picked by the requesting side, so each side starts at a random
+    /// point to make collisions between concurrent requests unlikely
+    fn allocate_transfer_id(&mut self) -> u32 {
+        if !self.has_feature(FEATURE_MULTIPLEX) {
+            return 0;
+        }
+        loop {
+            self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+            if self.next_transfer_id != 0 && !self.transfers.contains_key(&self.next_transfer_id) {
+                return self.next_transfer_id;
+            }
+        }
+    }
+
+    fn allocate_request_id(&mut self) -> u32 {
+        loop {
+            self.next_request_id = self.next_request_id.wrapping_add(1);
+            if self.next_request_id != 0 && !self.requests.contains(&self.next_request_id) {
+                return self.next_request_id;
+            }
+        }
+    }
+
+    /// With multiplexing, several clients may share the initiator's terminal on the other
+    /// side, so the answering side sees more than one Init and Disconnect
+    fn shares_terminal(&self) -> bool {
+        self.has_feature(FEATURE_MULTIPLEX) && !self.initiator
+    }
+
+    /// Whether a message belongs to a transfer of another client sharing this side's terminal.
+    /// Clients never get transfers they did not request, so the initiator ignores those.
+    fn is_foreign_transfer(&self, transfer_id: u32) -> bool {
+        self.has_feature(FEATURE_MULTIPLEX)
+            && self.initiator
+            && transfer_id != 0
+            && !self.transfers.contains_key(&transfer_id)
+    }
+
+    /// Like [`Self::is_foreign_transfer`], for answers to browse requests
+    fn is_foreign_request(&self, request_id: u32) -> bool {
+        self.has_feature(FEATURE_MULTIPLEX)
+            && self.initiator
+            && request_id != 0
+            && !self.requests.contains(&request_id)
+    }
+
+    fn check_browse(&self) -> Result<()> {
+        if !self.has_feature(FEATURE_BROWSE) {
+            bail!(ClientError::InvalidStateError(
+                "Browsing was not negotiated"
+            ));
+        }
+        Ok(())
+    }
+
     fn push_event(&mut self, event: ShiftClientEvent) {
         // println!("machine event: {:?}", event);
         self.events.push(event);
@@ -88,218 +447,723 @@ impl<'a> ShiftClient<'a> {
 
     fn consume(&mut self, input: Input) -> Result<()> {
         // println!("machine input: {:?}", input);
+        // Waiters only wake up once the input is through and the client is unlocked
+        self.changed.notify_all();
+        if matches!(input, Input::IncomingMessage(_)) {
+            self.keepalive.on_heard();
+        }
         match (&self.state, input) {
             (State::Initial, Input::Start) => {
-                self.writer.write(Content::Init(api::Init {
-                    version: 1,
-                    features: vec![],
-                }))?;
+                self.initiator = true;
+                match self.writer.session() {
+                    Some(session) => {
+                        let key_exchange = session.key_exchange();
+                        self.writer.write(Content::KeyExchange(key_exchange))?;
+                        self.transition(State::Securing);
+                    }
+                    None => {
+                        self.writer.write(Content::Init(self.init_message()))?;
+                        self.transition(State::Connecting);
+                    }
+                }
+            }
+
+            (State::Initial, Input::IncomingMessage(Content::KeyExchange(key_exchange))) => {
+                let reply = match self.writer.session() {
+                    Some(session) => session.respond(&key_exchange),
+                    None => Err(anyhow!("Peer requires a secure session")),
+                };
+                match reply {
+                    Ok(reply) => self.writer.write(Content::KeyExchange(reply))?,
+                    Err(error) => {
+                        self.writer.write(Content::Disconnect(api::Disconnect {}))?;
+                        self.transition(State::Disconnected);
+                        return Err(error);
+                    }
+                }
+            }
+
+            (State::Securing, Input::IncomingMessage(Content::KeyExchange(key_exchange))) => {
+                let session = self.writer.session().cloned();
+                if let Some(Err(error)) = session.map(|x| x.complete(&key_exchange)) {
+                    self.transition(State::Disconnected);
+                    return Err(error);
+                }
+                self.writer.write(Content::Init(self.init_message()))?;
                 self.transition(State::Connecting);
             }
 
-            (State::Initial, Input::IncomingMessage(Content::Init(_))) => {
-                self.writer.write(Content::Init(api::Init {
-                    version: 1,
-                    features: vec![],
-                }))?;
+            (State::Initial, Input::IncomingMessage(Content::Init(_)))
+                if self
+                    .writer
+                    .session()
+                    .map(|x| !x.is_established())
+                    .unwrap_or(false) =>
+            {
+                self.writer.write(Content::Disconnect(api::Disconnect {}))?;
+                self.transition(State::Disconnected);
+                bail!("Peer did not establish a secure session");
+            }
+
+            (State::Initial, Input::IncomingMessage(Content::Init(init))) => {
+                self.writer.write(Content::Init(self.init_message()))?;
+                self.negotiate(init)?;
                 self.push_event(ShiftClientEvent::Connected);
-                self.transition(State::Idle);
+                self.transition(State::Connected);
             }
 
-            (State::Connecting, Input::IncomingMessage(Content::Init(_))) => {
+            (State::Connecting, Input::IncomingMessage(Content::Init(init))) => {
+                self.negotiate(init)?;
                 self.push_event(ShiftClientEvent::Connected);
-                self.transition(State::Idle);
+                self.transition(State::Connected);
+            }
+
+            // Another client sharing the peer's terminal is connecting
+            (State::Connected, Input::IncomingMessage(Content::Init(init))) => {
+                if !self.initiator {
+                    self.writer.write(Content::Init(self.init_message()))?;
+                    if self.transfers.is_empty() {
+                        self.negotiate(init)?;
+                    }
+                    self.push_event(ShiftClientEvent::Connected);
+                }
             }
 
+            // ...or leaving while this side still has transfers with others
+            (State::Connected, Input::IncomingMessage(Content::Disconnect(_)))
+                if self.shares_terminal() && !self.transfers.is_empty() => {}
+
             (_, Input::IncomingMessage(Content::Disconnect(_))) => {
-                self.push_event(ShiftClientEvent::Disconnected);
+                self.push_event(ShiftClientEvent::Disconnected(DisconnectReason::Peer));
                 self.transition(State::Disconnected);
             }
 
+            (State::Connected, Input::Tick(now)) => {
+                // A paused transfer is not expected to make progress
+                let transferring = self
+                    .transfers
+                    .iter()
+                    .any(|(id, x)| x.is_active() && !self.paused.contains(id));
+                let outcome =
+                    self.keepalive
+                        .tick(now, self.has_feature(FEATURE_KEEPALIVE), transferring);
+                if let Some(latency) = outcome.latency {
+                    self.push_event(ShiftClientEvent::LatencyMeasured(latency));
+                }
+                if let Some(reason) = outcome.timeout {
+                    // No goodbye, writing to a stalled peer could block forever
+                    self.push_event(ShiftClientEvent::Disconnected(reason));
+                    self.transition(State::Disconnected);
+                } else if let Some(id) = outcome.ping {
+                    self.writer.write(Content::Ping(api::Ping { id }))?;
+                }
+            }
+
+            (_, Input::Tick(_)) => {}
+
+            (State::Connected, Input::IncomingMessage(Content::Ping(ping))) => {
+                self.writer
+                    .write(Content::Pong(api::Pong { id: ping.id }))?;
+            }
+
+            (State::Connected, Input::IncomingMessage(Content::Pong(pong))) => {
+                self.keepalive.on_pong(pong.id);
+            }
+
+            (_, Input::IncomingMessage(Content::Unsupported(unsupported))) => {
+                self.push_event(ShiftClientEvent::Unsupported(unsupported));
+            }
+
+            (_, Input::RejectUnsupported(message_type)) => {
+                self.writer
+                    .write(Content::Unsupported(api::Unsupported { message_type }))?;
+            }
+
+            (_, Input::SendError(error)) => {
+                if !self.has_feature(FEATURE_ERRORS) {
+                    bail!(ClientError::InvalidStateError(
+                        "Error reporting was not negotiated"
+                    ));
+                }
+                let (transfer_id, request_id) = (error.transfer_id, error.request_id);
+                self.writer.write(Content::Error(error))?;
+                if request_id == 0 {
+                    self.abort_transfer(transfer_id);
+                }
+            }
+
+            (_, Input::IncomingMessage(Content::Error(error)))
+                if self.is_foreign_transfer(error.transfer_id)
+                    || self.is_foreign_request(error.request_id) => {}
+
+            (_, Input::IncomingMessage(Content::Error(error))) => {
+                let (transfer_id, request_id) = (error.transfer_id, error.request_id);
+                self.push_event(ShiftClientEvent::PeerError(error));
+                if request_id == 0 {
+                    self.abort_transfer(transfer_id);
+                } else {
+                    self.requests.remove(&request_id);
+                }
+            }
+
+            // Browsing
+            (State::Connected, Input::ListDirectory(request)) => {
+                self.check_browse()?;
+                self.requests.insert(request.request_id);
+                self.writer.write(Content::ListDirectory(request))?;
+            }
+
+            (State::Connected, Input::IncomingMessage(Content::ListDirectory(request))) => {
+                self.push_event(ShiftClientEvent::ListDirectoryRequested(request));
+            }
+
+            (State::Connected, Input::SendDirectoryListing(listing)) => {
+                self.check_browse()?;
+                self.writer.write(Content::DirectoryListing(listing))?;
+            }
+
+            (State::Connected, Input::IncomingMessage(Content::DirectoryListing(listing)))
+                if self.requests.remove(&listing.request_id) =>
+            {
+                self.push_event(ShiftClientEvent::DirectoryListing(listing));
+            }
+
+            (State::Connected, Input::Stat(request)) => {
+                self.check_browse()?;
+                self.requests.insert(request.request_id);
+                self.writer.write(Content::Stat(request))?;
+            }
+
+            (State::Connected, Input::IncomingMessage(Content::Stat(request))) => {
+                self.push_event(ShiftClientEvent::StatRequested(request));
+            }
+
+            (State::Connected, Input::SendStatResult(result)) => {
+                self.check_browse()?;
+                self.writer.write(Content::StatResult(result))?;
+            }
+
+            (State::Connected, Input::IncomingMessage(Content::StatResult(result)))
+                if self.requests.remove(&result.request_id) =>
+            {
+                self.push_event(ShiftClientEvent::StatResult(result));
+            }
+
+            // Answers to another client sharing this side's terminal
+            (
+                State::Connected,
+                Input::IncomingMessage(Content::DirectoryListing(api::DirectoryListing {
+                    request_id,
+                    ..
+                })),
+            )
+            | (
+                State::Connected,
+                Input::IncomingMessage(Content::StatResult(api::StatResult { request_id, .. })),
+            ) if self.is_foreign_request(request_id) => {}
+
             (_, Input::Disconnect) => {
-                self.push_event(ShiftClientEvent::Disconnected);
+                self.push_event(ShiftClientEvent::Disconnected(DisconnectReason::Local));
                 self.writer.write(Content::Disconnect(api::Disconnect {}))?;
                 self.transition(State::Disconnected);
             }
 
+            (State::Disconnected, Input::Abandon) => {}
+
+            (_, Input::Abandon) => {
+                self.push_event(ShiftClientEvent::Disconnected(DisconnectReason::Local));
+                self.transition(State::Disconnected);
+            }
+
+            // Traffic of another client sharing the peer's terminal that is still connecting
+            (State::Connecting, Input::IncomingMessage(content))
+                if message_transfer_id(&content).is_some() => {}
+
+            (State::Connected, input) if input.transfer_id().is_some() => {
+                let transfer_id = input.transfer_id().unwrap_or_default();
+                self.keepalive.on_progress();
+                self.consume_transfer(transfer_id, input)?;
+            }
+
+            // Stragglers from a transfer that was already closed or aborted by an error
+            (_, input) if input.is_straggler() => {}
+
+            (_, input) => {
+                bail!(ClientError::InvalidTransitionError {
+                    state: Box::new(self.state.clone()),
+                    input,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn consume_transfer(&mut self, transfer_id: u32, input: Input) -> Result<()> {
+        match (self.transfers.get(&transfer_id).cloned(), input) {
+            (None, Input::IncomingMessage(_)) if self.is_foreign_transfer(transfer_id) => {}
+
             // Inbound transfer handling
-            (State::Idle, Input::RequestInboundTransfer(transfer)) => {
-                self.transition(State::InboundTransferRequested(transfer.clone()));
+            (None, Input::RequestInboundTransfer(transfer)) => {
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundTransferRequested(transfer.clone()),
+                );
                 self.writer.write(Content::ReceiveRequest(transfer))?;
             }
 
             (
-                State::Idle | State::InboundTransferRequested(_),
+                None | Some(TransferState::InboundTransferRequested(_)),
                 Input::IncomingMessage(Content::SendRequest(transfer)),
             ) => {
                 self.push_event(ShiftClientEvent::InboundTransferOffered(transfer.clone()));
-                self.transition(State::InboundTransferOffered(transfer));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundTransferOffered(transfer),
+                );
             }
 
-            (State::InboundTransferOffered(transfer), Input::AcceptTransfer) => {
+            (Some(TransferState::InboundTransferOffered(transfer)), Input::AcceptTransfer(_)) => {
                 self.writer
-                    .write(Content::AcceptTransfer(api::AcceptTransfer {}))?;
-                let transfer = transfer.clone();
-                self.transition(State::InboundTransfer(transfer, None));
+                    .write(Content::AcceptTransfer(api::AcceptTransfer { transfer_id }))?;
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundTransfer(transfer, None),
+                );
             }
 
-            (State::InboundTransferOffered(_), Input::RejectTransfer) => {
+            (Some(TransferState::InboundTransferOffered(_)), Input::RejectTransfer(_)) => {
                 self.writer
-                    .write(Content::RejectTransfer(api::RejectTransfer {}))?;
-                self.transition(State::Idle);
+                    .write(Content::RejectTransfer(api::RejectTransfer { transfer_id }))?;
+                self.end_transfer(transfer_id);
             }
 
             (
-                State::InboundTransfer(transfer, _),
+                Some(TransferState::InboundTransfer(_, None)),
+                Input::IncomingMessage(Content::TransferManifest(manifest)),
+            ) => {
+                self.push_event(ShiftClientEvent::TransferManifest(manifest));
+            }
+
+            (
+                Some(TransferState::InboundTransfer(transfer, _)),
                 Input::IncomingMessage(Content::OpenFile(file)),
             ) => {
-                let transfer = transfer.clone();
                 self.push_event(ShiftClientEvent::InboundFileOpening(
                     transfer.clone(),
                     file.clone(),
                 ));
-                self.transition(State::InboundTransfer(transfer, Some(file)));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundTransfer(transfer, Some(file)),
+                );
             }
 
             (
-                State::InboundTransfer(transfer, Some(requested_file)),
+                Some(TransferState::InboundTransfer(transfer, Some(requested_file))),
                 Input::ConfirmFileOpened(file),
             ) => {
-                let transfer = transfer.clone();
-                let requested_file = requested_file.clone();
+                if !file.prefix_hash_algorithm.is_empty()
+                    && !self.has_hash_feature(&file.prefix_hash_algorithm)
+                {
+                    bail!(ClientError::InvalidStateError(
+                        "Hash algorithm was not negotiated"
+                    ));
+                }
                 self.writer.write(Content::FileOpened(file))?;
-                self.transition(State::InboundFileTransfer(
-                    transfer,
-                    Some(OpenFile {
-                        info: requested_file
-                            .file_info
-                            .ok_or(ClientError::InvalidStateError(
-                                "Missing open file information",
-                            ))?,
-                    }),
-                ));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundFileTransfer(
+                        transfer,
+                        Some(OpenFile {
+                            info: requested_file.file_info.ok_or(
+                                ClientError::InvalidStateError("Missing open file information"),
+                            )?,
+                            transfer_id,
+                            file_id: requested_file.file_id,
+                        }),
+                    ),
+                );
+            }
+
+            (
+                Some(TransferState::InboundTransfer(transfer, Some(_))),
+                Input::RejectFile(reject),
+            ) => {
+                if !self.has_feature(FEATURE_REJECT_FILE) {
+                    bail!(ClientError::InvalidStateError(
+                        "Rejecting files was not negotiated"
+                    ));
+                }
+                self.writer.write(Content::RejectFile(reject))?;
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundTransfer(transfer, None),
+                );
+            }
+
+            // The receiver may already have confirmed the file by the time the attributes arrive
+            (
+                Some(
+                    TransferState::InboundTransfer(_, Some(_))
+                    | TransferState::InboundFileTransfer(_, Some(_)),
+                ),
+                Input::IncomingMessage(Content::FileAttributes(attributes)),
+            ) => {
+                if !self.has_feature(FEATURE_XATTRS) {
+                    bail!(ClientError::InvalidStateError(
+                        "Extended attributes were not negotiated"
+                    ));
+                }
+                self.push_event(ShiftClientEvent::FileAttributes(attributes));
+            }
+
+            (
+                Some(TransferState::InboundTransfer(_, Some(_))),
+                Input::SendBlockSignatures(signatures),
+            ) => {
+                if !self.has_feature(FEATURE_DELTA) {
+                    bail!(ClientError::InvalidStateError(
+                        "Delta transfers were not negotiated"
+                    ));
+                }
+                if !self.has_hash_feature(&signatures.hash_algorithm) {
+                    bail!(ClientError::InvalidStateError(
+                        "Hash algorithm was not negotiated"
+                    ));
+                }
+                self.writer.write(Content::BlockSignatures(signatures))?;
             }
 
             // Outbound transfer handling
-            (State::Idle, Input::IncomingMessage(Content::ReceiveRequest(request))) => {
+            (None, Input::IncomingMessage(Content::ReceiveRequest(request))) => {
                 self.push_event(ShiftClientEvent::OutboundTransferOffered(request));
             }
 
-            (State::Idle, Input::RequestOutboundTransfer(transfer)) => {
-                self.transition(State::OutboundTransferRequested(transfer.clone()));
+            (None, Input::RequestOutboundTransfer(transfer)) => {
+                if transfer.file_info.as_ref().is_some_and(|x| x.size_unknown) {
+                    self.check_streams()?;
+                }
+                if (!transfer.destination.is_empty() || !transfer.tags.is_empty())
+                    && !self.has_feature(FEATURE_DESTINATION_HINTS)
+                {
+                    bail!(ClientError::InvalidStateError(
+                        "Destination hints were not negotiated"
+                    ));
+                }
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::OutboundTransferRequested(transfer.clone()),
+                );
                 self.writer.write(Content::SendRequest(transfer))?;
             }
 
             (
-                State::OutboundTransferRequested(requested_transfer),
+                Some(TransferState::OutboundTransferRequested(requested_transfer)),
                 Input::IncomingMessage(Content::AcceptTransfer(_)),
             ) => {
-                let requested_transfer = requested_transfer.clone();
-                self.push_event(ShiftClientEvent::TransferAccepted());
-                self.transition(State::OutboundTransfer(requested_transfer, None));
+                self.push_event(ShiftClientEvent::TransferAccepted(transfer_id));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::OutboundTransfer(requested_transfer, None),
+                );
             }
 
             (
-                State::OutboundTransferRequested(_),
+                Some(TransferState::OutboundTransferRequested(_)),
                 Input::IncomingMessage(Content::RejectTransfer(_)),
             ) => {
-                self.push_event(ShiftClientEvent::TransferRejected());
-                self.transition(State::Idle)
+                self.push_event(ShiftClientEvent::TransferRejected(transfer_id));
+                self.end_transfer(transfer_id);
+            }
+
+            (
+                Some(TransferState::OutboundTransfer(_, None)),
+                Input::SendTransferManifest(manifest),
+            ) => {
+                if !self.has_feature(FEATURE_MANIFEST) {
+                    bail!(ClientError::InvalidStateError(
+                        "Transfer manifests were not negotiated"
+                    ));
+                }
+                self.writer.write(Content::TransferManifest(manifest))?;
             }
 
-            (State::OutboundTransfer(transfer, _), Input::OpenFile(file)) => {
-                let transfer = transfer.clone();
+            (Some(TransferState::OutboundTransfer(transfer, _)), Input::OpenFile(file)) => {
+                if file.file_info.as_ref().is_some_and(|x| x.size_unknown) {
+                    self.check_streams()?;
+                }
                 self.writer.write(Content::OpenFile(file.clone()))?;
-                self.transition(State::OutboundTransfer(transfer, Some(file)));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::OutboundTransfer(transfer, Some(file)),
+                );
+            }
+
+            (
+                Some(TransferState::OutboundTransfer(_, Some(_))),
+                Input::SendFileAttributes(attributes),
+            ) => {
+                if !self.has_feature(FEATURE_XATTRS) {
+                    bail!(ClientError::InvalidStateError(
+                        "Extended attributes were not negotiated"
+                    ));
+                }
+                self.writer.write(Content::FileAttributes(attributes))?;
             }
 
             (
-                State::OutboundTransfer(transfer, Some(requested_file)),
+                Some(TransferState::OutboundTransfer(_, Some(_))),
+                Input::IncomingMessage(Content::BlockSignatures(signatures)),
+            ) => {
+                if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&signatures.block_size) {
+                    bail!(ClientError::InvalidStateError("Block size is out of range"));
+                }
+                self.push_event(ShiftClientEvent::BlockSignatures(signatures));
+            }
+
+            (
+                Some(TransferState::OutboundTransfer(transfer, Some(requested_file))),
                 Input::IncomingMessage(Content::FileOpened(file)),
             ) => {
-                let transfer = transfer.clone();
-                let requested_file = requested_file.clone();
                 let open_file = OpenFile {
                     info: requested_file
                         .file_info
                         .ok_or(ClientError::InvalidStateError(
                             "Missing file info in request",
                         ))?,
+                    transfer_id,
+                    file_id: requested_file.file_id,
                 };
+                if let Some(flow) = &mut self.flow {
+                    flow.reset(transfer_id, file.continue_from);
+                }
                 self.push_event(ShiftClientEvent::FileTransferStarted(
                     open_file.clone(),
                     file,
                 ));
-                self.transition(State::OutboundFileTransfer(transfer, Some(open_file)));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::OutboundFileTransfer(transfer, Some(open_file)),
+                );
+            }
+
+            (
+                Some(TransferState::OutboundTransfer(transfer, Some(requested_file))),
+                Input::IncomingMessage(Content::RejectFile(_)),
+            ) => {
+                let file = OpenFile {
+                    info: requested_file
+                        .file_info
+                        .ok_or(ClientError::InvalidStateError(
+                            "Missing file info in request",
+                        ))?,
+                    transfer_id,
+                    file_id: requested_file.file_id,
+                };
+                self.push_event(ShiftClientEvent::FileRejected(file));
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::OutboundTransfer(transfer, None),
+                );
             }
 
             // General transfer handling
-            (State::OutboundFileTransfer(_, _), Input::SendChunk(chunk)) => {
+            (Some(TransferState::OutboundFileTransfer(_, _)), Input::SendChunk(chunk)) => {
+                if let Some(feature) = chunk.compression().feature() {
+                    if !self.has_feature(feature) {
+                        bail!(ClientError::InvalidStateError(
+                            "Chunk compression was not negotiated"
+                        ));
+                    }
+                }
+                if chunk.hole_length > 0 && !self.has_feature(FEATURE_SPARSE) {
+                    bail!(ClientError::InvalidStateError(
+                        "Sparse files were not negotiated"
+                    ));
+                }
+                self.check_restart(&chunk)?;
+                if chunk.end_of_stream {
+                    self.check_streams()?;
+                }
+                if let Some(flow) = &mut self.flow {
+                    flow.rewind(transfer_id, chunk.offset);
+                    flow.on_send(transfer_id, chunk.offset + chunk_length(&chunk), self.clock);
+                }
+                // Go to the back of the line so other transfers get their turn
+                self.send_queue.retain(|x| *x != transfer_id);
                 self.writer.write(Content::Chunk(chunk))?;
             }
 
-            (State::InboundFileTransfer(_, _), Input::IncomingMessage(Content::Chunk(chunk))) => {
-                self.push_event(ShiftClientEvent::Chunk(chunk));
+            (
+                Some(TransferState::InboundFileTransfer(_, _)),
+                Input::IncomingMessage(Content::Chunk(chunk)),
+            ) => {
+                if let Some(feature) = chunk.compression().feature() {
+                    if !self.has_feature(feature) {
+                        bail!(ClientError::InvalidStateError(
+                            "Chunk compression was not negotiated"
+                        ));
+                    }
+                }
+                if chunk.hole_length > 0 && !self.has_feature(FEATURE_SPARSE) {
+                    bail!(ClientError::InvalidStateError(
+                        "Sparse files were not negotiated"
+                    ));
+                }
+                self.check_restart(&chunk)?;
+                if chunk.end_of_stream {
+                    self.check_streams()?;
+                }
+                self.push_event(ShiftClientEvent::Chunk(decompress_chunk(chunk)?));
             }
 
-            (State::InboundFileTransfer(_, _), Input::AcknowledgeChunk) => {
-                self.writer
-                    .write(Content::AcknowledgeChunk(api::AcknowledgeChunk {}))?;
+            (Some(TransferState::OutboundFileTransfer(_, _)), Input::SendCopyBlock(copy)) => {
+                if !self.has_feature(FEATURE_DELTA) {
+                    bail!(ClientError::InvalidStateError(
+                        "Delta transfers were not negotiated"
+                    ));
+                }
+                self.writer.write(Content::CopyBlock(copy))?;
+            }
+
+            (
+                Some(TransferState::InboundFileTransfer(_, _)),
+                Input::IncomingMessage(Content::CopyBlock(copy)),
+            ) => {
+                if !self.has_feature(FEATURE_DELTA) {
+                    bail!(ClientError::InvalidStateError(
+                        "Delta transfers were not negotiated"
+                    ));
+                }
+                self.push_event(ShiftClientEvent::CopyBlock(copy));
             }
 
-            (State::OutboundFileTransfer(transfer, Some(file)), Input::CloseFile) => {
-                let transfer = transfer.clone();
-                let file = file.clone();
-                self.transition(State::OutboundTransfer(transfer, None));
-                self.writer.write(Content::CloseFile(api::CloseFile {}))?;
-                self.push_event(ShiftClientEvent::FileClosed(file));
+            // The reader thread may already have seen CloseFile by the time the last chunk is acknowledged
+            (
+                Some(
+                    TransferState::InboundFileTransfer(_, _) | TransferState::InboundTransfer(_, _),
+                ),
+                Input::AcknowledgeChunk(ack),
+            ) => {
+                self.writer.write(Content::AcknowledgeChunk(ack))?;
             }
 
             (
-                State::InboundFileTransfer(transfer, Some(file)),
-                Input::IncomingMessage(Content::CloseFile(_)),
+                Some(
+                    TransferState::OutboundFileTransfer(_, _)
+                    | TransferState::OutboundTransfer(_, _),
+                ),
+                Input::IncomingMessage(Content::AcknowledgeChunk(ack)),
             ) => {
-                let file = file.clone();
-                let transfer = transfer.clone();
-                self.transition(State::InboundTransfer(transfer, None));
-                self.push_event(ShiftClientEvent::FileClosed(file));
+                if let Some(flow) = &mut self.flow {
+                    flow.on_ack(transfer_id, ack.offset, self.clock);
+                }
             }
 
             (
-                State::InboundFileTransfer(_, _)
-                | State::InboundTransfer(_, _)
-                | State::OutboundFileTransfer(_, _)
-                | State::OutboundTransfer(_, _),
-                Input::CloseTransfer,
+                Some(TransferState::OutboundFileTransfer(transfer, Some(file))),
+                Input::CloseFile(close),
             ) => {
-                self.transition(State::Idle);
-                self.writer
-                    .write(Content::CloseTransfer(api::CloseTransfer {}))?;
-                self.push_event(ShiftClientEvent::TransferClosed);
+                if !close.hash_algorithm.is_empty() && !self.has_hash_feature(&close.hash_algorithm)
+                {
+                    bail!(ClientError::InvalidStateError(
+                        "Hash algorithm was not negotiated"
+                    ));
+                }
+                self.send_queue.retain(|x| *x != transfer_id);
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::OutboundTransfer(transfer, None),
+                );
+                self.writer.write(Content::CloseFile(close.clone()))?;
+                self.push_event(ShiftClientEvent::FileClosed(file, close));
             }
 
             (
-                State::InboundFileTransfer(_, _)
-                | State::InboundTransfer(_, _)
-                | State::OutboundFileTransfer(_, _)
-                | State::OutboundTransfer(_, _),
-                Input::IncomingMessage(Content::CloseTransfer(_)),
+                Some(TransferState::InboundFileTransfer(transfer, Some(file))),
+                Input::IncomingMessage(Content::CloseFile(close)),
             ) => {
-                self.transition(State::Idle);
-                self.push_event(ShiftClientEvent::TransferClosed);
+                self.transition_transfer(
+                    transfer_id,
+                    TransferState::InboundTransfer(transfer, None),
+                );
+                self.push_event(ShiftClientEvent::FileClosed(file, close));
             }
 
             (
-                _,
-                Input::CloseTransfer
-                | Input::CloseFile
-                | Input::IncomingMessage(Content::CloseTransfer(_))
-                | Input::IncomingMessage(Content::CloseFile(_)),
-            ) => {}
+                Some(TransferState::InboundTransfer(_, None)),
+                Input::SendFileVerification(verification),
+            ) => {
+                self.writer.write(Content::FileVerification(verification))?;
+            }
 
-            (_, input) => {
-                bail!(ClientError::InvalidTransitionError {
-                    state: self.state.clone(),
+            (
+                Some(TransferState::OutboundTransfer(_, None)),
+                Input::IncomingMessage(Content::FileVerification(verification)),
+            ) => {
+                self.push_event(ShiftClientEvent::FileVerified(verification));
+            }
+
+            (Some(state), Input::PauseTransfer(_)) if state.is_active() => {
+                if !self.has_feature(FEATURE_PAUSE) {
+                    bail!(ClientError::InvalidStateError(
+                        "Pausing transfers was not negotiated"
+                    ));
+                }
+                if self.paused.insert(transfer_id) {
+                    self.writer
+                        .write(Content::PauseTransfer(api::PauseTransfer { transfer_id }))?;
+                }
+            }
+
+            (Some(state), Input::ResumeTransfer(_)) if state.is_active() => {
+                if !self.has_feature(FEATURE_PAUSE) {
+                    bail!(ClientError::InvalidStateError(
+                        "Pausing transfers was not negotiated"
+                    ));
+                }
+                if self.paused.remove(&transfer_id) {
+                    self.writer
+                        .write(Content::ResumeTransfer(api::ResumeTransfer { transfer_id }))?;
+                }
+            }
+
+            (Some(state), Input::IncomingMessage(Content::PauseTransfer(_)))
+                if state.is_active() =>
+            {
+                if self.paused.insert(transfer_id) {
+                    self.push_event(ShiftClientEvent::TransferPaused(transfer_id));
+                }
+            }
+
+            (Some(state), Input::IncomingMessage(Content::ResumeTransfer(_)))
+                if state.is_active() =>
+            {
+                if self.paused.remove(&transfer_id) {
+                    self.push_event(ShiftClientEvent::TransferResumed(transfer_id));
+                }
+            }
+
+            (Some(state), Input::CloseTransfer(_)) if state.is_active() => {
+                self.end_transfer(transfer_id);
+                self.writer
+                    .write(Content::CloseTransfer(api::CloseTransfer { transfer_id }))?;
+                self.push_event(ShiftClientEvent::TransferClosed(transfer_id));
+            }
+
+            (Some(state), Input::IncomingMessage(Content::CloseTransfer(_)))
+                if state.is_active() =>
+            {
+                self.end_transfer(transfer_id);
+                self.push_event(ShiftClientEvent::TransferClosed(transfer_id));
+            }
+
+            (_, input) if input.is_straggler() => {}
+
+            (state, input) => {
+                bail!(ClientError::InvalidTransferTransitionError {
+                    transfer_id,
+                    state: state.map(Box::new),
                     input,
                 });
             }
@@ -313,6 +1177,120 @@ impl<'a> ShiftClient<'a> {
         events
     }
 
+    pub fn negotiated_version(&self) -> Option<u32> {
+        self.negotiated_version
+    }
+
+    pub fn negotiated_features(&self) -> &[String] {
+        &self.negotiated_features
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.negotiated_features.iter().any(|f| f == feature)
+    }
+
+    pub fn transfer_state(&self, transfer_id: u32) -> Option<&TransferState> {
+        self.transfers.get(&transfer_id)
+    }
+
+    /// Whether no transfer is in progress or being negotiated and no browse request is pending
+    pub fn is_idle(&self) -> bool {
+        self.transfers.is_empty() && self.requests.is_empty()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.state == State::Disconnected
+    }
+
+    /// Whether either side paused the transfer
+    pub fn is_paused(&self, transfer_id: u32) -> bool {
+        self.paused.contains(&transfer_id)
+    }
+
+    /// IDs of the transfers that were accepted and have not been closed yet
+    pub fn active_transfers(&self) -> Vec<u32> {
+        self.transfers
+            .iter()
+            .filter(|(_, x)| x.is_active())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Whether it is this transfer's turn to send and a chunk of `length` bytes fits into the
+    /// send window right now. Always true outside of an outbound file transfer, and an error
+    /// once disconnected.
+    pub fn can_send_chunk(&mut self, transfer_id: u32, length: u64) -> Result<bool> {
+        if self.state == State::Disconnected {
+            bail!(ClientError::InvalidStateError("Disconnected"));
+        }
+        match self.transfers.get(&transfer_id) {
+            Some(TransferState::OutboundFileTransfer(_, _)) => {}
+            _ => return Ok(true),
+        }
+        if self.paused.contains(&transfer_id) {
+            // Other transfers get their turn meanwhile
+            self.send_queue.retain(|x| *x != transfer_id);
+            self.changed.notify_all();
+            return Ok(false);
+        }
+        if !self.send_queue.contains(&transfer_id) {
+            self.send_queue.push_back(transfer_id);
+        }
+        Ok(self.send_queue.front() == Some(&transfer_id)
+            && self
+                .flow
+                .as_ref()
+                .map(|x| x.can_send(length))
+                .unwrap_or(true))
+    }
+
+    /// Signalled whenever something changed that a sender may be waiting for. Wait on it with
+    /// the guard of the mutex that holds this client.
+    pub fn changed(&self) -> Arc<Condvar> {
+        self.changed.clone()
+    }
+
+    pub fn send_window(&self) -> Option<u64> {
+        self.flow.as_ref().map(|x| x.window())
+    }
+
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.flow.as_ref().and_then(|x| x.round_trip_time())
+    }
+
+    /// Round trip time of the last keepalive ping
+    pub fn latency(&self) -> Option<Duration> {
+        self.keepalive.latency()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.keepalive.set_timeouts(timeouts);
+    }
+
+    fn has_hash_feature(&self, algorithm: &str) -> bool {
+        HashAlgorithm::from_name(algorithm)
+            .map(|x| self.has_feature(x.feature()))
+            .unwrap_or(false)
+    }
+
+    fn check_restart(&self, chunk: &api::Chunk) -> Result<()> {
+        if chunk.restart && !self.has_feature(FEATURE_RESTART) {
+            bail!(ClientError::InvalidStateError(
+                "Restarting files was not negotiated"
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_streams(&self) -> Result<()> {
+        if !self.has_feature(FEATURE_STREAMS) {
+            bail!(ClientError::InvalidStateError(
+                "Streams of unknown size were not negotiated"
+            ));
+        }
+        Ok(())
+    }
+
     pub fn start(&mut self) -> Result<()> {
         self.consume(Input::Start)
     }
@@ -321,47 +1299,148 @@ impl<'a> ShiftClient<'a> {
         self.consume(Input::Disconnect)
     }
 
+    /// Ends the session without telling the peer, which may have stopped listening
+    pub fn abandon(&mut self) -> Result<()> {
+        self.consume(Input::Abandon)
+    }
+
+    /// Lets time pass for keepalive pings and timeouts. Call regularly with the current time,
+    /// and right before sending a chunk or feeding an acknowledgement to time them precisely.
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        self.clock = Some(now);
+        self.consume(Input::Tick(now))
+    }
+
     pub fn feed_message(&mut self, msg: Content) -> Result<()> {
         self.consume(Input::IncomingMessage(msg))
     }
 
-    pub fn request_inbound_transfer(&mut self, request: api::ReceiveRequest) -> Result<()> {
-        self.consume(Input::RequestInboundTransfer(request))
+    pub fn reject_unsupported(&mut self, message_type: u32) -> Result<()> {
+        self.consume(Input::RejectUnsupported(message_type))
+    }
+
+    /// Returns the ID of the new transfer, allocating one unless `transfer_id` is set
+    pub fn request_inbound_transfer(&mut self, mut request: api::ReceiveRequest) -> Result<u32> {
+        if request.transfer_id == 0 {
+            request.transfer_id = self.allocate_transfer_id();
+        }
+        let transfer_id = request.transfer_id;
+        self.consume(Input::RequestInboundTransfer(request))?;
+        Ok(transfer_id)
+    }
+
+    /// Returns the ID of the new transfer, allocating one unless `transfer_id` is set, as it
+    /// is when answering a peer's `ReceiveRequest`
+    pub fn request_outbound_transfer(&mut self, mut request: api::SendRequest) -> Result<u32> {
+        if request.transfer_id == 0 {
+            request.transfer_id = self.allocate_transfer_id();
+        }
+        let transfer_id = request.transfer_id;
+        self.consume(Input::RequestOutboundTransfer(request))?;
+        Ok(transfer_id)
     }
 
-    pub fn request_outbound_transfer(&mut self, request: api::SendRequest) -> Result<()> {
-        self.consume(Input::RequestOutboundTransfer(request))
+    pub fn accept_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.consume(Input::AcceptTransfer(transfer_id))
     }
 
-    pub fn accept_transfer(&mut self) -> Result<()> {
-        self.consume(Input::AcceptTransfer)
+    pub fn reject_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.consume(Input::RejectTransfer(transfer_id))
     }
 
-    pub fn reject_transfer(&mut self) -> Result<()> {
-        self.consume(Input::RejectTransfer)
+    /// Announces the files of an outbound transfer before the first one is opened
+    pub fn send_transfer_manifest(&mut self, manifest: api::TransferManifest) -> Result<()> {
+        self.consume(Input::SendTransferManifest(manifest))
     }
 
-    pub fn open_file(&mut self, request: api::OpenFile) -> Result<()> {
-        self.consume(Input::OpenFile(request))
+    /// Returns the ID of the file, allocating one unless `file_id` is set
+    pub fn open_file(&mut self, mut request: api::OpenFile) -> Result<u32> {
+        if request.file_id == 0 {
+            self.next_file_id = self.next_file_id.wrapping_add(1);
+            request.file_id = self.next_file_id;
+        }
+        let file_id = request.file_id;
+        self.consume(Input::OpenFile(request))?;
+        Ok(file_id)
     }
 
     pub fn confirm_file_opened(&mut self, file: api::FileOpened) -> Result<()> {
         self.consume(Input::ConfirmFileOpened(file))
     }
 
+    pub fn reject_file(&mut self, reject: api::RejectFile) -> Result<()> {
+        self.consume(Input::RejectFile(reject))
+    }
+
+    pub fn send_file_attributes(&mut self, attributes: api::FileAttributes) -> Result<()> {
+        self.consume(Input::SendFileAttributes(attributes))
+    }
+
+    pub fn send_block_signatures(&mut self, signatures: api::BlockSignatures) -> Result<()> {
+        self.consume(Input::SendBlockSignatures(signatures))
+    }
+
     pub fn send_chunk(&mut self, chunk: api::Chunk) -> Result<()> {
         self.consume(Input::SendChunk(chunk))
     }
 
-    pub fn acknolwedge_chunk(&mut self) -> Result<()> {
-        self.consume(Input::AcknowledgeChunk)
+    pub fn send_copy_block(&mut self, copy: api::CopyBlock) -> Result<()> {
+        self.consume(Input::SendCopyBlock(copy))
+    }
+
+    pub fn acknowledge_chunk(&mut self, ack: api::AcknowledgeChunk) -> Result<()> {
+        self.consume(Input::AcknowledgeChunk(ack))
     }
 
-    pub fn close_file(&mut self) -> Result<()> {
-        self.consume(Input::CloseFile)
+    pub fn close_file(&mut self, close: api::CloseFile) -> Result<()> {
+        self.consume(Input::CloseFile(close))
+    }
+
+    pub fn send_file_verification(&mut self, verification: api::FileVerification) -> Result<()> {
+        self.consume(Input::SendFileVerification(verification))
+    }
+
+    pub fn send_error(&mut self, error: api::Error) -> Result<()> {
+        self.consume(Input::SendError(error))
+    }
+
+    pub fn pause_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.consume(Input::PauseTransfer(transfer_id))
+    }
+
+    pub fn resume_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.consume(Input::ResumeTransfer(transfer_id))
+    }
+
+    pub fn close_transfer(&mut self, transfer_id: u32) -> Result<()> {
+        self.consume(Input::CloseTransfer(transfer_id))
+    }
+
+    /// Returns the ID of the request, allocating one unless `request_id` is set
+    pub fn list_directory(&mut self, mut request: api::ListDirectory) -> Result<u32> {
+        if request.request_id == 0 {
+            request.request_id = self.allocate_request_id();
+        }
+        let request_id = request.request_id;
+        self.consume(Input::ListDirectory(request))?;
+        Ok(request_id)
+    }
+
+    pub fn send_directory_listing(&mut self, listing: api::DirectoryListing) -> Result<()> {
+        self.consume(Input::SendDirectoryListing(listing))
+    }
+
+    /// Returns the ID of the request, allocating one unless `request_id` is set
+    pub fn stat(&mut self, mut request: api::Stat) -> Result<u32> {
+        if request.request_id == 0 {
+            request.request_id = self.allocate_request_id();
+        }
+        let request_id = request.request_id;
+        self.consume(Input::Stat(request))?;
+        Ok(request_id)
     }
 
-    pub fn close_transfer(&mut self) -> Result<()> {
-        self.consume(Input::CloseTransfer)
+    pub fn send_stat_result(&mut self, result: api::StatResult) -> Result<()> {
+        self.consume(Input::SendStatResult(result))
     }
 }
@@ -0,0 +1,1185 @@
+#[cfg(test)]
+use super::api::{self, message::Content};
+#[cfg(test)]
+use super::compression::compress_chunk;
+#[cfg(test)]
+use super::*;
+#[cfg(test)]
+use prost::Message;
+#[cfg(test)]
+use std::io::{self, Write};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn client_with_features(features: &[&str]) -> (ShiftClient<'static>, SharedBuffer) {
+    let buffer = SharedBuffer::default();
+    let client = ShiftClient::with_features(
+        MessageWriter::new(TransportWriter::new(
+            TransportConfig::default(),
+            Box::new(buffer.clone()),
+        )),
+        features.iter().map(|x| x.to_string()).collect(),
+    );
+    (client, buffer)
+}
+
+#[cfg(test)]
+fn sent_messages(buffer: &SharedBuffer) -> Vec<Content> {
+    let data = std::mem::take(&mut *buffer.0.lock().unwrap());
+    MessageReader::new(TransportConfig::default())
+        .feed(&data)
+        .into_iter()
+        .filter_map(|x| match x {
+            MessageOutput::Message(content) => Some(content),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn pump(from: &SharedBuffer, to: &mut ShiftClient) {
+    for msg in sent_messages(from) {
+        to.feed_message(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+type Peer = (ShiftClient<'static>, SharedBuffer);
+
+/// Connects two clients and brings them into a file transfer from `sender` to `receiver`
+#[cfg(test)]
+fn open_file_transfer(features_a: &[&str], features_b: &[&str]) -> (Peer, Peer) {
+    let (mut sender, sender_buffer) = client_with_features(features_a);
+    let (mut receiver, receiver_buffer) = client_with_features(features_b);
+    let file_info = api::FileInfo {
+        name: "test".to_string(),
+        size: 4,
+        mode: 0o644,
+        ..Default::default()
+    };
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .request_outbound_transfer(api::SendRequest {
+            file_info: Some(file_info.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .open_file(api::OpenFile {
+            file_info: Some(file_info),
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver
+        .confirm_file_opened(api::FileOpened::default())
+        .unwrap();
+    pump(&receiver_buffer, &mut sender);
+    sender.take_events();
+    receiver.take_events();
+    ((sender, sender_buffer), (receiver, receiver_buffer))
+}
+
+#[test]
+fn test_negotiation_features() {
+    let (mut client, buffer) = client_with_features(&["a", "b"]);
+    let (mut server, server_buffer) = client_with_features(&["b", "c"]);
+    client.start().unwrap();
+    for msg in sent_messages(&buffer) {
+        server.feed_message(msg).unwrap();
+    }
+    for msg in sent_messages(&server_buffer) {
+        client.feed_message(msg).unwrap();
+    }
+    assert_eq!(client.negotiated_version(), Some(1));
+    assert_eq!(client.negotiated_features(), &["b".to_string()]);
+    assert_eq!(server.negotiated_features(), &["b".to_string()]);
+    assert!(client.has_feature("b"));
+    assert!(!client.has_feature("a"));
+}
+
+#[test]
+fn test_negotiation_legacy_peer() {
+    let (mut client, _) = client_with_features(&["a"]);
+    client.start().unwrap();
+    client
+        .feed_message(Content::Init(api::Init {
+            version: 1,
+            features: vec![],
+            supported_versions: vec![],
+            window_size: 0,
+        }))
+        .unwrap();
+    assert_eq!(client.negotiated_version(), Some(1));
+    assert!(client.negotiated_features().is_empty());
+}
+
+#[test]
+fn test_negotiation_incompatible_version() {
+    let (mut client, buffer) = client_with_features(&[]);
+    client.start().unwrap();
+    sent_messages(&buffer);
+    assert!(client
+        .feed_message(Content::Init(api::Init {
+            version: 99,
+            features: vec![],
+            supported_versions: vec![99],
+            window_size: 0,
+        }))
+        .is_err());
+    assert_eq!(client.negotiated_version(), None);
+    assert!(matches!(
+        sent_messages(&buffer)[..],
+        [Content::Disconnect(_)]
+    ));
+}
+
+#[test]
+fn test_unknown_message_is_unsupported() {
+    // Field 100 is not a known `Message.content` variant
+    let mut data = vec![];
+    prost::encoding::message::encode(100, &api::Disconnect {}, &mut data);
+    assert!(api::Message::decode(&data[..]).unwrap().content.is_none());
+
+    let buffer = SharedBuffer::default();
+    TransportWriter::new(TransportConfig::default(), Box::new(buffer.clone()))
+        .write(&data)
+        .unwrap();
+    let output = MessageReader::new(TransportConfig::default()).feed(&buffer.0.lock().unwrap());
+    assert!(matches!(output[..], [MessageOutput::Unsupported(100)]));
+
+    let (mut client, buffer) = client_with_features(&[]);
+    client.reject_unsupported(100).unwrap();
+    assert_eq!(
+        sent_messages(&buffer)[0],
+        Content::Unsupported(api::Unsupported { message_type: 100 })
+    );
+}
+
+#[test]
+fn test_file_verification() {
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&["hash-sha256"], &["hash-sha256"]);
+    let close = api::CloseFile {
+        hash_algorithm: "sha256".to_string(),
+        hash: vec![1, 2, 3],
+        ..Default::default()
+    };
+    sender.close_file(close.clone()).unwrap();
+    pump(&sender_buffer, &mut receiver);
+    assert!(matches!(
+        &receiver.take_events()[..],
+        [ShiftClientEvent::FileClosed(_, c)] if c == &close
+    ));
+
+    receiver
+        .send_file_verification(api::FileVerification {
+            name: "test".to_string(),
+            verified: true,
+            hash_algorithm: "sha256".to_string(),
+            hash: vec![1, 2, 3],
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&receiver_buffer, &mut sender);
+    let events = sender.take_events();
+    assert!(matches!(
+        &events[..],
+        [ShiftClientEvent::FileClosed(_, _), ShiftClientEvent::FileVerified(v)] if v.verified
+    ));
+}
+
+#[test]
+fn test_file_hash_requires_negotiation() {
+    let ((mut sender, _), _) = open_file_transfer(&["hash-sha256"], &["hash-blake3"]);
+    assert!(sender
+        .close_file(api::CloseFile {
+            hash_algorithm: "sha256".to_string(),
+            hash: vec![1, 2, 3],
+            ..Default::default()
+        })
+        .is_err());
+}
+
+#[test]
+fn test_flow_control_window() {
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&["flow-control"], &["flow-control"]);
+    let window = sender.send_window().unwrap();
+    let start = std::time::Instant::now();
+    sender.tick(start).unwrap();
+    sender
+        .send_chunk(api::Chunk {
+            offset: 0,
+            data: vec![0; window as usize],
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(!sender.can_send_chunk(0, 1).unwrap());
+
+    pump(&sender_buffer, &mut receiver);
+    receiver
+        .acknowledge_chunk(api::AcknowledgeChunk {
+            offset: window,
+            ..Default::default()
+        })
+        .unwrap();
+    // Flow control goes by the time of the last tick
+    sender
+        .tick(start + std::time::Duration::from_millis(30))
+        .unwrap();
+    pump(&receiver_buffer, &mut sender);
+    assert!(sender.can_send_chunk(0, window).unwrap());
+    assert_eq!(
+        sender.round_trip_time(),
+        Some(std::time::Duration::from_millis(30))
+    );
+}
+
+#[test]
+fn test_acknowledgement_wakes_waiting_sender() {
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&["flow-control"], &["flow-control"]);
+    let window = sender.send_window().unwrap();
+    sender
+        .send_chunk(api::Chunk {
+            data: vec![0; window as usize],
+            ..Default::default()
+        })
+        .unwrap();
+    let changed = sender.changed();
+    let sender = Arc::new(Mutex::new(sender));
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let waiter = std::thread::spawn({
+        let sender = sender.clone();
+        move || {
+            let mut client = sender.lock().unwrap();
+            tx.send(()).unwrap();
+            while !client.can_send_chunk(0, 1).unwrap() {
+                client = changed.wait(client).unwrap();
+            }
+        }
+    });
+    // The waiter holds the client until it waits
+    rx.recv().unwrap();
+
+    pump(&sender_buffer, &mut receiver);
+    receiver
+        .acknowledge_chunk(api::AcknowledgeChunk {
+            offset: window,
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&receiver_buffer, &mut sender.lock().unwrap());
+    waiter.join().unwrap();
+}
+
+#[test]
+fn test_flow_control_requires_negotiation() {
+    let ((mut sender, _), _) = open_file_transfer(&["flow-control"], &[]);
+    assert_eq!(sender.send_window(), None);
+    sender
+        .send_chunk(api::Chunk {
+            offset: 0,
+            data: vec![0; 1024],
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(sender.can_send_chunk(0, u64::MAX).unwrap());
+}
+
+#[test]
+fn test_chunk_compression() {
+    let features = ["compression-deflate", "flow-control"];
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&features, &features);
+    let data = "compressible ".repeat(1000).into_bytes();
+    let chunk = compress_chunk(0, &data, api::chunk::Compression::Deflate).unwrap();
+    assert_eq!(chunk.compression(), api::chunk::Compression::Deflate);
+    assert!(chunk.data.len() < data.len());
+    sender.send_chunk(chunk).unwrap();
+
+    pump(&sender_buffer, &mut receiver);
+    match &receiver.take_events()[..] {
+        [ShiftClientEvent::Chunk(chunk)] => {
+            assert_eq!(chunk.data, data);
+            assert_eq!(chunk.compression(), api::chunk::Compression::None);
+        }
+        other => panic!("unexpected events: {:?}", other),
+    }
+    receiver
+        .acknowledge_chunk(api::AcknowledgeChunk {
+            offset: data.len() as u64,
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&receiver_buffer, &mut sender);
+    assert!(sender
+        .can_send_chunk(0, sender.send_window().unwrap())
+        .unwrap());
+
+    // Incompressible data goes out as-is
+    let mut state = 0x2545f491u32;
+    let data: Vec<u8> = (0..4096)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let chunk = compress_chunk(0, &data, api::chunk::Compression::Deflate).unwrap();
+    assert_eq!(chunk.compression(), api::chunk::Compression::None);
+    assert_eq!(chunk.data, data);
+}
+
+#[test]
+fn test_chunk_compression_requires_negotiation() {
+    let ((mut sender, _), _) = open_file_transfer(&["compression-deflate"], &[]);
+    let data = "compressible ".repeat(1000).into_bytes();
+    let chunk = compress_chunk(0, &data, api::chunk::Compression::Deflate).unwrap();
+    assert!(sender.send_chunk(chunk.clone()).is_err());
+
+    // Nor is it accepted from a peer that sends it anyway
+    let (_, (mut receiver, _)) = open_file_transfer(&["compression-deflate"], &[]);
+    assert!(receiver
+        .feed_message(Content::Chunk(chunk.clone()))
+        .is_err());
+}
+
+#[test]
+fn test_chunk_decompression_is_bounded() {
+    let features = ["compression-deflate"];
+    let (_, (mut receiver, _)) = open_file_transfer(&features, &features);
+    // Inflates fine, but to more than any chunk may take
+    let data = vec![0; 2 * MAX_WINDOW_SIZE as usize];
+    let chunk = compress_chunk(0, &data, api::chunk::Compression::Deflate).unwrap();
+    assert!(chunk.data.len() < 64 * 1024);
+    assert!(receiver.feed_message(Content::Chunk(chunk)).is_err());
+}
+
+#[test]
+fn test_restart_requires_negotiation() {
+    let restart = api::Chunk {
+        restart: true,
+        ..Default::default()
+    };
+    let ((mut sender, sender_buffer), (mut receiver, _)) =
+        open_file_transfer(&["restart"], &["restart"]);
+    sender.send_chunk(restart.clone()).unwrap();
+    pump(&sender_buffer, &mut receiver);
+    assert!(matches!(
+        &receiver.take_events()[..],
+        [ShiftClientEvent::Chunk(chunk)] if chunk.restart
+    ));
+
+    let ((mut sender, _), (mut receiver, _)) = open_file_transfer(&["restart"], &[]);
+    assert!(sender.send_chunk(restart.clone()).is_err());
+    assert!(receiver.feed_message(Content::Chunk(restart)).is_err());
+}
+
+#[test]
+fn test_peer_error_aborts_transfer() {
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&["errors"], &["errors"]);
+    let error = api::Error::from_error(
+        &io::Error::from(io::ErrorKind::PermissionDenied).into(),
+        "test",
+    );
+    assert_eq!(error.code(), api::error::Code::PermissionDenied);
+    receiver.send_error(error.clone()).unwrap();
+    assert!(matches!(
+        receiver.take_events()[..],
+        [ShiftClientEvent::TransferClosed(0)]
+    ));
+
+    // A chunk already in flight must not break the receiver
+    sender
+        .send_chunk(api::Chunk {
+            offset: 0,
+            data: b"data".to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    match &sender.take_events()[..] {
+        [ShiftClientEvent::PeerError(received), ShiftClientEvent::TransferClosed(0)] => {
+            assert_eq!(received, &error);
+        }
+        other => panic!("unexpected events: {:?}", other),
+    }
+    assert!(sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .is_ok());
+}
+
+#[test]
+fn test_error_requires_negotiation() {
+    let (_, (mut receiver, _)) = open_file_transfer(&[], &["errors"]);
+    assert!(receiver
+        .send_error(api::Error::new(api::error::Code::Unknown, "failed", "test"))
+        .is_err());
+}
+
+#[test]
+fn test_sparse_hole_chunk() {
+    let ((mut sender, sender_buffer), (mut receiver, _)) =
+        open_file_transfer(&["sparse"], &["sparse"]);
+    let hole = api::Chunk {
+        offset: 0,
+        hole_length: 1 << 30,
+        ..Default::default()
+    };
+    sender.send_chunk(hole.clone()).unwrap();
+    // Holes cost nothing on the wire
+    assert!(sender_buffer.0.lock().unwrap().len() < 32);
+    pump(&sender_buffer, &mut receiver);
+    match &receiver.take_events()[..] {
+        [ShiftClientEvent::Chunk(chunk)] => assert_eq!(chunk, &hole),
+        other => panic!("unexpected events: {:?}", other),
+    }
+
+    let ((mut sender, _), (mut receiver, _)) = open_file_transfer(&["sparse"], &[]);
+    assert!(sender.send_chunk(hole.clone()).is_err());
+    assert!(receiver.feed_message(Content::Chunk(hole)).is_err());
+}
+
+#[test]
+fn test_file_attributes() {
+    let attributes = api::FileAttributes {
+        attributes: vec![api::ExtendedAttribute {
+            name: "user.tag".to_string(),
+            value: b"value".to_vec(),
+        }],
+        ..Default::default()
+    };
+    let (mut sender, sender_buffer) = client_with_features(&["xattrs"]);
+    let (mut receiver, receiver_buffer) = client_with_features(&["xattrs"]);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    assert!(sender.send_file_attributes(attributes.clone()).is_err());
+    sender.open_file(api::OpenFile::default()).unwrap();
+    sender.send_file_attributes(attributes.clone()).unwrap();
+    pump(&sender_buffer, &mut receiver);
+    match &receiver.take_events()[..] {
+        [_, _, ShiftClientEvent::InboundFileOpening(_, _), ShiftClientEvent::FileAttributes(received)] =>
+        {
+            assert_eq!(received, &attributes)
+        }
+        other => panic!("unexpected events: {:?}", other),
+    }
+
+    let ((mut sender, _), (mut receiver, _)) = open_file_transfer(&["xattrs"], &[]);
+    assert!(sender.send_file_attributes(attributes.clone()).is_err());
+    assert!(receiver
+        .feed_message(Content::FileAttributes(attributes))
+        .is_err());
+}
+
+/// Accepts every offered transfer and confirms every opened file on `receiver`
+#[cfg(test)]
+fn accept_all(receiver: &mut ShiftClient) {
+    for event in receiver.take_events() {
+        match event {
+            ShiftClientEvent::InboundTransferOffered(request) => {
+                receiver.accept_transfer(request.transfer_id).unwrap()
+            }
+            ShiftClientEvent::InboundFileOpening(_, file) => receiver
+                .confirm_file_opened(api::FileOpened {
+                    continue_from: 0,
+                    transfer_id: file.transfer_id,
+                    file_id: file.file_id,
+                    ..Default::default()
+                })
+                .unwrap(),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_concurrent_transfers() {
+    let (mut a, a_buffer) = client_with_features(&["multiplex"]);
+    let (mut b, b_buffer) = client_with_features(&["multiplex"]);
+    a.start().unwrap();
+    pump(&a_buffer, &mut b);
+    pump(&b_buffer, &mut a);
+
+    // Two uploads from a and a download into a at the same time
+    let first = a
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    let second = a
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    let download = a
+        .request_inbound_transfer(api::ReceiveRequest::default())
+        .unwrap();
+    assert_ne!(first, second);
+    pump(&a_buffer, &mut b);
+    for event in b.take_events() {
+        match event {
+            ShiftClientEvent::InboundTransferOffered(request) => {
+                b.accept_transfer(request.transfer_id).unwrap()
+            }
+            ShiftClientEvent::OutboundTransferOffered(request) => {
+                let id = b
+                    .request_outbound_transfer(api::SendRequest {
+                        transfer_id: request.transfer_id,
+                        ..Default::default()
+                    })
+                    .unwrap();
+                assert_eq!(id, download);
+            }
+            _ => {}
+        }
+    }
+    pump(&b_buffer, &mut a);
+    accept_all(&mut a);
+    pump(&b_buffer, &mut a);
+    pump(&a_buffer, &mut b);
+    for id in [first, second] {
+        a.open_file(api::OpenFile {
+            file_info: Some(api::FileInfo::default()),
+            transfer_id: id,
+            file_id: 0,
+        })
+        .unwrap();
+    }
+    pump(&a_buffer, &mut b);
+    accept_all(&mut b);
+    pump(&b_buffer, &mut a);
+    let started: Vec<_> = a
+        .take_events()
+        .into_iter()
+        .filter_map(|x| match x {
+            ShiftClientEvent::FileTransferStarted(file, _) => Some(file),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(started.len(), 2);
+
+    // Senders take turns
+    assert!(a.can_send_chunk(first, 1).unwrap());
+    assert!(!a.can_send_chunk(second, 1).unwrap());
+    a.send_chunk(api::Chunk {
+        data: b"1".to_vec(),
+        transfer_id: first,
+        file_id: started[0].file_id,
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(!a.can_send_chunk(first, 1).unwrap());
+    assert!(a.can_send_chunk(second, 1).unwrap());
+
+    pump(&a_buffer, &mut b);
+    match &b.take_events()[..] {
+        [ShiftClientEvent::Chunk(chunk)] => assert_eq!(chunk.transfer_id, first),
+        other => panic!("unexpected events: {:?}", other),
+    }
+
+    // Closing one transfer leaves the others alone
+    a.close_transfer(second).unwrap();
+    pump(&a_buffer, &mut b);
+    assert!(matches!(
+        &b.take_events()[..],
+        [ShiftClientEvent::TransferClosed(id)] if *id == second
+    ));
+    assert!(a.can_send_chunk(first, 1).unwrap());
+    assert!(a.transfer_state(download).is_some());
+    assert!(!a.is_idle());
+}
+
+#[test]
+fn test_single_transfer_without_multiplex() {
+    let (mut a, a_buffer) = client_with_features(&["multiplex"]);
+    let (mut b, b_buffer) = client_with_features(&[]);
+    a.start().unwrap();
+    pump(&a_buffer, &mut b);
+    pump(&b_buffer, &mut a);
+    assert_eq!(
+        a.request_outbound_transfer(api::SendRequest::default())
+            .unwrap(),
+        0
+    );
+    assert!(a
+        .request_outbound_transfer(api::SendRequest::default())
+        .is_err());
+}
+
+#[test]
+fn test_clients_sharing_terminal() {
+    let (mut host, host_buffer) = client_with_features(&["multiplex"]);
+    let (mut first, first_buffer) = client_with_features(&["multiplex"]);
+    let (mut second, second_buffer) = client_with_features(&["multiplex"]);
+    first.start().unwrap();
+    pump(&first_buffer, &mut host);
+    pump(&host_buffer, &mut first);
+    first.take_events();
+    let upload = first
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&first_buffer, &mut host);
+    host.take_events();
+
+    // Both clients see everything the host sends
+    second.start().unwrap();
+    pump(&second_buffer, &mut host);
+    host.accept_transfer(upload).unwrap();
+    let replies = sent_messages(&host_buffer);
+    for msg in replies {
+        first.feed_message(msg.clone()).unwrap();
+        second.feed_message(msg).unwrap();
+    }
+    assert!(matches!(
+        &host.take_events()[..],
+        [ShiftClientEvent::Connected]
+    ));
+    assert!(matches!(
+        &first.take_events()[..],
+        [ShiftClientEvent::TransferAccepted(id)] if *id == upload
+    ));
+    assert!(matches!(
+        &second.take_events()[..],
+        [ShiftClientEvent::Connected]
+    ));
+
+    // The second client leaving does not end the session
+    second.disconnect().unwrap();
+    pump(&second_buffer, &mut host);
+    assert!(host.take_events().is_empty());
+    assert!(host.transfer_state(upload).is_some());
+}
+
+#[test]
+fn test_prefix_hash_requires_negotiation() {
+    let (mut sender, sender_buffer) = client_with_features(&["hash-sha256"]);
+    let (mut receiver, receiver_buffer) = client_with_features(&["hash-blake3"]);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .open_file(api::OpenFile {
+            file_info: Some(api::FileInfo::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    let mut opened = api::FileOpened {
+        continue_from: 4,
+        prefix_hash_algorithm: "sha256".to_string(),
+        prefix_hash: vec![1, 2, 3],
+        ..Default::default()
+    };
+    assert!(receiver.confirm_file_opened(opened.clone()).is_err());
+    opened.prefix_hash_algorithm = String::new();
+    opened.prefix_hash = vec![];
+    assert!(receiver.confirm_file_opened(opened).is_ok());
+}
+
+#[test]
+fn test_delta_messages() {
+    let signatures = api::BlockSignatures {
+        block_size: 4096,
+        hash_algorithm: "sha256".to_string(),
+        blocks: vec![api::BlockSignature {
+            weak: 1,
+            strong: vec![2],
+        }],
+        ..Default::default()
+    };
+    let features = ["delta", "hash-sha256"];
+    let (mut sender, sender_buffer) = client_with_features(&features);
+    let (mut receiver, receiver_buffer) = client_with_features(&features);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .open_file(api::OpenFile {
+            file_info: Some(api::FileInfo::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.take_events();
+    for block_size in [0, 1, u64::MAX] {
+        let invalid = api::BlockSignatures {
+            block_size,
+            ..signatures.clone()
+        };
+        assert!(sender
+            .feed_message(Content::BlockSignatures(invalid))
+            .is_err());
+    }
+    receiver.send_block_signatures(signatures.clone()).unwrap();
+    receiver
+        .confirm_file_opened(api::FileOpened::default())
+        .unwrap();
+    pump(&receiver_buffer, &mut sender);
+    match &sender.take_events()[..] {
+        [.., ShiftClientEvent::BlockSignatures(received), ShiftClientEvent::FileTransferStarted(_, _)] =>
+        {
+            assert_eq!(received, &signatures)
+        }
+        other => panic!("unexpected events: {:?}", other),
+    }
+
+    let copy = api::CopyBlock {
+        count: 2,
+        ..Default::default()
+    };
+    sender.send_copy_block(copy.clone()).unwrap();
+    pump(&sender_buffer, &mut receiver);
+    assert!(matches!(
+        &receiver.take_events()[..],
+        [ShiftClientEvent::CopyBlock(received)] if received == &copy
+    ));
+
+    let ((mut sender, _), (mut receiver, _)) = open_file_transfer(&["delta"], &[]);
+    assert!(sender.send_copy_block(copy.clone()).is_err());
+    assert!(receiver.feed_message(Content::CopyBlock(copy)).is_err());
+}
+
+#[test]
+fn test_browse_requests() {
+    let features = ["browse", "errors"];
+    let (mut client, client_buffer) = client_with_features(&features);
+    let (mut host, host_buffer) = client_with_features(&features);
+    client.start().unwrap();
+    pump(&client_buffer, &mut host);
+    pump(&host_buffer, &mut client);
+    client.take_events();
+    host.take_events();
+
+    let request_id = client
+        .list_directory(api::ListDirectory {
+            path: "dir".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+    assert!(!client.is_idle());
+    pump(&client_buffer, &mut host);
+    match &host.take_events()[..] {
+        [ShiftClientEvent::ListDirectoryRequested(request)] => {
+            assert_eq!(request.request_id, request_id);
+            assert_eq!(request.path, "dir");
+        }
+        other => panic!("unexpected events: {:?}", other),
+    }
+    host.send_directory_listing(api::DirectoryListing {
+        request_id,
+        entries: vec![api::FileInfo::default()],
+        ..Default::default()
+    })
+    .unwrap();
+    pump(&host_buffer, &mut client);
+    assert!(matches!(
+        &client.take_events()[..],
+        [ShiftClientEvent::DirectoryListing(listing)] if listing.entries.len() == 1
+    ));
+    assert!(client.is_idle());
+
+    // A failed request does not affect transfers
+    let request_id = client.stat(api::Stat::default()).unwrap();
+    pump(&client_buffer, &mut host);
+    host.take_events();
+    host.send_error(api::Error {
+        request_id,
+        ..api::Error::new(api::error::Code::NotFound, "missing", "")
+    })
+    .unwrap();
+    pump(&host_buffer, &mut client);
+    assert!(matches!(
+        &client.take_events()[..],
+        [ShiftClientEvent::PeerError(error)] if error.request_id == request_id
+    ));
+    assert!(client.is_idle());
+
+    let (mut client, _) = client_with_features(&features);
+    let (mut host, host_buffer) = client_with_features(&[]);
+    host.start().unwrap();
+    pump(&host_buffer, &mut client);
+    assert!(client.stat(api::Stat::default()).is_err());
+}
+
+#[test]
+fn test_keepalive_ping() {
+    let start = std::time::Instant::now();
+    let (mut client, client_buffer) = client_with_features(&["keepalive"]);
+    let (mut host, host_buffer) = client_with_features(&["keepalive"]);
+    client.start().unwrap();
+    pump(&client_buffer, &mut host);
+    pump(&host_buffer, &mut client);
+    client.take_events();
+
+    client.tick(start).unwrap();
+    assert!(matches!(
+        &sent_messages(&client_buffer)[..],
+        [Content::Ping(api::Ping { id: 1 })]
+    ));
+    client
+        .feed_message(Content::Ping(api::Ping { id: 1 }))
+        .unwrap();
+    client
+        .feed_message(Content::Pong(api::Pong { id: 1 }))
+        .unwrap();
+    assert!(matches!(
+        &sent_messages(&client_buffer)[..],
+        [Content::Pong(api::Pong { id: 1 })]
+    ));
+    client
+        .tick(start + std::time::Duration::from_millis(40))
+        .unwrap();
+    assert!(matches!(
+        &client.take_events()[..],
+        [ShiftClientEvent::LatencyMeasured(latency)] if latency.as_millis() == 40
+    ));
+
+    client
+        .tick(start + std::time::Duration::from_secs(60))
+        .unwrap();
+    assert!(matches!(
+        &client.take_events()[..],
+        [ShiftClientEvent::Disconnected(
+            DisconnectReason::IdleTimeout
+        )]
+    ));
+    assert!(sent_messages(&client_buffer).is_empty());
+}
+
+/// Connects two clients and has `sender` offer a file that `receiver` has not answered yet
+#[cfg(test)]
+fn offer_file(features_a: &[&str], features_b: &[&str]) -> (Peer, Peer) {
+    let (mut sender, sender_buffer) = client_with_features(features_a);
+    let (mut receiver, receiver_buffer) = client_with_features(features_b);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .open_file(api::OpenFile {
+            file_info: Some(api::FileInfo::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    ((sender, sender_buffer), (receiver, receiver_buffer))
+}
+
+#[test]
+fn test_reject_file() {
+    let features = ["reject-file", "xattrs"];
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        offer_file(&features, &features);
+    sender
+        .send_file_attributes(api::FileAttributes::default())
+        .unwrap();
+    let messages = sent_messages(&sender_buffer);
+    let file_id = match &messages[0] {
+        Content::OpenFile(file) => file.file_id,
+        other => panic!("unexpected message: {:?}", other),
+    };
+    receiver.feed_message(messages[0].clone()).unwrap();
+    receiver
+        .reject_file(api::RejectFile {
+            transfer_id: 0,
+            file_id,
+        })
+        .unwrap();
+    // Attributes sent before the rejection arrived are dropped
+    receiver.feed_message(messages[1].clone()).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    assert!(matches!(
+        &sender.take_events()[..],
+        [.., ShiftClientEvent::FileRejected(file)] if file.file_id == file_id
+    ));
+
+    // The transfer goes on with the next file
+    sender
+        .open_file(api::OpenFile {
+            file_info: Some(api::FileInfo::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    assert!(matches!(
+        &receiver.take_events()[..],
+        [.., ShiftClientEvent::InboundFileOpening(_, _)]
+    ));
+
+    let ((_, sender_buffer), (mut receiver, _)) = offer_file(&features, &[]);
+    pump(&sender_buffer, &mut receiver);
+    assert!(receiver.reject_file(api::RejectFile::default()).is_err());
+}
+
+#[test]
+fn test_pause_transfer() {
+    let ((mut sender, _), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&["pause"], &["pause"]);
+    receiver.pause_transfer(0).unwrap();
+    assert!(receiver.is_paused(0));
+    pump(&receiver_buffer, &mut sender);
+    assert!(matches!(
+        &sender.take_events()[..],
+        [ShiftClientEvent::TransferPaused(0)]
+    ));
+    assert!(!sender.can_send_chunk(0, 1).unwrap());
+
+    // Pausing twice says nothing new
+    receiver.pause_transfer(0).unwrap();
+    assert!(sent_messages(&receiver_buffer).is_empty());
+
+    receiver.resume_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    assert!(matches!(
+        &sender.take_events()[..],
+        [ShiftClientEvent::TransferResumed(0)]
+    ));
+    assert!(sender.can_send_chunk(0, 1).unwrap());
+
+    sender.pause_transfer(0).unwrap();
+    sender.close_transfer(0).unwrap();
+    assert!(!sender.is_paused(0));
+
+    let ((mut sender, _), _) = open_file_transfer(&["pause"], &[]);
+    assert!(sender.pause_transfer(0).is_err());
+}
+
+#[test]
+fn test_abandon_ends_transfers() {
+    let ((mut sender, sender_buffer), (mut receiver, receiver_buffer)) =
+        open_file_transfer(&["pause"], &["pause"]);
+    receiver.pause_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    sender.take_events();
+    assert!(sender.is_paused(0));
+
+    sender.abandon().unwrap();
+    assert!(matches!(
+        &sender.take_events()[..],
+        [ShiftClientEvent::Disconnected(DisconnectReason::Local)]
+    ));
+    assert!(sent_messages(&sender_buffer).is_empty());
+    // Senders waiting for their turn learn that it never comes
+    assert!(!sender.is_paused(0));
+    assert!(sender.can_send_chunk(0, 1).is_err());
+    assert!(sender.send_chunk(api::Chunk::default()).is_err());
+    assert!(sender.is_idle());
+
+    sender.abandon().unwrap();
+    assert!(sender.take_events().is_empty());
+}
+
+#[test]
+fn test_transfer_manifest() {
+    let features = ["manifest", "errors"];
+    let (mut sender, sender_buffer) = client_with_features(&features);
+    let (mut receiver, receiver_buffer) = client_with_features(&features);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    let manifest = api::TransferManifest {
+        file_count: 1,
+        total_bytes: 4,
+        entries: vec![api::FileInfo {
+            name: "test".to_string(),
+            size: 4,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    sender.send_transfer_manifest(manifest.clone()).unwrap();
+    sender
+        .open_file(api::OpenFile {
+            file_info: Some(api::FileInfo::default()),
+            ..Default::default()
+        })
+        .unwrap();
+    let messages = sent_messages(&sender_buffer);
+    receiver.take_events();
+    receiver.feed_message(messages[0].clone()).unwrap();
+    assert!(matches!(
+        &receiver.take_events()[..],
+        [ShiftClientEvent::TransferManifest(received)] if received == &manifest
+    ));
+
+    // Refusing the transfer drops the file already on its way
+    receiver
+        .send_error(api::Error {
+            code: api::error::Code::NoSpace as i32,
+            ..Default::default()
+        })
+        .unwrap();
+    receiver.feed_message(messages[1].clone()).unwrap();
+    assert!(receiver.transfer_state(0).is_none());
+
+    let ((mut sender, _), _) = open_file_transfer(&["manifest"], &[]);
+    sender.close_file(api::CloseFile::default()).unwrap();
+    assert!(sender.send_transfer_manifest(manifest).is_err());
+}
+
+#[test]
+fn test_stream_of_unknown_size() {
+    let ((mut sender, sender_buffer), (mut receiver, _)) =
+        open_file_transfer(&["streams"], &["streams"]);
+    let last = api::Chunk {
+        offset: 4,
+        end_of_stream: true,
+        ..Default::default()
+    };
+    sender.send_chunk(last.clone()).unwrap();
+    pump(&sender_buffer, &mut receiver);
+    match &receiver.take_events()[..] {
+        [ShiftClientEvent::Chunk(chunk)] => assert_eq!(chunk, &last),
+        other => panic!("unexpected events: {:?}", other),
+    }
+
+    let ((mut sender, _), (mut receiver, _)) = open_file_transfer(&["streams"], &[]);
+    assert!(sender.send_chunk(last.clone()).is_err());
+    assert!(receiver.feed_message(Content::Chunk(last)).is_err());
+
+    let (mut sender, sender_buffer) = client_with_features(&["streams"]);
+    let (mut receiver, receiver_buffer) = client_with_features(&[]);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    let stream = api::FileInfo {
+        size_unknown: true,
+        ..Default::default()
+    };
+    assert!(sender
+        .request_outbound_transfer(api::SendRequest {
+            file_info: Some(stream.clone()),
+            ..Default::default()
+        })
+        .is_err());
+    sender
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    pump(&sender_buffer, &mut receiver);
+    receiver.accept_transfer(0).unwrap();
+    pump(&receiver_buffer, &mut sender);
+    assert!(sender
+        .open_file(api::OpenFile {
+            file_info: Some(stream),
+            ..Default::default()
+        })
+        .is_err());
+}
+
+#[test]
+fn test_destination_hint() {
+    let request = api::SendRequest {
+        destination: "projects/foo".to_string(),
+        tags: vec!["nightly".to_string()],
+        ..Default::default()
+    };
+    let features = ["destination-hints"];
+    let (mut sender, sender_buffer) = client_with_features(&features);
+    let (mut receiver, receiver_buffer) = client_with_features(&features);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    receiver.take_events();
+    sender.request_outbound_transfer(request.clone()).unwrap();
+    pump(&sender_buffer, &mut receiver);
+    assert!(matches!(
+        &receiver.take_events()[..],
+        [ShiftClientEvent::InboundTransferOffered(offered)] if offered == &request
+    ));
+
+    let (mut sender, sender_buffer) = client_with_features(&features);
+    let (mut receiver, receiver_buffer) = client_with_features(&[]);
+    sender.start().unwrap();
+    pump(&sender_buffer, &mut receiver);
+    pump(&receiver_buffer, &mut sender);
+    assert!(sender.request_outbound_transfer(request).is_err());
+}
+
+#[test]
+fn test_negotiated_encoding() {
+    let (mut client, buffer) = client_with_features(&["encoding-yenc", "encoding-z85"]);
+    let (mut server, server_buffer) = client_with_features(&["encoding-z85"]);
+    client.start().unwrap();
+    pump(&buffer, &mut server);
+    let data = server_buffer.0.lock().unwrap().clone();
+    pump(&server_buffer, &mut client);
+    // The reply to Init is still base64, the peer might not know any other encoding
+    assert!(!data.contains(&b'~'));
+
+    client.disconnect().unwrap();
+    let data = buffer.0.lock().unwrap().clone();
+    assert!(data.starts_with(b"\x1b]1337;SHIFT;~"));
+    pump(&buffer, &mut server);
+    assert!(matches!(
+        &server.take_events()[..],
+        [.., ShiftClientEvent::Disconnected(_)]
+    ));
+
+    // Only offered where the link passes every byte
+    let writer = |eight_bit_clean| {
+        MessageWriter::new(TransportWriter::new(
+            TransportConfig {
+                eight_bit_clean,
+                ..Default::default()
+            },
+            Box::new(io::sink()),
+        ))
+    };
+    assert!(!writer(false).supports_feature(FEATURE_ENCODING_YENC));
+    assert!(writer(true).supports_feature(FEATURE_ENCODING_YENC));
+    assert!(writer(false).supports_feature(FEATURE_ENCODING_Z85));
+}
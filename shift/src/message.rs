@@ -1,20 +1,46 @@
 use bytes::Bytes;
 use cancellation::*;
+use prost::encoding::decode_key;
 use prost::Message;
 use std::io::{self, Read};
+use std::sync::Arc;
 
 use super::api::{self, message::Content};
+use super::secure::SecureSession;
 use super::transport::{
-    TransportConfig, TransportFeeder, TransportOutput, TransportReader, TransportWriter,
+    Encoding, TransportConfig, TransportCounters, TransportFeeder, TransportOutput,
+    TransportReader, TransportWriter,
 };
 
-fn decode(output: TransportOutput) -> Option<MessageOutput> {
+/// Once a secure session is established, only authenticated packets get through. Sessions are
+/// never rekeyed, so a plaintext key exchange then is as foreign as anything else printed.
+fn unseal(data: Bytes, session: &Option<SecureSession>) -> Option<Bytes> {
+    match session {
+        Some(session) if session.is_established() => {
+            if let Some(data) = session.decrypt(&data) {
+                return Some(Bytes::from(data));
+            }
+            session.reject_packet();
+            None
+        }
+        _ => Some(data),
+    }
+}
+
+fn decode(output: TransportOutput, session: &Option<SecureSession>) -> Option<MessageOutput> {
     match output {
         TransportOutput::Passthrough(data) => return Some(MessageOutput::Passthrough(data)),
         TransportOutput::Packet(data) => {
-            if let Ok(msg) = api::Message::decode(data) {
-                if let Some(content) = msg.content {
-                    return Some(MessageOutput::Message(content));
+            let data = unseal(data, session)?;
+            if let Ok(msg) = api::Message::decode(data.clone()) {
+                match msg.content {
+                    Some(content) => return Some(MessageOutput::Message(content)),
+                    None => {
+                        // A well-formed message with a content variant we don't know about
+                        if let Ok((message_type, _)) = decode_key(&mut data.clone()) {
+                            return Some(MessageOutput::Unsupported(message_type));
+                        }
+                    }
                 }
             }
         }
@@ -22,32 +48,43 @@ fn decode(output: TransportOutput) -> Option<MessageOutput> {
     None
 }
 
-fn decode_from(output: Vec<TransportOutput>) -> Vec<MessageOutput> {
-    output.into_iter().filter_map(decode).collect()
+fn decode_from(
+    output: Vec<TransportOutput>,
+    session: &Option<SecureSession>,
+) -> Vec<MessageOutput> {
+    output
+        .into_iter()
+        .filter_map(|x| decode(x, session))
+        .collect()
 }
 
 #[derive(Debug)]
 pub enum MessageOutput {
     Passthrough(Bytes),
     Message(Content),
+    Unsupported(u32),
 }
 
-pub struct MessageReader<'a> {
-    reader: TransportReader<'a>,
+pub struct MessageReader {
+    reader: TransportReader,
+    session: Option<SecureSession>,
 }
 
 pub struct MessageFeeder<'a> {
     feeder: TransportFeeder<'a>,
+    session: Option<SecureSession>,
 }
 
 impl<'a> MessageFeeder<'a> {
     pub fn new(
-        reader: &'a mut TransportReader<'a>,
+        reader: &'a mut TransportReader,
         stream: &'a mut dyn Read,
         ct: &'a CancellationToken,
+        session: Option<SecureSession>,
     ) -> Self {
         Self {
             feeder: reader.feed_from(stream, ct),
+            session,
         }
     }
 }
@@ -56,43 +93,95 @@ impl<'a> Iterator for MessageFeeder<'a> {
     type Item = MessageOutput;
 
     fn next(&mut self) -> Option<MessageOutput> {
-        self.feeder.next().and_then(decode)
+        // Undecodable packets are skipped rather than ending the iteration
+        loop {
+            let output = self.feeder.next()?;
+            if let Some(output) = decode(output, &self.session) {
+                return Some(output);
+            }
+        }
     }
 }
 
-impl<'a> MessageReader<'a> {
-    pub fn new(config: TransportConfig<'a>) -> Self {
+impl MessageReader {
+    pub fn new(config: TransportConfig) -> Self {
         Self {
             reader: TransportReader::new(config),
+            session: None,
         }
     }
 
+    pub fn with_session(config: TransportConfig, session: SecureSession) -> Self {
+        Self {
+            reader: TransportReader::new(config),
+            session: Some(session),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<TransportCounters> {
+        self.reader.counters()
+    }
+
+    pub fn set_counters(&mut self, counters: Arc<TransportCounters>) {
+        self.reader.set_counters(counters);
+    }
+
     pub fn feed(&mut self, data: &[u8]) -> Vec<MessageOutput> {
         let output = self.reader.feed(data);
-        decode_from(output)
+        decode_from(output, &self.session)
     }
 
-    pub fn feed_from(
+    pub fn feed_from<'a>(
         &'a mut self,
         stream: &'a mut dyn Read,
         ct: &'a CancellationToken,
     ) -> MessageFeeder<'a> {
-        MessageFeeder::new(&mut self.reader, stream, ct)
+        MessageFeeder::new(&mut self.reader, stream, ct, self.session.clone())
     }
 }
 
 pub struct MessageWriter<'a> {
     writer: TransportWriter<'a>,
+    session: Option<SecureSession>,
 }
 
 impl<'a> MessageWriter<'a> {
     pub fn new(writer: TransportWriter<'a>) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            session: None,
+        }
+    }
+
+    pub fn with_session(writer: TransportWriter<'a>, session: SecureSession) -> Self {
+        Self {
+            writer,
+            session: Some(session),
+        }
+    }
+
+    pub fn session(&self) -> Option<&SecureSession> {
+        self.session.as_ref()
+    }
+
+    pub fn supports_feature(&self, feature: &str) -> bool {
+        self.writer.supports_feature(feature)
+    }
+
+    pub fn set_encoding(&mut self, encoding: &'static dyn Encoding) {
+        self.writer.set_encoding(encoding);
     }
 
     pub fn write(&mut self, msg: Content) -> io::Result<()> {
-        let packet = api::Message { content: Some(msg) };
-        self.writer.write(&packet.encode_to_vec())?;
+        // Key exchanges, and the refusal of a peer that never starts one, have no keys to use
+        let plaintext = matches!(msg, Content::KeyExchange(_));
+        let packet = api::Message { content: Some(msg) }.encode_to_vec();
+        match &self.session {
+            Some(session) if !plaintext && session.is_established() => {
+                self.writer.write(&session.encrypt(&packet)?)?
+            }
+            _ => self.writer.write(&packet)?,
+        }
         Ok(())
     }
 }
@@ -23,7 +23,7 @@ pub fn enable_raw_mode(fd: RawFd) -> Result<Termios> {
     termios.c_cflag &= !(termios::CSIZE | termios::PARENB);
     termios.c_cflag |= termios::CS8;
     termios.c_cc[termios::VMIN] = 1;
-    termios::tcsetattr(0, termios::TCSANOW, &termios)?;
+    termios::tcsetattr(fd, termios::TCSANOW, &termios)?;
     Ok(old_termios)
 }
 
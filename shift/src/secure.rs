@@ -0,0 +1,267 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::OsRng;
+use sha2::Sha256;
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use super::api;
+use super::hash::{from_hex, to_hex};
+
+const KEY_DERIVATION_INFO: &[u8] = b"shift-session-v1";
+
+/// How the key exchange is authenticated
+#[derive(Clone)]
+pub enum SecureConfig {
+    /// Both peers know the same secret
+    PreSharedKey(Vec<u8>),
+    /// Each peer has a static X25519 identity and knows the other side's public key in advance
+    PinnedKey {
+        identity: StaticSecret,
+        peer: PublicKey,
+    },
+}
+
+impl SecureConfig {
+    /// Builds a config from command line style options, `None` meaning an unauthenticated session
+    pub fn from_options(
+        secret_file: Option<&Path>,
+        identity: Option<&Path>,
+        pin: Option<&str>,
+    ) -> Result<Option<Self>> {
+        match (secret_file, identity, pin) {
+            (None, None, None) => Ok(None),
+            (Some(secret_file), None, None) => Ok(Some(Self::load_pre_shared_key(secret_file)?)),
+            (None, Some(identity), Some(pin)) => Ok(Some(Self::load_pinned_key(identity, pin)?)),
+            (None, _, _) => bail!("An identity and a pinned peer key must be given together"),
+            (Some(_), _, _) => bail!("A pre-shared secret cannot be combined with pinned keys"),
+        }
+    }
+
+    pub fn load_pre_shared_key(path: &Path) -> Result<Self> {
+        let secret = std::fs::read(path)
+            .with_context(|| format!("Could not read secret from {}", path.display()))?;
+        if secret.is_empty() {
+            bail!("Pre-shared secret in {} is empty", path.display());
+        }
+        Ok(SecureConfig::PreSharedKey(secret))
+    }
+
+    /// Loads a hex-encoded identity written by [`generate_identity`] and pins the peer's hex-encoded public key
+    pub fn load_pinned_key(identity_path: &Path, peer: &str) -> Result<Self> {
+        let identity = std::fs::read_to_string(identity_path)
+            .with_context(|| format!("Could not read identity from {}", identity_path.display()))?;
+        Ok(SecureConfig::PinnedKey {
+            identity: StaticSecret::from(parse_key(identity.trim())?),
+            peer: PublicKey::from(parse_key(peer.trim())?),
+        })
+    }
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    let data = from_hex(hex).ok_or(anyhow!("Key is not valid hex"))?;
+    <[u8; 32]>::try_from(&data[..]).map_err(|_| anyhow!("Key must be 32 bytes long"))
+}
+
+/// Returns a new hex-encoded identity and its hex-encoded public key
+pub fn generate_identity() -> (String, String) {
+    let identity = StaticSecret::random_from_rng(OsRng);
+    (
+        to_hex(identity.as_bytes()),
+        to_hex(PublicKey::from(&identity).as_bytes()),
+    )
+}
+
+struct SessionKeys {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    send_counter: u64,
+    receive_counter: u64,
+}
+
+struct SessionState {
+    config: SecureConfig,
+    ephemeral: Option<EphemeralSecret>,
+    ephemeral_public: PublicKey,
+    keys: Option<SessionKeys>,
+    rejected_packets: u64,
+}
+
+/// Encryption state shared between a [`MessageWriter`](crate::MessageWriter) and a
+/// [`MessageReader`](crate::MessageReader). Every packet other than `KeyExchange` is sealed with
+/// ChaCha20-Poly1305 once the handshake completes, using a separate key and an implicit counter
+/// nonce per direction.
+#[derive(Clone)]
+pub struct SecureSession {
+    state: Arc<Mutex<SessionState>>,
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+impl SecureSession {
+    pub fn new(config: SecureConfig) -> Self {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        Self {
+            state: Arc::new(Mutex::new(SessionState {
+                config,
+                ephemeral_public: PublicKey::from(&ephemeral),
+                ephemeral: Some(ephemeral),
+                keys: None,
+                rejected_packets: 0,
+            })),
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state.lock().unwrap().keys.is_some()
+    }
+
+    /// Number of packets dropped because they failed authentication
+    pub fn rejected_packets(&self) -> u64 {
+        self.state.lock().unwrap().rejected_packets
+    }
+
+    pub(crate) fn key_exchange(&self) -> api::KeyExchange {
+        let state = self.state.lock().unwrap();
+        api::KeyExchange {
+            public_key: state.ephemeral_public.as_bytes().to_vec(),
+            identity: match &state.config {
+                SecureConfig::PinnedKey { identity, .. } => {
+                    PublicKey::from(identity).as_bytes().to_vec()
+                }
+                SecureConfig::PreSharedKey(_) => vec![],
+            },
+            confirmation: vec![],
+        }
+    }
+
+    /// Responder side: derives the session keys and returns the reply, which proves we know the secret
+    pub(crate) fn respond(&self, peer: &api::KeyExchange) -> Result<api::KeyExchange> {
+        let mut reply = self.key_exchange();
+        reply.confirmation = self.establish(peer)?.finalize().into_bytes().to_vec();
+        Ok(reply)
+    }
+
+    /// Initiator side: derives the session keys and checks that the responder knows the secret
+    pub(crate) fn complete(&self, peer: &api::KeyExchange) -> Result<()> {
+        if self
+            .establish(peer)?
+            .verify_slice(&peer.confirmation)
+            .is_err()
+        {
+            self.state.lock().unwrap().keys = None;
+            bail!("Secure session authentication failed");
+        }
+        Ok(())
+    }
+
+    /// Derives and installs the session keys, returning the MAC used for key confirmation
+    fn establish(&self, peer: &api::KeyExchange) -> Result<Hmac<Sha256>> {
+        let mut state = self.state.lock().unwrap();
+        let peer_public = PublicKey::from(
+            <[u8; 32]>::try_from(&peer.public_key[..])
+                .map_err(|_| anyhow!("Invalid key exchange public key"))?,
+        );
+        if peer_public == state.ephemeral_public {
+            bail!("Peer reflected our own key exchange");
+        }
+
+        let authentication = match &state.config {
+            SecureConfig::PreSharedKey(secret) => secret.clone(),
+            SecureConfig::PinnedKey {
+                identity,
+                peer: pinned,
+            } => {
+                if peer.identity != pinned.as_bytes() {
+                    bail!(
+                        "Peer identity {} does not match the pinned key",
+                        to_hex(&peer.identity)
+                    );
+                }
+                identity.diffie_hellman(pinned).as_bytes().to_vec()
+            }
+        };
+
+        let ephemeral = state
+            .ephemeral
+            .take()
+            .ok_or(anyhow!("Key exchange already completed"))?;
+        let shared = ephemeral.diffie_hellman(&peer_public);
+        if !shared.was_contributory() {
+            bail!("Peer sent a low-order public key");
+        }
+
+        let local_is_low = state.ephemeral_public.as_bytes() < peer_public.as_bytes();
+        let (low, high) = if local_is_low {
+            (state.ephemeral_public, peer_public)
+        } else {
+            (peer_public, state.ephemeral_public)
+        };
+        let mut info = KEY_DERIVATION_INFO.to_vec();
+        info.extend_from_slice(low.as_bytes());
+        info.extend_from_slice(high.as_bytes());
+
+        let mut material = [0; 96];
+        Hkdf::<Sha256>::new(Some(&authentication), shared.as_bytes())
+            .expand(&info, &mut material)
+            .map_err(|_| anyhow!("Key derivation failed"))?;
+        let (low_to_high, rest) = material.split_at(32);
+        let (high_to_low, confirmation_key) = rest.split_at(32);
+        let (send, receive) = if local_is_low {
+            (low_to_high, high_to_low)
+        } else {
+            (high_to_low, low_to_high)
+        };
+
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(confirmation_key)
+            .map_err(|_| anyhow!("Key derivation failed"))?;
+        mac.update(&info);
+
+        state.keys = Some(SessionKeys {
+            send: ChaCha20Poly1305::new(Key::from_slice(send)),
+            receive: ChaCha20Poly1305::new(Key::from_slice(receive)),
+            send_counter: 0,
+            receive_counter: 0,
+        });
+        Ok(mac)
+    }
+
+    pub(crate) fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let keys = state
+            .keys
+            .as_mut()
+            .ok_or_else(|| io::Error::other("Secure session is not established yet"))?;
+        let sealed = keys
+            .send
+            .encrypt(&nonce(keys.send_counter), data)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        keys.send_counter += 1;
+        Ok(sealed)
+    }
+
+    pub(crate) fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let keys = state.keys.as_mut()?;
+        let opened = keys
+            .receive
+            .decrypt(&nonce(keys.receive_counter), data)
+            .ok()?;
+        keys.receive_counter += 1;
+        Some(opened)
+    }
+
+    pub(crate) fn reject_packet(&self) {
+        self.state.lock().unwrap().rejected_packets += 1;
+    }
+}
@@ -0,0 +1,214 @@
+#[cfg(test)]
+use super::api;
+#[cfg(test)]
+use super::secure::{SecureConfig, SecureSession};
+#[cfg(test)]
+use super::*;
+#[cfg(test)]
+use std::io::{self, Write};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use x25519_dalek::{PublicKey, StaticSecret};
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct Peer {
+    client: ShiftClient<'static>,
+    reader: MessageReader,
+    session: Option<SecureSession>,
+    buffer: SharedBuffer,
+}
+
+#[cfg(test)]
+fn peer(config: Option<SecureConfig>) -> Peer {
+    let buffer = SharedBuffer::default();
+    let transport = TransportWriter::new(TransportConfig::default(), Box::new(buffer.clone()));
+    let session = config.map(SecureSession::new);
+    let (writer, reader) = match &session {
+        Some(session) => (
+            MessageWriter::with_session(transport, session.clone()),
+            MessageReader::with_session(TransportConfig::default(), session.clone()),
+        ),
+        None => (
+            MessageWriter::new(transport),
+            MessageReader::new(TransportConfig::default()),
+        ),
+    };
+    Peer {
+        client: ShiftClient::new(writer),
+        reader,
+        session,
+        buffer,
+    }
+}
+
+/// Delivers everything `from` has written to `to`, feeding it one packet at a time
+#[cfg(test)]
+fn pump(from: &Peer, to: &mut Peer) -> anyhow::Result<()> {
+    let data = std::mem::take(&mut *from.buffer.0.lock().unwrap());
+    for output in TransportReader::new(TransportConfig::default()).feed(&data) {
+        if let TransportOutput::Packet(packet) = output {
+            let frame = SharedBuffer::default();
+            TransportWriter::new(TransportConfig::default(), Box::new(frame.clone()))
+                .write(&packet)?;
+            let bytes = frame.0.lock().unwrap().clone();
+            for msg in to.reader.feed(&bytes) {
+                if let MessageOutput::Message(msg) = msg {
+                    to.client.feed_message(msg)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn handshake(a: &mut Peer, b: &mut Peer) -> anyhow::Result<()> {
+    a.client.start()?;
+    pump(a, b)?;
+    pump(b, a)?;
+    pump(a, b)?;
+    pump(b, a)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn connected(peer: &mut Peer) -> bool {
+    peer.client
+        .take_events()
+        .iter()
+        .any(|x| matches!(x, ShiftClientEvent::Connected))
+}
+
+#[cfg(test)]
+fn pinned_pair() -> (SecureConfig, SecureConfig, StaticSecret) {
+    let a = StaticSecret::from([1; 32]);
+    let b = StaticSecret::from([2; 32]);
+    (
+        SecureConfig::PinnedKey {
+            identity: a.clone(),
+            peer: PublicKey::from(&b),
+        },
+        SecureConfig::PinnedKey {
+            identity: b,
+            peer: PublicKey::from(&a),
+        },
+        a,
+    )
+}
+
+#[test]
+fn test_pre_shared_key_session() {
+    let secret = b"correct horse battery staple".to_vec();
+    let mut a = peer(Some(SecureConfig::PreSharedKey(secret.clone())));
+    let mut b = peer(Some(SecureConfig::PreSharedKey(secret)));
+    handshake(&mut a, &mut b).unwrap();
+    assert!(connected(&mut a));
+    assert!(connected(&mut b));
+    assert!(a.session.as_ref().unwrap().is_established());
+
+    // Nothing after the handshake is readable without the session keys
+    a.client
+        .request_outbound_transfer(api::SendRequest::default())
+        .unwrap();
+    let data = a.buffer.0.lock().unwrap().clone();
+    assert!(MessageReader::new(TransportConfig::default())
+        .feed(&data)
+        .iter()
+        .all(|x| !matches!(x, MessageOutput::Message(_))));
+    pump(&a, &mut b).unwrap();
+    assert!(matches!(
+        b.client.take_events()[..],
+        [ShiftClientEvent::InboundTransferOffered(_)]
+    ));
+}
+
+#[test]
+fn test_wrong_pre_shared_key() {
+    let mut a = peer(Some(SecureConfig::PreSharedKey(b"one".to_vec())));
+    let mut b = peer(Some(SecureConfig::PreSharedKey(b"two".to_vec())));
+    assert!(handshake(&mut a, &mut b).is_err());
+    assert!(!a.session.as_ref().unwrap().is_established());
+    assert!(!connected(&mut a));
+    assert!(!connected(&mut b));
+}
+
+#[test]
+fn test_pinned_key_session() {
+    let (config_a, config_b, _) = pinned_pair();
+    let mut a = peer(Some(config_a));
+    let mut b = peer(Some(config_b));
+    handshake(&mut a, &mut b).unwrap();
+    assert!(connected(&mut a));
+    assert!(connected(&mut b));
+}
+
+#[test]
+fn test_pinned_key_mismatch() {
+    let (config_a, _, identity_a) = pinned_pair();
+    let mut a = peer(Some(config_a));
+    let mut b = peer(Some(SecureConfig::PinnedKey {
+        identity: identity_a,
+        peer: PublicKey::from(&StaticSecret::from([3; 32])),
+    }));
+    assert!(handshake(&mut a, &mut b).is_err());
+    assert!(!connected(&mut a));
+    assert!(!connected(&mut b));
+}
+
+#[test]
+fn test_unsecured_peer_rejected() {
+    let mut a = peer(None);
+    let mut b = peer(Some(SecureConfig::PreSharedKey(b"secret".to_vec())));
+    let error = handshake(&mut a, &mut b).unwrap_err();
+    assert_eq!(error.to_string(), "Peer did not establish a secure session");
+    assert!(!connected(&mut b));
+    assert!(b.client.is_disconnected());
+
+    // The refusal goes out in plaintext, since there are no keys yet
+    pump(&b, &mut a).unwrap();
+    assert!(a.client.is_disconnected());
+}
+
+#[test]
+fn test_injected_packets_dropped() {
+    let secret = b"secret".to_vec();
+    let mut a = peer(Some(SecureConfig::PreSharedKey(secret.clone())));
+    let mut b = peer(Some(SecureConfig::PreSharedKey(secret)));
+    handshake(&mut a, &mut b).unwrap();
+
+    let mut injected = peer(None);
+    injected.client.start().unwrap();
+    pump(&injected, &mut b).unwrap();
+    assert_eq!(b.session.as_ref().unwrap().rejected_packets(), 1);
+
+    // A key exchange printed into the terminal cannot end the session either
+    let mut intruder = peer(Some(SecureConfig::PreSharedKey(b"other".to_vec())));
+    intruder.client.start().unwrap();
+    pump(&intruder, &mut b).unwrap();
+    assert_eq!(b.session.as_ref().unwrap().rejected_packets(), 2);
+    a.client.take_events();
+    b.client.disconnect().unwrap();
+    pump(&b, &mut a).unwrap();
+    assert!(a
+        .client
+        .take_events()
+        .iter()
+        .any(|x| matches!(x, ShiftClientEvent::Disconnected(_))));
+}
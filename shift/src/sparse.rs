@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+#[cfg(target_os = "linux")]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    // SAFETY: lseek only repositions the descriptor, which `file` keeps open for the duration of the call
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if result >= 0 {
+        return Ok(Some(result as u64));
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        // No more data past `offset`
+        Some(libc::ENXIO) => Ok(None),
+        _ => Err(error),
+    }
+}
+
+/// Finds the next region of `file` at or after `offset` that contains data, as `(start, end)`.
+/// Everything between `offset` and `start` is a hole. Moves the file position.
+#[cfg(target_os = "linux")]
+pub fn next_data(file: &File, offset: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+    if offset >= size {
+        return Ok(None);
+    }
+    let start = match seek(file, offset, libc::SEEK_DATA) {
+        Ok(Some(start)) => start,
+        Ok(None) => return Ok(None),
+        // File systems without hole reporting
+        Err(error) if error.raw_os_error() == Some(libc::EINVAL) => {
+            return Ok(Some((offset, size)))
+        }
+        Err(error) => return Err(error),
+    };
+    let end = seek(file, start, libc::SEEK_HOLE)?.unwrap_or(size);
+    Ok(Some((start, std::cmp::min(end, size))))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn next_data(_file: &File, offset: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+    if offset >= size {
+        return Ok(None);
+    }
+    Ok(Some((offset, size)))
+}
@@ -1,16 +1,423 @@
-use bytes::{Bytes, BytesMut};
+use super::constants::{
+    DEFAULT_OSC, FEATURE_ENCODING_YENC, FEATURE_ENCODING_Z85, MAX_PAYLOAD_LENGTH,
+};
+use bytes::{Buf, Bytes, BytesMut};
 use cancellation::*;
+use std::convert::TryFrom;
 use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-#[derive(Clone)]
-pub struct TransportConfig<'a> {
-    pub prefix: &'a [u8],
-    pub suffix: &'a [u8],
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+/// String terminator, `ESC \`
+const ST: &[u8] = b"\x1b\\";
+/// GNU screen drops DCS strings longer than 256 bytes, wrapper included
+const SCREEN_CHUNK_LENGTH: usize = 252;
+
+/// How frames get through a terminal multiplexer, which otherwise swallows them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrapping {
+    None,
+    /// tmux passes on `ESC P tmux; ... ESC \`, with every ESC inside doubled
+    Tmux,
+    /// GNU screen passes on `ESC P ... ESC \`, so long frames are split into several of them
+    Screen,
+}
+
+impl Wrapping {
+    /// The wrapping for the multiplexer this process runs in, if any
+    pub fn from_environment() -> Self {
+        if std::env::var_os("TMUX").is_some() {
+            Wrapping::Tmux
+        } else if std::env::var_os("STY").is_some() {
+            Wrapping::Screen
+        } else {
+            Wrapping::None
+        }
+    }
+
+    fn header(&self) -> &'static [u8] {
+        match self {
+            Wrapping::None => b"",
+            Wrapping::Tmux => b"\x1bPtmux;",
+            Wrapping::Screen => b"\x1bP",
+        }
+    }
+}
+
+/// What ends the frames that are written. Frames ending in either are read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Terminator {
+    Bel,
+    /// `ESC \`, for terminals that do not take BEL as the end of an OSC sequence
+    St,
+}
+
+impl Terminator {
+    fn bytes(&self) -> &'static [u8] {
+        match self {
+            Terminator::Bel => &[BEL],
+            Terminator::St => ST,
+        }
+    }
+}
+
+impl FromStr for Terminator {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bel" => Ok(Terminator::Bel),
+            "st" => Ok(Terminator::St),
+            _ => anyhow::bail!("Unknown terminator {}, expected bel or st", value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransportConfig {
+    pub prefix: Vec<u8>,
+    pub suffix: Vec<u8>,
+    /// Prefixes of further framings that are read, besides `prefix`
+    pub accepted_prefixes: Vec<Vec<u8>>,
+    /// Frames are written wrapped like this, and wrapped frames are unwrapped when read
+    pub wrapping: Wrapping,
+    /// Whether the link passes bytes with the high bit set, which encodings like yEnc need
+    pub eight_bit_clean: bool,
+    /// Frames whose payload grows longer than this are given up on while reading
+    pub max_payload_length: usize,
+}
+
+impl TransportConfig {
+    /// Frames as `ESC ] <osc>;SHIFT; ... <terminator>`
+    pub fn osc(osc: u32, terminator: Terminator) -> Self {
+        TransportConfig {
+            prefix: osc_prefix(osc),
+            suffix: terminator.bytes().to_vec(),
+            accepted_prefixes: vec![],
+            wrapping: Wrapping::None,
+            eight_bit_clean: false,
+            max_payload_length: MAX_PAYLOAD_LENGTH,
+        }
+    }
+
+    /// Writes frames with `osc`, and reads frames with it or any of `accepted_osc`
+    pub fn from_options(osc: u32, terminator: Terminator, accepted_osc: &[u32]) -> Self {
+        TransportConfig {
+            accepted_prefixes: accepted_osc
+                .iter()
+                .filter(|x| **x != osc)
+                .map(|x| osc_prefix(*x))
+                .collect(),
+            ..Self::osc(osc, terminator)
+        }
+    }
+
+    fn prefixes(&self) -> impl Iterator<Item = &[u8]> {
+        std::iter::once(&self.prefix[..]).chain(self.accepted_prefixes.iter().map(|x| &x[..]))
+    }
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self::osc(DEFAULT_OSC, Terminator::Bel)
+    }
+}
+
+fn osc_prefix(osc: u32) -> Vec<u8> {
+    format!("\x1b]{};SHIFT;", osc).into_bytes()
+}
+
+/// Turns packets into frame payloads and back. Frames in any encoding but base64 start with
+/// the encoding's tag, so that readers can tell them apart without knowing what was negotiated.
+pub trait Encoding: Sync {
+    /// The feature that negotiates this encoding, if it has to be negotiated
+    fn feature(&self) -> Option<&'static str>;
+    fn tag(&self) -> Option<u8>;
+    /// Whether payloads contain bytes with the high bit set
+    fn needs_eight_bit_clean(&self) -> bool {
+        false
+    }
+    /// Whether the byte can appear in a payload, so that readers can give up on frames early
+    fn is_valid(&self, byte: u8) -> bool;
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+    fn decode(&self, payload: &[u8]) -> Option<Vec<u8>>;
+}
+
+pub struct Base64;
+
+impl Encoding for Base64 {
+    fn feature(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn tag(&self) -> Option<u8> {
+        None
+    }
+
+    fn is_valid(&self, byte: u8) -> bool {
+        byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'/' | b'=')
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        base64::encode(data).into_bytes()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        base64::decode(payload).ok()
+    }
+}
+
+const Z85_DIGITS: &[u8; 85] =
+    b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ.-:+=^!/*?&<>()[]{}@%$#";
+const Z85_VALUES: [u8; 256] = z85_values();
+
+const fn z85_values() -> [u8; 256] {
+    let mut values = [u8::MAX; 256];
+    let mut i = 0;
+    while i < Z85_DIGITS.len() {
+        values[Z85_DIGITS[i] as usize] = i as u8;
+        i += 1;
+    }
+    values
+}
+
+/// ZeroMQ's base85 alphabet, printable ASCII without quotes or backslashes. A trailing group of
+/// n < 4 bytes is written as n + 1 digits, like Ascii85 does.
+pub struct Z85;
+
+impl Encoding for Z85 {
+    fn feature(&self) -> Option<&'static str> {
+        Some(FEATURE_ENCODING_Z85)
+    }
+
+    fn tag(&self) -> Option<u8> {
+        Some(b'~')
+    }
+
+    fn is_valid(&self, byte: u8) -> bool {
+        Z85_VALUES[byte as usize] != u8::MAX
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(data.len() * 5 / 4 + 4);
+        for group in data.chunks(4) {
+            let mut bytes = [0; 4];
+            bytes[..group.len()].copy_from_slice(group);
+            let mut value = u32::from_be_bytes(bytes);
+            let mut digits = [0; 5];
+            for digit in digits.iter_mut().rev() {
+                *digit = Z85_DIGITS[(value % 85) as usize];
+                value /= 85;
+            }
+            result.extend_from_slice(&digits[..group.len() + 1]);
+        }
+        result
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut result = Vec::with_capacity(payload.len() * 4 / 5 + 3);
+        for group in payload.chunks(5) {
+            if group.len() == 1 {
+                return None;
+            }
+            // Padding with the largest digit rounds the missing bytes up, so the rest are exact
+            let mut value = 0u64;
+            for i in 0..5 {
+                let digit = match group.get(i) {
+                    Some(x) => Z85_VALUES[*x as usize],
+                    None => 84,
+                };
+                if digit == u8::MAX {
+                    return None;
+                }
+                value = value * 85 + digit as u64;
+            }
+            let value = u32::try_from(value).ok()?;
+            result.extend_from_slice(&value.to_be_bytes()[..group.len() - 1]);
+        }
+        Some(result)
+    }
 }
 
-pub struct TransportReader<'a> {
-    config: TransportConfig<'a>,
+const YENC_ESCAPE: u8 = b'=';
+
+/// Bytes that terminals or line disciplines could act on, such as signal keys while `ISIG` is
+/// still set, or that end a frame
+fn is_yenc_critical(byte: u8) -> bool {
+    matches!(
+        byte,
+        0x00 | 0x03
+            | BEL
+            | b'\n'
+            | b'\r'
+            | 0x11
+            | 0x13
+            | 0x18
+            | 0x1a
+            | ESC
+            | 0x1c
+            | YENC_ESCAPE
+            | 0x7f
+            | 0x90
+            | 0x9b..=0x9d
+    )
+}
+
+/// Shifts every byte by 42 like yEnc, and escapes only the few that cannot be sent as they are
+pub struct YEnc;
+
+impl Encoding for YEnc {
+    fn feature(&self) -> Option<&'static str> {
+        Some(FEATURE_ENCODING_YENC)
+    }
+
+    fn tag(&self) -> Option<u8> {
+        Some(b'_')
+    }
+
+    fn needs_eight_bit_clean(&self) -> bool {
+        true
+    }
+
+    fn is_valid(&self, byte: u8) -> bool {
+        byte == YENC_ESCAPE || !is_yenc_critical(byte)
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(data.len() + data.len() / 32 + 1);
+        for byte in data {
+            let byte = byte.wrapping_add(42);
+            if is_yenc_critical(byte) {
+                result.push(YENC_ESCAPE);
+                result.push(byte.wrapping_add(64));
+            } else {
+                result.push(byte);
+            }
+        }
+        result
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut result = Vec::with_capacity(payload.len());
+        let mut bytes = payload.iter();
+        while let Some(byte) = bytes.next() {
+            let byte = match *byte {
+                YENC_ESCAPE => bytes.next()?.wrapping_sub(64),
+                x if is_yenc_critical(x) => return None,
+                x => x,
+            };
+            result.push(byte.wrapping_sub(42));
+        }
+        Some(result)
+    }
+}
+
+/// Every encoding frames can be read in
+pub const ENCODINGS: &[&dyn Encoding] = &[&Base64, &Z85, &YEnc];
+
+/// Picks the first encoding from a list of negotiated features, base64 if there is none
+pub fn negotiated_encoding(features: &[String]) -> &'static dyn Encoding {
+    features
+        .iter()
+        .find_map(|feature| {
+            ENCODINGS
+                .iter()
+                .find(|x| x.feature() == Some(feature.as_str()))
+                .copied()
+        })
+        .unwrap_or(&Base64)
+}
+
+/// The encoding of a payload that starts with `first`, and where the encoded data starts
+fn payload_encoding(first: u8) -> (&'static dyn Encoding, usize) {
+    match ENCODINGS.iter().find(|x| x.tag() == Some(first)) {
+        Some(encoding) => (*encoding, 1),
+        None => (&Base64, 0),
+    }
+}
+
+fn decode_payload(payload: &[u8]) -> Option<Vec<u8>> {
+    match payload.first() {
+        Some(first) => {
+            let (encoding, start) = payload_encoding(*first);
+            encoding.decode(&payload[start..])
+        }
+        None => Some(vec![]),
+    }
+}
+
+/// What reading a candidate frame's payload found
+enum Scan {
+    /// The payload ends at the index, followed by a terminator of the given length
+    Complete(usize, usize),
+    /// No terminator yet, and everything up to the index is valid
+    Incomplete(usize),
+    /// The byte at the index cannot be part of the payload, or the payload gets too long there
+    Abandon(usize),
+}
+
+/// Reads `payload` on from `from`, up to where it is known to be valid already
+fn scan_payload(payload: &[u8], from: usize, max_length: usize) -> Scan {
+    let (encoding, start) = match payload.first() {
+        Some(first) => payload_encoding(*first),
+        None => return Scan::Incomplete(0),
+    };
+    for (index, byte) in payload.iter().enumerate().skip(from.max(start)) {
+        match *byte {
+            BEL => return Scan::Complete(index, 1),
+            ESC => {
+                return match payload.get(index + 1) {
+                    Some(b'\\') => Scan::Complete(index, ST.len()),
+                    Some(_) => Scan::Abandon(index),
+                    None => Scan::Incomplete(index),
+                }
+            }
+            x if !encoding.is_valid(x) || index >= max_length => return Scan::Abandon(index),
+            _ => {}
+        }
+    }
+    Scan::Incomplete(payload.len())
+}
+
+/// What a reader has made of its input so far. Shared, so that it can be looked at while the
+/// reader is busy on another thread.
+#[derive(Debug, Default)]
+pub struct TransportCounters {
+    packets: AtomicU64,
+    abandoned: AtomicU64,
+    undecodable: AtomicU64,
+}
+
+impl TransportCounters {
+    /// Frames decoded into packets
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    /// Candidate frames given back as passthrough, because they held bytes their encoding does
+    /// not use or grew too long
+    pub fn abandoned(&self) -> u64 {
+        self.abandoned.load(Ordering::Relaxed)
+    }
+
+    /// Complete frames that could not be decoded and were dropped
+    pub fn undecodable(&self) -> u64 {
+        self.undecodable.load(Ordering::Relaxed)
+    }
+}
+
+pub struct TransportReader {
+    config: TransportConfig,
     buffer: BytesMut,
+    /// Input that may still be part of a wrapper
+    wrapped: BytesMut,
+    in_wrapper: bool,
+    /// How much of the payload of the frame at the start of `buffer` is known to be valid
+    scanned: usize,
+    counters: Arc<TransportCounters>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -20,7 +427,7 @@ pub enum TransportOutput {
 }
 
 pub struct TransportFeeder<'a> {
-    reader: &'a mut TransportReader<'a>,
+    reader: &'a mut TransportReader,
     stream: &'a mut dyn Read,
     data_buffer: Vec<u8>,
     result_buffer: Vec<TransportOutput>,
@@ -29,7 +436,7 @@ pub struct TransportFeeder<'a> {
 
 impl<'a> TransportFeeder<'a> {
     pub fn new(
-        reader: &'a mut TransportReader<'a>,
+        reader: &'a mut TransportReader,
         stream: &'a mut dyn Read,
         ct: &'a CancellationToken,
     ) -> Self {
@@ -67,15 +474,28 @@ impl<'a> Iterator for TransportFeeder<'a> {
     }
 }
 
-impl<'a> TransportReader<'a> {
-    pub fn new(config: TransportConfig<'a>) -> Self {
+impl TransportReader {
+    pub fn new(config: TransportConfig) -> Self {
         Self {
             buffer: BytesMut::new(),
+            wrapped: BytesMut::new(),
+            in_wrapper: false,
+            scanned: 0,
+            counters: Arc::default(),
             config,
         }
     }
 
-    pub fn feed_from(
+    pub fn counters(&self) -> Arc<TransportCounters> {
+        self.counters.clone()
+    }
+
+    /// Counts into `counters` from now on, such as ones created before the reader
+    pub fn set_counters(&mut self, counters: Arc<TransportCounters>) {
+        self.counters = counters;
+    }
+
+    pub fn feed_from<'a>(
         &'a mut self,
         stream: &'a mut dyn Read,
         ct: &'a CancellationToken,
@@ -83,51 +503,146 @@ impl<'a> TransportReader<'a> {
         TransportFeeder::new(self, stream, ct)
     }
 
+    /// Moves `wrapped` to `buffer` without the wrappers, up to what could be the start of one
+    fn unwrap(&mut self) {
+        let header = self.config.wrapping.header();
+        loop {
+            if self.in_wrapper {
+                let index = match self.wrapped.iter().position(|x| *x == ESC) {
+                    Some(index) => index,
+                    None => {
+                        self.buffer.extend_from_slice(&self.wrapped.split());
+                        return;
+                    }
+                };
+                self.buffer.extend_from_slice(&self.wrapped.split_to(index));
+                let next = match self.wrapped.get(1) {
+                    Some(next) => *next,
+                    None => return,
+                };
+                match next {
+                    b'\\' => {
+                        self.in_wrapper = false;
+                        self.wrapped.advance(2);
+                    }
+                    ESC if self.config.wrapping == Wrapping::Tmux => {
+                        self.buffer.extend_from_slice(&[ESC]);
+                        self.wrapped.advance(2);
+                    }
+                    _ => {
+                        self.buffer.extend_from_slice(&[ESC]);
+                        self.wrapped.advance(1);
+                    }
+                }
+            } else {
+                match twoway::find_bytes(&self.wrapped, header) {
+                    Some(index) => {
+                        self.buffer.extend_from_slice(&self.wrapped.split_to(index));
+                        self.wrapped.advance(header.len());
+                        self.in_wrapper = true;
+                    }
+                    None => {
+                        let keep = (1..header.len())
+                            .rev()
+                            .find(|x| self.wrapped.ends_with(&header[..*x]))
+                            .unwrap_or(0);
+                        let length = self.wrapped.len() - keep;
+                        self.buffer
+                            .extend_from_slice(&self.wrapped.split_to(length));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Where the first frame starts in `buffer`, and the length of its prefix
+    fn find_prefix(&self) -> Option<(usize, usize)> {
+        if let Some(prefix) = self.config.prefixes().find(|x| self.buffer.starts_with(x)) {
+            return Some((0, prefix.len()));
+        }
+        self.config
+            .prefixes()
+            .filter_map(|prefix| {
+                twoway::find_bytes(&self.buffer, prefix).map(|index| (index, prefix.len()))
+            })
+            .min()
+    }
+
+    /// How much of the end of `buffer` could be the start of a prefix
+    fn partial_prefix_length(&self) -> usize {
+        let buffer = &self.buffer;
+        self.config
+            .prefixes()
+            .flat_map(|prefix| (1..prefix.len()).filter(move |x| buffer.ends_with(&prefix[..*x])))
+            .max()
+            .unwrap_or(0)
+    }
+
     pub fn feed(&mut self, data: &[u8]) -> Vec<TransportOutput> {
-        self.buffer.extend_from_slice(data);
+        match self.config.wrapping {
+            Wrapping::None => self.buffer.extend_from_slice(data),
+            _ => {
+                self.wrapped.extend_from_slice(data);
+                self.unwrap();
+            }
+        }
         let mut result = vec![];
 
-        'outer: loop {
+        loop {
             if self.buffer.is_empty() {
                 break;
             }
 
-            match twoway::find_bytes(&self.buffer, self.config.prefix) {
-                Some(0) => match twoway::find_bytes(&self.buffer, self.config.suffix) {
-                    Some(end_index) => {
+            match self.find_prefix() {
+                Some((0, prefix_length)) => match scan_payload(
+                    &self.buffer[prefix_length..],
+                    self.scanned,
+                    self.config.max_payload_length,
+                ) {
+                    Scan::Complete(end_index, terminator_length) => {
+                        self.scanned = 0;
                         let content = self
                             .buffer
-                            .split_to(end_index + self.config.suffix.len())
-                            .split_to(end_index)
-                            .split_off(self.config.prefix.len());
-                        if let Ok(content) = base64::decode(content) {
-                            result.push(TransportOutput::Packet(Bytes::from(content)));
+                            .split_to(prefix_length + end_index + terminator_length)
+                            .split_to(prefix_length + end_index)
+                            .split_off(prefix_length);
+                        match decode_payload(&content) {
+                            Some(content) => {
+                                self.counters.packets.fetch_add(1, Ordering::Relaxed);
+                                result.push(TransportOutput::Packet(Bytes::from(content)));
+                            }
+                            None => {
+                                self.counters.undecodable.fetch_add(1, Ordering::Relaxed);
+                            }
                         }
                         continue;
                     }
-                    None => {
+                    Scan::Incomplete(scanned) => {
+                        self.scanned = scanned;
                         break;
                     }
+                    // Not a frame after all, such as a binary that happens to contain a prefix
+                    Scan::Abandon(index) => {
+                        self.scanned = 0;
+                        self.counters.abandoned.fetch_add(1, Ordering::Relaxed);
+                        let passthrough = self.buffer.split_to(prefix_length + index);
+                        result.push(TransportOutput::Passthrough(passthrough.freeze()));
+                        continue;
+                    }
                 },
-                Some(start_index) => {
+                Some((start_index, _)) => {
                     let passthrough = self.buffer.split_to(start_index);
                     result.push(TransportOutput::Passthrough(passthrough.freeze()));
                     continue;
                 }
                 None => {
-                    for prefix_len in
-                        1..=std::cmp::min(self.buffer.len(), self.config.prefix.len() - 1)
-                    {
-                        if self.buffer[self.buffer.len() - prefix_len..]
-                            == self.config.prefix[..prefix_len]
-                        {
-                            // Could potentially be a prefix of the transport prefix
-                            break 'outer;
-                        }
+                    let keep = self.partial_prefix_length();
+                    let length = self.buffer.len() - keep;
+                    if length > 0 {
+                        let passthrough = self.buffer.split_to(length);
+                        result.push(TransportOutput::Passthrough(passthrough.freeze()));
                     }
-                    let mut buf = BytesMut::new();
-                    std::mem::swap(&mut self.buffer, &mut buf);
-                    result.push(TransportOutput::Passthrough(buf.freeze()));
                     break;
                 }
             }
@@ -138,19 +653,68 @@ impl<'a> TransportReader<'a> {
 }
 
 pub struct TransportWriter<'a> {
-    config: TransportConfig<'a>,
+    config: TransportConfig,
+    encoding: &'static dyn Encoding,
     stream: Box<dyn Write + Send + 'a>,
 }
 
 impl<'a> TransportWriter<'a> {
-    pub fn new(config: TransportConfig<'a>, stream: Box<dyn Write + Send>) -> Self {
-        Self { config, stream }
+    pub fn new(config: TransportConfig, stream: Box<dyn Write + Send>) -> Self {
+        Self {
+            config,
+            encoding: &Base64,
+            stream,
+        }
+    }
+
+    /// Whether the feature can be offered over this transport. Multiplexers do not pass on every
+    /// byte, so eight-bit encodings are only offered without them.
+    pub fn supports_feature(&self, feature: &str) -> bool {
+        match ENCODINGS.iter().find(|x| x.feature() == Some(feature)) {
+            Some(encoding) if encoding.needs_eight_bit_clean() => {
+                self.config.eight_bit_clean && self.config.wrapping == Wrapping::None
+            }
+            _ => true,
+        }
+    }
+
+    pub fn set_encoding(&mut self, encoding: &'static dyn Encoding) {
+        self.encoding = encoding;
     }
 
     pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
-        self.stream.write_all(self.config.prefix)?;
-        self.stream.write_all(base64::encode(data).as_bytes())?;
-        self.stream.write_all(self.config.suffix)?;
+        let mut encoded = self.encoding.tag().into_iter().collect::<Vec<_>>();
+        encoded.extend(self.encoding.encode(data));
+        // An ST inside would end the wrapper early
+        let suffix = match self.config.wrapping {
+            Wrapping::Screen => &[BEL],
+            _ => &self.config.suffix[..],
+        };
+        let frame = [&self.config.prefix[..], &encoded, suffix].concat();
+        let header = self.config.wrapping.header();
+        match self.config.wrapping {
+            Wrapping::None => self.stream.write_all(&frame)?,
+            Wrapping::Tmux => {
+                let mut wrapped = header.to_vec();
+                for byte in frame {
+                    if byte == ESC {
+                        wrapped.push(ESC);
+                    }
+                    wrapped.push(byte);
+                }
+                wrapped.extend_from_slice(ST);
+                self.stream.write_all(&wrapped)?;
+            }
+            Wrapping::Screen => {
+                let mut wrapped = vec![];
+                for piece in frame.chunks(SCREEN_CHUNK_LENGTH) {
+                    wrapped.extend_from_slice(header);
+                    wrapped.extend_from_slice(piece);
+                    wrapped.extend_from_slice(ST);
+                }
+                self.stream.write_all(&wrapped)?;
+            }
+        }
         self.stream.flush()?;
         Ok(())
     }
@@ -2,16 +2,18 @@
 use super::*;
 #[cfg(test)]
 use bytes::{Bytes, BytesMut};
+#[cfg(test)]
+use std::io::{self, Write};
 
 #[test]
 fn test_reader_single() {
     let mut buf = BytesMut::new();
     buf.extend("passthrough".as_bytes());
-    buf.extend(TRANSPORT.prefix);
+    buf.extend(TransportConfig::default().prefix);
     buf.extend("dGVzdA==".as_bytes());
-    buf.extend(TRANSPORT.suffix);
+    buf.extend(TransportConfig::default().suffix);
     buf.extend("passthrough".as_bytes());
-    let mut reader = TransportReader::new(TRANSPORT);
+    let mut reader = TransportReader::new(TransportConfig::default());
     let result = reader.feed(&buf);
     assert_eq!(result.len(), 3);
     assert_eq!(
@@ -27,12 +29,12 @@ fn test_reader_single() {
 
 #[test]
 fn test_reader_split_body() {
-    let mut reader = TransportReader::new(TRANSPORT);
-    assert_eq!(reader.feed(TRANSPORT.prefix).len(), 0);
+    let mut reader = TransportReader::new(TransportConfig::default());
+    assert_eq!(reader.feed(&TransportConfig::default().prefix).len(), 0);
     assert_eq!(reader.feed("dGVzdH".as_bytes()).len(), 0);
     assert_eq!(reader.feed("Rlc3Q=".as_bytes()).len(), 0);
 
-    let result = reader.feed(TRANSPORT.suffix);
+    let result = reader.feed(&TransportConfig::default().suffix);
     assert_eq!(result.len(), 1);
     assert_eq!(result[0], TransportOutput::Packet(Bytes::from("testtest")));
 }
@@ -40,32 +42,41 @@ fn test_reader_split_body() {
 #[test]
 fn test_reader_mutiple() {
     let mut buf = BytesMut::new();
-    buf.extend(TRANSPORT.prefix);
+    buf.extend(TransportConfig::default().prefix);
     buf.extend("dGVzdA==".as_bytes());
-    buf.extend(TRANSPORT.suffix);
-    buf.extend(TRANSPORT.prefix);
+    buf.extend(TransportConfig::default().suffix);
+    buf.extend(TransportConfig::default().prefix);
     buf.extend("dGV".as_bytes());
-    let mut reader = TransportReader::new(TRANSPORT);
+    let mut reader = TransportReader::new(TransportConfig::default());
     let result = reader.feed(&buf);
     assert_eq!(result[0], TransportOutput::Packet(Bytes::from("test")));
 
     let mut buf = BytesMut::new();
     buf.extend("zdA==".as_bytes());
-    buf.extend(TRANSPORT.suffix);
+    buf.extend(TransportConfig::default().suffix);
     let result = reader.feed(&buf);
     assert_eq!(result[0], TransportOutput::Packet(Bytes::from("test")));
 }
 
 #[test]
+#[allow(clippy::useless_conversion)]
 fn test_reader_split_prefix() {
-    let mut reader = TransportReader::new(TRANSPORT);
+    let mut reader = TransportReader::new(TransportConfig::default());
     let result = reader
         .feed("passthrough".as_bytes())
         .into_iter()
-        .chain(reader.feed(&TRANSPORT.prefix[..3]).into_iter())
-        .chain(reader.feed(&TRANSPORT.prefix[3..]).into_iter())
+        .chain(
+            reader
+                .feed(&TransportConfig::default().prefix[..3])
+                .into_iter(),
+        )
+        .chain(
+            reader
+                .feed(&TransportConfig::default().prefix[3..])
+                .into_iter(),
+        )
         .chain(reader.feed("dGVzdA==".as_bytes()).into_iter())
-        .chain(reader.feed(TRANSPORT.suffix).into_iter())
+        .chain(reader.feed(&TransportConfig::default().suffix).into_iter())
         .chain(reader.feed("passthrough".as_bytes()).into_iter())
         .collect::<Vec<_>>();
     assert_eq!(result.len(), 3);
@@ -79,3 +90,262 @@ fn test_reader_split_prefix() {
         TransportOutput::Passthrough(Bytes::from("passthrough"))
     );
 }
+
+#[cfg(test)]
+fn write_frame(config: TransportConfig, data: &[u8]) -> Vec<u8> {
+    write_frame_with(config, &Base64, data)
+}
+
+#[cfg(test)]
+fn write_frame_with(
+    config: TransportConfig,
+    encoding: &'static dyn Encoding,
+    data: &[u8],
+) -> Vec<u8> {
+    #[derive(Clone, Default)]
+    struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let buffer = Buffer::default();
+    let mut writer = TransportWriter::new(config, Box::new(buffer.clone()));
+    writer.set_encoding(encoding);
+    writer.write(data).unwrap();
+    let written = buffer.0.lock().unwrap().clone();
+    written
+}
+
+#[test]
+fn test_wrapping_round_trip() {
+    let data = vec![7; 1000];
+    for wrapping in [Wrapping::Tmux, Wrapping::Screen] {
+        let config = TransportConfig {
+            wrapping,
+            ..Default::default()
+        };
+        let mut input = b"before".to_vec();
+        input.extend(write_frame(config.clone(), &data));
+        input.extend(b"after");
+
+        // Split anywhere, even within a doubled escape
+        let mut reader = TransportReader::new(config);
+        let result = input
+            .iter()
+            .flat_map(|x| reader.feed(&[*x]))
+            .collect::<Vec<_>>();
+        let packets = result
+            .iter()
+            .filter(|x| matches!(x, TransportOutput::Packet(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            packets,
+            [&TransportOutput::Packet(Bytes::from(data.clone()))]
+        );
+        let passthrough = result
+            .iter()
+            .filter_map(|x| match x {
+                TransportOutput::Passthrough(x) => Some(x.to_vec()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .concat();
+        assert_eq!(passthrough, b"beforeafter");
+    }
+}
+
+#[test]
+fn test_wrapping_format() {
+    let tmux = write_frame(
+        TransportConfig {
+            wrapping: Wrapping::Tmux,
+            ..Default::default()
+        },
+        b"test",
+    );
+    assert_eq!(tmux, b"\x1bPtmux;\x1b\x1b]1337;SHIFT;dGVzdA==\x07\x1b\\");
+
+    let screen = write_frame(
+        TransportConfig {
+            wrapping: Wrapping::Screen,
+            ..Default::default()
+        },
+        &[0; 1000],
+    );
+    // Each piece is at most 256 bytes with its wrapper
+    let frame_length =
+        TransportConfig::default().prefix.len() + 1336 + TransportConfig::default().suffix.len();
+    assert_eq!(screen.len(), frame_length + 4 * frame_length.div_ceil(252));
+    assert!(screen.starts_with(b"\x1bP\x1b]1337;SHIFT;"));
+    assert_eq!(&screen[254..258], b"\x1b\\\x1bP");
+
+    // Plain frames still get through a reader expecting wrapped ones
+    let mut reader = TransportReader::new(TransportConfig {
+        wrapping: Wrapping::Tmux,
+        ..Default::default()
+    });
+    let plain = write_frame(TransportConfig::default(), b"test");
+    assert_eq!(
+        reader.feed(&plain),
+        [TransportOutput::Packet(Bytes::from("test"))]
+    );
+}
+
+#[test]
+fn test_reader_terminators() {
+    let config = TransportConfig::osc(5379, Terminator::St);
+    assert_eq!(config.prefix, b"\x1b]5379;SHIFT;");
+    let mut input = write_frame(config.clone(), b"st");
+    input.extend(b"\x1b]5379;SHIFT;YmVs\x07");
+
+    // Split anywhere, even within a terminator
+    let mut reader = TransportReader::new(config);
+    let result = input
+        .iter()
+        .flat_map(|x| reader.feed(&[*x]))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        result,
+        [
+            TransportOutput::Packet(Bytes::from("st")),
+            TransportOutput::Packet(Bytes::from("bel")),
+        ]
+    );
+}
+
+#[test]
+fn test_reader_accepted_framings() {
+    let config = TransportConfig::from_options(5379, Terminator::Bel, &[1337, 5379]);
+    assert_eq!(config.accepted_prefixes.len(), 1);
+
+    let mut input = write_frame(TransportConfig::default(), b"old");
+    input.extend(b"\x1b]1338;SHIFT;dGVzdA==\x07");
+    input.extend(write_frame(config.clone(), b"new"));
+    let mut reader = TransportReader::new(config);
+    let result = reader.feed(&input);
+    assert_eq!(
+        result,
+        [
+            TransportOutput::Packet(Bytes::from("old")),
+            TransportOutput::Passthrough(Bytes::from("\x1b]1338;SHIFT;dGVzdA==\x07")),
+            TransportOutput::Packet(Bytes::from("new")),
+        ]
+    );
+
+    assert_eq!("st".parse::<Terminator>().unwrap(), Terminator::St);
+    assert!("esc".parse::<Terminator>().is_err());
+}
+
+#[test]
+fn test_encodings_round_trip() {
+    let data = (0..=255u8).chain((0..=255u8).rev()).collect::<Vec<_>>();
+    for encoding in ENCODINGS {
+        for length in (0..9).chain([255, 512]) {
+            let encoded = encoding.encode(&data[..length]);
+            assert!(!encoded.iter().any(|x| [0x07, 0x1b].contains(x)));
+            assert_eq!(encoding.decode(&encoded).unwrap(), &data[..length]);
+        }
+
+        let config = TransportConfig::default();
+        let mut writer_output = vec![];
+        for packet in [&b"first"[..], &data] {
+            writer_output.extend(write_frame_with(config.clone(), *encoding, packet));
+        }
+        let mut reader = TransportReader::new(config);
+        assert_eq!(
+            reader.feed(&writer_output),
+            [
+                TransportOutput::Packet(Bytes::from("first")),
+                TransportOutput::Packet(Bytes::from(data.clone())),
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_encodings_format() {
+    // The reference vector from the Z85 specification
+    let data = [0x86, 0x4F, 0xD2, 0x6F, 0xB5, 0x59, 0xF7, 0x5B];
+    assert_eq!(Z85.encode(&data), b"HelloWorld");
+    assert_eq!(Z85.encode(&data[..5]).len(), 7);
+    assert!(Z85.decode(b"Hello\"").is_none());
+    assert!(Z85.decode(b"HelloW").is_none());
+
+    assert_eq!(YEnc.encode(b"\x00\x13\xdd"), b"*=}=\x47");
+    assert!(YEnc.decode(b"*\x07").is_none());
+
+    let dense = YEnc.encode(&[7; 3000]).len();
+    assert!(dense < Z85.encode(&[7; 3000]).len());
+    assert!(Z85.encode(&[7; 3000]).len() < Base64.encode(&[7; 3000]).len());
+}
+
+#[cfg(test)]
+fn passthrough_and_packets(result: &[TransportOutput]) -> (Vec<u8>, Vec<Bytes>) {
+    let mut passthrough = vec![];
+    let mut packets = vec![];
+    for output in result {
+        match output {
+            TransportOutput::Passthrough(x) => passthrough.extend_from_slice(x),
+            TransportOutput::Packet(x) => packets.push(x.clone()),
+        }
+    }
+    (passthrough, packets)
+}
+
+#[test]
+fn test_reader_abandons_invalid_frames() {
+    // A binary that happens to contain a prefix
+    let mut garbage = b"\x1b]1337;SHIFT;ab\x00\xff".to_vec();
+    garbage.extend(b"\x1b]1337;SHIFT;~\x1b[0m");
+    let mut input = garbage.clone();
+    input.extend(write_frame(TransportConfig::default(), b"test"));
+
+    let mut reader = TransportReader::new(TransportConfig::default());
+    let result = input
+        .iter()
+        .flat_map(|x| reader.feed(&[*x]))
+        .collect::<Vec<_>>();
+    let (passthrough, packets) = passthrough_and_packets(&result);
+    assert_eq!(passthrough, garbage);
+    assert_eq!(packets, [Bytes::from("test")]);
+    assert_eq!(reader.counters().abandoned(), 2);
+    assert_eq!(reader.counters().packets(), 1);
+
+    // Complete frames that do not decode are dropped
+    assert!(reader.feed(b"\x1b]1337;SHIFT;a\x07").is_empty());
+    assert_eq!(reader.counters().undecodable(), 1);
+}
+
+#[test]
+fn test_reader_bounded_payload() {
+    let config = TransportConfig {
+        max_payload_length: 16,
+        ..Default::default()
+    };
+    let counters = std::sync::Arc::new(TransportCounters::default());
+    let mut reader = TransportReader::new(config.clone());
+    reader.set_counters(counters.clone());
+
+    let mut input = TransportConfig::default().prefix;
+    input.extend(vec![b'A'; 1000]);
+    let mut result = vec![];
+    for piece in input.chunks(7) {
+        result.extend(reader.feed(piece));
+    }
+    let (passthrough, packets) = passthrough_and_packets(&result);
+    assert_eq!(passthrough, input);
+    assert!(packets.is_empty());
+    assert_eq!(counters.abandoned(), 1);
+
+    // Frames within the limit still get through
+    let frame = write_frame(config, b"0123456789");
+    assert_eq!(
+        reader.feed(&frame),
+        [TransportOutput::Packet(Bytes::from("0123456789"))]
+    );
+}
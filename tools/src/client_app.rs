@@ -1,27 +1,111 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use cancellation::*;
 use clap::{self, AppSettings, Parser, Subcommand};
-use indicatif::{ProgressBar, ProgressStyle};
+use colored::*;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use path_clean::PathClean;
+use shift::api::file_info::FileType;
 use shift::pty::{enable_raw_mode, restore_mode};
-use shift::{api, MessageWriter, ShiftClient, TransportWriter, TRANSPORT};
-use shift_fileclient::{ShiftFileClient, ShiftFileClientDelegate};
-use std::io;
+use shift::secure::{generate_identity, SecureConfig};
+use shift::{
+    api, DisconnectReason, MessageWriter, OpenFile, ShiftClient, Terminator, Timeouts,
+    TransportConfig, TransportWriter, Wrapping,
+};
+use shift_fileclient::{
+    preflight, AttributeFilter, ShiftFileClient, ShiftFileClientDelegate, SymlinkPolicy,
+};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Ctrl-P pauses and resumes transfers
+const PAUSE_KEY: u8 = 0x10;
 
 #[derive(Subcommand, Debug, PartialEq)]
 enum Commands {
-    /// Send files or directories
+    /// Send files or directories, or stdin given as `-`
     Send {
         #[clap(multiple_values = true)]
         paths: Vec<String>,
+
+        /// Name to send stdin under
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Suggest a directory for the receiver to put everything in, relative to its own
+        #[clap(long)]
+        to: Option<String>,
+
+        /// Label what is sent for the receiver
+        #[clap(long)]
+        tag: Vec<String>,
+
+        /// How to send symbolic links: preserve, follow or skip
+        #[clap(long, default_value = "preserve")]
+        symlinks: SymlinkPolicy,
     },
-    /// Receive files or directories
+    /// Receive files or directories, or a single file to stdout with `-`
     Receive {
         #[clap(multiple_values = true)]
         paths: Vec<String>,
+
+        /// Write a checksum manifest of the received files
+        #[clap(long)]
+        manifest: Option<String>,
+
+        /// Give received files the sender's owner and group (requires root)
+        #[clap(long)]
+        preserve_ownership: bool,
+
+        /// Skip incoming files and directories whose name matches this glob, such as `node_modules`
+        #[clap(long)]
+        exclude: Vec<String>,
     },
+    /// Receive specific files or directories from the other side
+    Get {
+        /// Paths or glob patterns relative to the other side's shared directory
+        #[clap(required = true, multiple_values = true)]
+        patterns: Vec<String>,
+
+        /// Write a checksum manifest of the received files
+        #[clap(long)]
+        manifest: Option<String>,
+
+        /// Give received files the sender's owner and group (requires root)
+        #[clap(long)]
+        preserve_ownership: bool,
+
+        /// Skip incoming files and directories whose name matches this glob, such as `node_modules`
+        #[clap(long)]
+        exclude: Vec<String>,
+    },
+    /// List what the other side offers
+    Ls {
+        /// Path relative to the other side's shared directory
+        #[clap(default_value = "")]
+        path: String,
+    },
+    /// Generate an identity for pinned-key sessions and print its public key
+    Keygen {
+        /// Where to write the private identity
+        identity: String,
+    },
+}
+
+impl Commands {
+    /// Whether stdin or stdout carry a stream rather than messages
+    fn uses_stdio(&self) -> bool {
+        match self {
+            Commands::Send { paths, .. } | Commands::Receive { paths, .. } => {
+                paths.iter().any(|x| x == "-")
+            }
+            _ => false,
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -31,70 +115,281 @@ enum Commands {
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Authenticate and encrypt the session with a pre-shared secret read from this file
+    #[clap(long, global = true)]
+    secret_file: Option<String>,
+
+    /// Authenticate and encrypt the session with this identity (see `keygen`)
+    #[clap(long, global = true, requires = "pin")]
+    identity: Option<String>,
+
+    /// Public key of the peer's identity, in hex
+    #[clap(long, global = true, requires = "identity")]
+    pin: Option<String>,
+
+    /// Extended attributes to transfer, such as `security.*` (default: `user.*` and POSIX ACLs)
+    #[clap(long, global = true)]
+    xattr_allow: Vec<String>,
+
+    /// Extended attributes never to transfer
+    #[clap(long, global = true)]
+    xattr_deny: Vec<String>,
+
+    /// Give up when the peer stops answering keepalive pings for this many seconds, 0 to never
+    #[clap(long, default_value = "30", global = true)]
+    idle_timeout: u64,
+
+    /// Give up when a transfer makes no progress for this many seconds
+    #[clap(long, global = true)]
+    transfer_timeout: Option<u64>,
+
+    /// OSC number to frame messages with, for terminals where 1337 is taken
+    #[clap(long, global = true, env = "SHIFT_OSC", default_value = "1337")]
+    osc: u32,
+
+    /// What ends a frame: bel, or st for `ESC \\`. Frames ending in either are read.
+    #[clap(long, global = true, env = "SHIFT_TERMINATOR", default_value = "bel")]
+    terminator: Terminator,
+
+    /// Also read frames with this OSC number, such as one a peer still uses
+    #[clap(long, global = true)]
+    accept_osc: Vec<u32>,
+
+    /// The link passes every byte, so denser eight-bit encodings can be negotiated
+    #[clap(long, global = true)]
+    eight_bit_clean: bool,
+}
+
+/// Receives what arrives besides messages, which is whatever is typed on the other side, and
+/// watches it for the pause key
+struct KeyWatcher(Arc<AtomicBool>);
+
+impl io::Write for KeyWatcher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.contains(&PAUSE_KEY) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Progress of an `ls`, which looks at the path first and then lists it page by page
+struct Listing {
+    path: String,
+    started: bool,
+    next_offset: Option<u64>,
+    failed: bool,
 }
 
 struct App<'a> {
     send_mode: bool,
+    /// Name to send stdin under
+    stream_name: Option<String>,
+    /// Whether the file received goes to stdout, and whether it arrived already
+    to_stdout: bool,
+    stdout_taken: bool,
+    uses_stdio: bool,
     remaining_receives: u32,
+    /// Whether `paths` name what to receive rather than what to send
+    selecting: bool,
+    listing: Option<Listing>,
+    /// Names of inbound files to skip
+    exclude: Vec<glob::Pattern>,
 
     paths: Vec<String>,
+    progress_bar: Option<ProgressBar>,
 
     client: Arc<Mutex<ShiftFileClient<'a>>>,
     cancellation_token_source: CancellationTokenSource,
-    current_inbound_transfer: Option<api::SendRequest>,
+    inbound_transfers: HashMap<u32, api::SendRequest>,
+    latency: Option<Duration>,
+    pause_requested: Arc<AtomicBool>,
+    restore_terminal: Box<dyn Fn() + Send>,
+    /// Set when the peer stopped answering
+    timed_out: bool,
 }
 
 impl<'a> App<'a> {
-    pub fn new(args: Cli) -> Self {
+    pub fn new(args: Cli, restore_terminal: Box<dyn Fn() + Send>) -> Result<Self> {
         let _paths;
         let send_mode;
+        let mut listing = None;
+        let mut selecting = false;
+        let mut exclude = vec![];
+        let mut stream_name = None;
+        let mut to_stdout = false;
+        let uses_stdio = args.command.uses_stdio();
+        let security = SecureConfig::from_options(
+            args.secret_file.as_deref().map(Path::new),
+            args.identity.as_deref().map(Path::new),
+            args.pin.as_deref(),
+        )?;
+        let pause_requested = Arc::new(AtomicBool::new(false));
+        let mut client = ShiftFileClient::with_transport(
+            message_output(uses_stdio)?,
+            Some(Box::new(KeyWatcher(pause_requested.clone()))),
+            security,
+            transport(&args),
+        );
+        client.set_attribute_filter(AttributeFilter::from_options(
+            args.xattr_allow,
+            args.xattr_deny,
+        ));
+        client.set_timeouts(Timeouts::from_options(
+            args.idle_timeout,
+            args.transfer_timeout,
+        ));
         match args.command {
-            Commands::Send { paths } => {
+            Commands::Send {
+                paths,
+                name,
+                to,
+                tag,
+                symlinks,
+            } => {
+                if paths.iter().any(|x| x == "-") && name.is_none() {
+                    return Err(anyhow!("Sending stdin needs a --name"));
+                }
+                stream_name = name;
                 _paths = paths;
                 send_mode = true;
+                client.set_symlink_policy(symlinks);
+                client.set_destination_hint(to.unwrap_or_default(), tag);
             }
-            Commands::Receive { paths } => {
+            Commands::Receive {
+                paths,
+                manifest,
+                preserve_ownership,
+                exclude: excluded,
+            } => {
+                to_stdout = paths.iter().any(|x| x == "-");
                 _paths = paths;
+                exclude = parse_patterns(&excluded)?;
+                send_mode = false;
+                client.set_preserve_ownership(preserve_ownership);
+                if let Some(manifest) = manifest {
+                    client.set_manifest(Box::new(File::create(manifest)?));
+                }
+            }
+            Commands::Get {
+                patterns,
+                manifest,
+                preserve_ownership,
+                exclude: excluded,
+            } => {
+                _paths = patterns;
+                exclude = parse_patterns(&excluded)?;
+                send_mode = false;
+                selecting = true;
+                client.set_preserve_ownership(preserve_ownership);
+                if let Some(manifest) = manifest {
+                    client.set_manifest(Box::new(File::create(manifest)?));
+                }
+            }
+            Commands::Ls { path } => {
+                _paths = vec![];
                 send_mode = false;
+                listing = Some(Listing {
+                    path,
+                    started: false,
+                    next_offset: None,
+                    failed: false,
+                });
             }
+            Commands::Keygen { .. } => unreachable!(),
         }
-        Self {
+        Ok(Self {
             send_mode,
+            stream_name,
+            to_stdout,
+            stdout_taken: false,
+            uses_stdio,
             paths: _paths,
+            progress_bar: None,
             remaining_receives: 1,
-            client: Arc::new(Mutex::new(ShiftFileClient::new(
-                Box::new(io::stdout()),
-                None,
-            ))),
+            selecting,
+            listing,
+            exclude,
+            client: Arc::new(Mutex::new(client)),
             cancellation_token_source: CancellationTokenSource::new(),
-            current_inbound_transfer: None,
+            inbound_transfers: HashMap::new(),
+            latency: None,
+            pause_requested,
+            restore_terminal,
+            timed_out: false,
+        })
+    }
+
+    /// A bar for `length` bytes, or a spinner if the length is unknown
+    fn new_progress_bar(&mut self, length: Option<u64>) -> ProgressBar {
+        let bar = match length {
+            Some(length) => ProgressBar::new(length).with_style(ProgressStyle::default_bar()
+                .template("{bar:20.cyan/blue} {wide_msg} {bytes}/{total_bytes}  ETA {eta_precise}  {bytes_per_sec:10} {prefix}")),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner:.cyan} {wide_msg} {bytes}  {bytes_per_sec:10} {prefix}"),
+            ),
+        };
+        if let Some(latency) = self.latency {
+            bar.set_prefix(format_latency(latency));
         }
+        self.progress_bar = Some(bar.clone());
+        bar
     }
 
-    pub fn run(mut self) -> Result<()> {
+    fn report(&self, message: String) {
+        match &self.progress_bar {
+            Some(bar) => bar.println(message),
+            None => eprint!("{}\r\n", message),
+        }
+    }
+
+    /// Returns whether the session timed out
+    pub fn run(mut self) -> Result<bool> {
         let token = self.cancellation_token_source.token().clone();
         let client = self.client.clone();
-        let mut stdin = io::stdin();
-        client
-            .lock()
-            .unwrap()
-            .run(true, &mut stdin, &mut self, &token)?;
-        Ok(())
+        let input = message_input(self.uses_stdio)?;
+        client.lock().unwrap().run(true, input, &mut self, &token)?;
+        Ok(self.timed_out)
     }
 }
 
 impl<'a> ShiftFileClientDelegate<'a> for App<'a> {
     fn on_idle(&mut self, client: &mut ShiftFileClient<'a>) -> Result<()> {
-        if self.send_mode {
+        if let Some(listing) = &mut self.listing {
+            if !listing.started {
+                listing.started = true;
+                client.stat(&listing.path)?;
+            } else if let Some(offset) = listing.next_offset {
+                client.list_directory(&listing.path, offset)?;
+            } else {
+                client.disconnect()?;
+                std::process::exit(if listing.failed { 1 } else { 0 });
+            }
+        } else if self.send_mode {
             if self.paths.is_empty() {
                 client.disconnect()?;
                 std::process::exit(0);
             }
             let path_str = self.paths.remove(0);
+            if path_str == "-" {
+                let name = self.stream_name.clone().unwrap_or_default();
+                let bar = self.new_progress_bar(None);
+                bar.set_message(name.clone());
+                client.send_stream(
+                    &name,
+                    Box::new(io::stdin()),
+                    Box::new(move |_, sent, _| bar.set_position(sent)),
+                )?;
+                return Ok(());
+            }
             let path = Path::new(&path_str).canonicalize()?;
-            let bar = ProgressBar::new(1);
-            bar.set_style(ProgressStyle::default_bar()
-                .template("{bar:20.cyan/blue} {wide_msg} {bytes}/{total_bytes}  ETA {eta_precise}  {bytes_per_sec:10}"));
+            let bar = self.new_progress_bar(Some(1));
             bar.set_message("Preparing");
             client.send(
                 &path,
@@ -114,7 +409,16 @@ impl<'a> ShiftFileClientDelegate<'a> for App<'a> {
             )?;
         } else if self.remaining_receives > 0 {
             self.remaining_receives -= 1;
-            client.receive()?;
+            if self.selecting {
+                let (patterns, paths) = self
+                    .paths
+                    .iter()
+                    .cloned()
+                    .partition(|x| x.contains(['*', '?', '[']));
+                client.receive_selected(paths, patterns)?;
+            } else {
+                client.receive()?;
+            }
         } else {
             client.disconnect()?;
             std::process::exit(0);
@@ -122,63 +426,404 @@ impl<'a> ShiftFileClientDelegate<'a> for App<'a> {
         Ok(())
     }
 
+    fn on_file_verified(&mut self, verification: &api::FileVerification) {
+        if verification.verified {
+            return;
+        }
+        self.report(format!(
+            "{} {}",
+            "Integrity check failed:".red(),
+            verification.name
+        ));
+    }
+
+    fn on_file_rejected(&mut self, file: &OpenFile) {
+        self.report(format!(
+            "{} {}",
+            "Skipped by the receiver:".yellow(),
+            file.info.name
+        ));
+    }
+
+    fn on_peer_error(&mut self, error: &api::Error) {
+        if error.request_id != 0 {
+            if let Some(listing) = &mut self.listing {
+                listing.next_offset = None;
+                listing.failed = true;
+            }
+            self.report(format!("{} {}", "Request failed:".red(), error));
+            return;
+        }
+        self.report(format!("{} {}", "Transfer failed:".red(), error));
+    }
+
+    fn on_stat_result(&mut self, result: &api::StatResult) {
+        let listing = match &mut self.listing {
+            Some(listing) => listing,
+            None => return,
+        };
+        let message = match &result.file_info {
+            Some(info) if info.file_type() == FileType::Directory => {
+                listing.next_offset = Some(0);
+                return;
+            }
+            Some(info) => format_entry(info),
+            None => {
+                listing.failed = true;
+                format!("{} {}", "Not found:".red(), listing.path)
+            }
+        };
+        self.report(message);
+    }
+
+    fn on_directory_listing(&mut self, listing: &api::DirectoryListing) {
+        for entry in &listing.entries {
+            self.report(format_entry(entry));
+        }
+        if let Some(state) = &mut self.listing {
+            state.next_offset = match listing.more {
+                true => Some(listing.offset + listing.entries.len() as u64),
+                false => None,
+            };
+        }
+    }
+
+    fn on_warning(&mut self, message: &str) {
+        self.report(message.yellow().to_string());
+    }
+
+    fn on_latency(&mut self, latency: Duration) {
+        self.latency = Some(latency);
+        if let Some(bar) = &self.progress_bar {
+            bar.set_prefix(format_latency(latency));
+        }
+    }
+
+    fn on_disconnected(&mut self, reason: &DisconnectReason) {
+        if matches!(
+            reason,
+            DisconnectReason::IdleTimeout | DisconnectReason::TransferTimeout
+        ) {
+            (self.restore_terminal)();
+            // The terminal may be as stuck as the peer, so do not wait on it for long
+            if let Some(bar) = self.progress_bar.take() {
+                bar.set_draw_target(ProgressDrawTarget::hidden());
+            }
+            let message = reason.to_string().red().to_string();
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                eprint!("{}\r\n", message);
+                let _ = tx.send(());
+            });
+            let _ = rx.recv_timeout(Duration::from_secs(1));
+            self.timed_out = true;
+        }
+    }
+
     fn on_inbound_transfer_request(&mut self, request: &api::SendRequest) -> bool {
         if self.send_mode {
             return false;
         }
-        self.current_inbound_transfer = Some(request.clone());
+        self.inbound_transfers
+            .insert(request.transfer_id, request.clone());
         true
     }
 
+    fn on_transfer_manifest(&mut self, manifest: &api::TransferManifest) -> Result<()> {
+        if self.to_stdout {
+            if manifest.entries.len() != 1 || !manifest.entries.iter().all(is_file) {
+                let error = anyhow!("Only a single file can be written to stdout");
+                self.report(format!("{} {}", "Refusing transfer:".red(), error));
+                return Err(error);
+            }
+            let entry = &manifest.entries[0];
+            let bar = self.new_progress_bar(Some(entry.size).filter(|_| !entry.size_unknown));
+            bar.set_message("stdout");
+            return Ok(());
+        }
+        let name = self
+            .inbound_transfers
+            .get(&manifest.transfer_id)
+            .and_then(|x| x.file_info.as_ref())
+            .map(|x| x.name.clone())
+            .ok_or(anyhow!("No active transfer"))?;
+        let result = preflight(&PathBuf::from(&name).clean(), manifest).inspect_err(|error| {
+            self.report(format!("{} {}", "Refusing transfer:".red(), error));
+        })?;
+        let bar = self.new_progress_bar(Some(manifest.total_bytes));
+        bar.set_message(name);
+        if !result.existing.is_empty() {
+            self.report(
+                format!(
+                    "{} files exist already and will be resumed or updated",
+                    result.existing.len()
+                )
+                .yellow()
+                .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn on_inbound_progress(&mut self, _transfer_id: u32, received_bytes: u64) {
+        if let Some(bar) = &self.progress_bar {
+            bar.set_position(received_bytes);
+        }
+    }
+
+    fn inbound_file_stream(&mut self, _file: &api::OpenFile) -> Option<Box<dyn io::Write + Send>> {
+        if !self.to_stdout || std::mem::replace(&mut self.stdout_taken, true) {
+            return None;
+        }
+        Some(Box::new(io::stdout()))
+    }
+
     fn on_inbound_transfer_file(&mut self, file: &api::OpenFile) -> Result<Option<PathBuf>> {
         if self.send_mode {
             return Ok(None);
         }
-        // TODO path check
+        if self.to_stdout {
+            return Err(anyhow!("Only a single file can be written to stdout"));
+        }
         let transfer = self
-            .current_inbound_transfer
-            .clone()
+            .inbound_transfers
+            .get(&file.transfer_id)
+            .cloned()
             .ok_or(anyhow!("No active transfer"))?;
         let transfer_info = &transfer.file_info.ok_or(anyhow!("Missing file info"))?;
-        let rel_path = Path::new(&transfer_info.name)
-            .join(
-                file.file_info
-                    .clone()
-                    .ok_or(anyhow!("Missing file info in request"))?
-                    .name,
-            )
-            .clean();
+        let name = file
+            .file_info
+            .clone()
+            .ok_or(anyhow!("Missing file info in request"))?
+            .name;
+        let excluded = Path::new(&name).components().any(|x| {
+            let component = x.as_os_str().to_string_lossy();
+            self.exclude
+                .iter()
+                .any(|pattern| pattern.matches(&component))
+        });
+        if excluded {
+            return Ok(None);
+        }
+        let rel_path = Path::new(&transfer_info.name).join(name).clean();
         Ok(Some(rel_path))
     }
+
+    fn on_tick(&mut self, client: &mut ShiftFileClient<'a>) -> Result<()> {
+        if self.pause_requested.swap(false, Ordering::Relaxed) && client.has_active_transfers() {
+            match client.toggle_pause() {
+                Ok(true) => self.report("Paused, press Ctrl-P to resume".yellow().to_string()),
+                Ok(false) => self.report("Resumed".green().to_string()),
+                Err(error) => self.report(format!("{} {}", "Cannot pause:".red(), error)),
+            }
+        }
+        Ok(())
+    }
+
+    fn on_transfer_paused(&mut self, _transfer_id: u32) {
+        self.report("Paused by the other side".yellow().to_string());
+    }
+
+    fn on_transfer_resumed(&mut self, _transfer_id: u32) {
+        self.report("Resumed by the other side".green().to_string());
+    }
+
+    fn on_transfer_closed(&mut self, transfer_id: u32) {
+        self.inbound_transfers.remove(&transfer_id);
+        if let Some(bar) = self.progress_bar.take() {
+            bar.finish();
+        }
+    }
+}
+
+/// Frames wrapped for the multiplexer the client runs in, if any, so that they get through it
+fn transport(args: &Cli) -> TransportConfig {
+    TransportConfig {
+        wrapping: Wrapping::from_environment(),
+        eight_bit_clean: args.eight_bit_clean,
+        ..TransportConfig::from_options(args.osc, args.terminator, &args.accept_osc)
+    }
+}
+
+fn is_file(info: &api::FileInfo) -> bool {
+    info.file_type() == FileType::Regular
+}
+
+#[cfg(target_family = "unix")]
+const TERMINAL: &str = "/dev/tty";
+#[cfg(target_family = "windows")]
+const TERMINAL: &str = "CON";
+
+fn open_terminal() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(TERMINAL)
+        .with_context(|| "Streams need a terminal to exchange messages through")
+}
+
+#[cfg(target_family = "unix")]
+fn terminal_fd(terminal: Option<&File>) -> std::os::unix::io::RawFd {
+    use std::os::unix::io::AsRawFd;
+    terminal.map(|x| x.as_raw_fd()).unwrap_or(0)
+}
+
+#[cfg(target_family = "windows")]
+fn terminal_fd(_terminal: Option<&File>) -> u32 {
+    0
+}
+
+/// Messages normally arrive on stdin. When stdin or stdout carry a stream, they use the
+/// terminal itself.
+fn message_input(uses_stdio: bool) -> Result<Box<dyn io::Read + Send>> {
+    Ok(match uses_stdio {
+        true => Box::new(open_terminal()?),
+        false => Box::new(io::stdin()),
+    })
+}
+
+fn message_output(uses_stdio: bool) -> Result<Box<dyn io::Write + Send>> {
+    Ok(match uses_stdio {
+        true => Box::new(open_terminal()?),
+        false => Box::new(io::stdout()),
+    })
+}
+
+fn parse_patterns(patterns: &[String]) -> Result<Vec<glob::Pattern>> {
+    Ok(patterns
+        .iter()
+        .map(|x| glob::Pattern::new(x))
+        .collect::<Result<_, _>>()?)
+}
+
+fn format_latency(latency: Duration) -> String {
+    format!("RTT {}ms", latency.as_millis())
+}
+
+/// Formats an entry like `ls -l` does, with times in UTC
+fn format_entry(info: &api::FileInfo) -> String {
+    let kind = match (info.file_type(), info.mode & 0o170000) {
+        (FileType::Directory, _) => 'd',
+        (FileType::Symlink, _) => 'l',
+        (_, 0o010000) => 'p',
+        (_, 0o140000) => 's',
+        (_, 0o020000) => 'c',
+        (_, 0o060000) => 'b',
+        _ => '-',
+    };
+    let permissions: String = (0..9)
+        .map(|i| match info.mode & (0o400 >> i) {
+            0 => '-',
+            _ => ['r', 'w', 'x'][i % 3],
+        })
+        .collect();
+    let name = match info.file_type() {
+        FileType::Directory => format!("{}/", info.name).blue().to_string(),
+        FileType::Symlink => format!("{} -> {}", info.name.cyan(), info.link_target),
+        FileType::Regular => info.name.clone(),
+    };
+    format!(
+        "{}{} {:>12} {} {}",
+        kind,
+        permissions,
+        info.size,
+        format_time(info.mtime),
+        name
+    )
+}
+
+fn format_time(nanos: i64) -> String {
+    let seconds = nanos.div_euclid(1_000_000_000);
+    let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60
+    )
+}
+
+fn keygen(path: &Path) -> Result<()> {
+    let (identity, public_key) = generate_identity();
+    // Only readable by the owner, and never in place of an existing identity
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .with_context(|| format!("Cannot create the identity {}", path.display()))?
+        .write_all((identity + "\n").as_bytes())?;
+    println!("{}", public_key);
+    Ok(())
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    if let Commands::Keygen { identity } = &cli.command {
+        return keygen(Path::new(identity));
+    }
 
-    let old_mode = enable_raw_mode(0).ok();
-    let abort = move || {
+    let uses_stdio = cli.command.uses_stdio();
+    let terminal = match uses_stdio {
+        true => Some(open_terminal()?),
+        false => None,
+    };
+    let fd = terminal_fd(terminal.as_ref());
+    let old_mode = enable_raw_mode(fd).ok();
+    let restore_terminal = move || {
         if let Some(old_mode) = old_mode {
-            restore_mode(0, old_mode).expect("Failed to restore TTY mode");
+            restore_mode(fd, old_mode).expect("Failed to restore TTY mode");
+        }
+    };
+    let transport = transport(&cli);
+    let abort = move || {
+        restore_terminal();
+        if let Ok(output) = message_output(uses_stdio) {
+            let _ = ShiftClient::new(MessageWriter::new(TransportWriter::new(
+                transport.clone(),
+                output,
+            )))
+            .disconnect();
         }
-        let _ = ShiftClient::new(MessageWriter::new(TransportWriter::new(
-            TRANSPORT,
-            Box::new(std::io::stdout()),
-        )))
-        .disconnect();
     };
 
-    ctrlc::set_handler(move || {
-        abort();
-        std::process::exit(1);
+    ctrlc::set_handler({
+        let abort = abort.clone();
+        move || {
+            abort();
+            std::process::exit(1);
+        }
     })?;
 
-    App::new(cli).run().unwrap_or_else(|e| {
-        abort();
-        panic!("{}", e);
-    });
+    let timed_out = App::new(cli, Box::new(restore_terminal))?
+        .run()
+        .unwrap_or_else(|e| {
+            abort();
+            panic!("{}", e);
+        });
 
     if let Some(old_mode) = old_mode {
-        restore_mode(0, old_mode)?;
+        restore_mode(fd, old_mode)?;
+    }
+    if timed_out {
+        std::process::exit(1);
     }
     Ok(())
 }
@@ -4,15 +4,28 @@ use clap::{self, Parser};
 use colored::*;
 use path_clean::PathClean;
 use portable_pty::{native_pty_system, CommandBuilder, PtyPair, PtySize};
+use std::collections::HashMap;
+use std::fs::File;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use terminal_size::{terminal_size, Height, Width};
 
-use shift::api;
 use shift::pty::{enable_raw_mode, restore_mode};
-use shift_fileclient::{ShiftFileClient, ShiftFileClientDelegate};
+use shift::secure::SecureConfig;
+use shift::{
+    api, DisconnectReason, OpenFile, Terminator, Timeouts, TransportConfig, TransportCounters,
+};
+use shift_fileclient::{
+    preflight, resolve_destination, resolve_selection, AttributeFilter, ShiftFileClient,
+    ShiftFileClientDelegate, SymlinkPolicy,
+};
+
+/// Ctrl-P pauses and resumes transfers. It only reaches the command while nothing is being
+/// transferred.
+const PAUSE_KEY: u8 = 0x10;
 
 #[derive(Parser, Debug)]
 #[clap(version)]
@@ -20,6 +33,62 @@ pub struct Cli {
     #[clap(short, long)]
     directory: String,
 
+    /// Write a checksum manifest of the received files
+    #[clap(long)]
+    manifest: Option<String>,
+
+    /// Give received files the sender's owner and group (requires root)
+    #[clap(long)]
+    preserve_ownership: bool,
+
+    /// How to send symbolic links: preserve, follow or skip
+    #[clap(long, default_value = "preserve")]
+    symlinks: SymlinkPolicy,
+
+    /// Extended attributes to transfer, such as `security.*` (default: `user.*` and POSIX ACLs)
+    #[clap(long)]
+    xattr_allow: Vec<String>,
+
+    /// Extended attributes never to transfer
+    #[clap(long)]
+    xattr_deny: Vec<String>,
+
+    /// Give up when the peer stops answering keepalive pings for this many seconds, 0 to never
+    #[clap(long, default_value = "30")]
+    idle_timeout: u64,
+
+    /// Give up when a transfer makes no progress for this many seconds
+    #[clap(long)]
+    transfer_timeout: Option<u64>,
+
+    /// Authenticate and encrypt the session with a pre-shared secret read from this file
+    #[clap(long)]
+    secret_file: Option<String>,
+
+    /// Authenticate and encrypt the session with this identity (see `client keygen`)
+    #[clap(long, requires = "pin")]
+    identity: Option<String>,
+
+    /// Public key of the peer's identity, in hex
+    #[clap(long, requires = "identity")]
+    pin: Option<String>,
+
+    /// OSC number to frame messages with, for terminals where 1337 is taken
+    #[clap(long, env = "SHIFT_OSC", default_value = "1337")]
+    osc: u32,
+
+    /// What ends a frame: bel, or st for `ESC \\`. Frames ending in either are read.
+    #[clap(long, env = "SHIFT_TERMINATOR", default_value = "bel")]
+    terminator: Terminator,
+
+    /// Also read frames with this OSC number, such as one a peer still uses
+    #[clap(long)]
+    accept_osc: Vec<u32>,
+
+    /// The link passes every byte, so denser eight-bit encodings can be negotiated
+    #[clap(long)]
+    eight_bit_clean: bool,
+
     #[clap(multiple_values = true)]
     args: Vec<String>,
 }
@@ -31,7 +100,13 @@ pub struct App<'a> {
     old_mode: termios::Termios,
     cancellation_token_source: CancellationTokenSource,
 
-    current_inbound_transfer: Option<api::SendRequest>,
+    /// Name of each inbound transfer and the directory it goes to
+    inbound_transfers: HashMap<u32, (String, PathBuf)>,
+    transferring: Arc<AtomicBool>,
+    pause_requested: Arc<AtomicBool>,
+    transport_counters: Arc<TransportCounters>,
+    /// Abandoned frames already reported
+    abandoned_frames: u64,
 }
 
 impl<'a> App<'a> {
@@ -54,6 +129,9 @@ impl<'a> App<'a> {
         println!("Starting {}", args.args[0]);
         let mut cmd = CommandBuilder::new(&args.args[0]);
         cmd.cwd(std::env::current_dir()?);
+        // The command gets a terminal of its own, outside of any multiplexer the host runs in
+        cmd.env_remove("TMUX");
+        cmd.env_remove("STY");
         cmd.args(&args.args[1..]);
 
         pty_pair
@@ -70,9 +148,13 @@ impl<'a> App<'a> {
             std::process::exit(1);
         })?;
 
+        let transferring = Arc::new(AtomicBool::new(false));
+        let pause_requested = Arc::new(AtomicBool::new(false));
         thread::spawn({
             let mut master = pty_pair.master.try_clone_writer().unwrap();
             let mut stdin = io::stdin();
+            let transferring = transferring.clone();
+            let pause_requested = pause_requested.clone();
             move || {
                 let mut buf = [0; 1024];
                 loop {
@@ -80,7 +162,12 @@ impl<'a> App<'a> {
                     if size == 0 {
                         break;
                     }
-                    if master.write(&buf[..size]).is_err() {
+                    let mut input = buf[..size].to_vec();
+                    if transferring.load(Ordering::Relaxed) && input.contains(&PAUSE_KEY) {
+                        pause_requested.store(true, Ordering::Relaxed);
+                        input.retain(|x| *x != PAUSE_KEY);
+                    }
+                    if master.write(&input).is_err() {
                         break;
                     }
                 }
@@ -88,16 +175,45 @@ impl<'a> App<'a> {
         });
 
         let writer = pty_pair.master.try_clone_writer()?;
+        let security = SecureConfig::from_options(
+            args.secret_file.as_deref().map(Path::new),
+            args.identity.as_deref().map(Path::new),
+            args.pin.as_deref(),
+        )?;
+        let mut client = ShiftFileClient::with_transport(
+            Box::new(writer),
+            Some(Box::new(io::stdout())),
+            security,
+            TransportConfig {
+                eight_bit_clean: args.eight_bit_clean,
+                ..TransportConfig::from_options(args.osc, args.terminator, &args.accept_osc)
+            },
+        );
+        if let Some(manifest) = args.manifest {
+            client.set_manifest(Box::new(File::create(manifest)?));
+        }
+        client.set_preserve_ownership(args.preserve_ownership);
+        client.set_symlink_policy(args.symlinks);
+        client.set_attribute_filter(AttributeFilter::from_options(
+            args.xattr_allow,
+            args.xattr_deny,
+        ));
+        client.set_timeouts(Timeouts::from_options(
+            args.idle_timeout,
+            args.transfer_timeout,
+        ));
+        let transport_counters = client.transport_counters();
         let mut _self = Self {
             pty: Some(pty_pair),
             work_dir: args.directory,
-            client: Arc::new(Mutex::new(ShiftFileClient::new(
-                Box::new(writer),
-                Some(Box::new(io::stdout())),
-            ))),
+            client: Arc::new(Mutex::new(client)),
             old_mode,
             cancellation_token_source: CancellationTokenSource::new(),
-            current_inbound_transfer: None,
+            inbound_transfers: HashMap::new(),
+            transferring,
+            pause_requested,
+            transport_counters,
+            abandoned_frames: 0,
         };
 
         Ok(_self)
@@ -107,9 +223,10 @@ impl<'a> App<'a> {
         let token = self.cancellation_token_source.token().clone();
         let client = self.client.clone();
         let pty = self.pty.take().expect("PTY not set");
-        let mut master = pty.master.try_clone_reader().unwrap();
+        let master = pty.master.try_clone_reader().unwrap();
 
         thread::spawn(move || {
+            #[allow(clippy::needless_borrows_for_generic_args)]
             for _ in &mut signal_hook::iterator::SignalsInfo::<
                 signal_hook::iterator::exfiltrator::SignalOnly,
             >::new(&vec![signal_hook::consts::signal::SIGWINCH])
@@ -122,7 +239,7 @@ impl<'a> App<'a> {
         client
             .lock()
             .unwrap()
-            .run(false, &mut master, &mut self, &token)?;
+            .run(false, master, &mut self, &token)?;
 
         Ok(())
     }
@@ -149,37 +266,106 @@ fn resize_pty(pty: &PtyPair) -> Result<()> {
 
 impl<'a> ShiftFileClientDelegate<'a> for App<'a> {
     fn on_inbound_transfer_request(&mut self, request: &api::SendRequest) -> bool {
-        self.current_inbound_transfer = Some(request.clone());
+        let name = match &request.file_info {
+            Some(info) => info.name.clone(),
+            None => return false,
+        };
+        if !request.tags.is_empty() {
+            println!("[host]: Tagged {}", request.tags.join(", "));
+        }
+        // The sender may only suggest a place within the working directory
+        let directory = match resolve_destination(Path::new(&self.work_dir), &request.destination) {
+            Ok(directory) => {
+                if !request.destination.is_empty() {
+                    println!("[host]: Receiving into {}", request.destination);
+                }
+                directory
+            }
+            Err(error) => {
+                println!(
+                    "[host]: {} {}",
+                    "Ignoring the suggested destination:".yellow(),
+                    error
+                );
+                PathBuf::from(&self.work_dir)
+            }
+        };
+        self.inbound_transfers
+            .insert(request.transfer_id, (name, directory));
         true
     }
 
+    fn on_transfer_manifest(&mut self, manifest: &api::TransferManifest) -> Result<()> {
+        let (name, directory) = self
+            .inbound_transfers
+            .get(&manifest.transfer_id)
+            .ok_or(anyhow!("No active transfer"))?;
+        let destination = directory.join(name).clean();
+        match preflight(&destination, manifest) {
+            Ok(result) => {
+                if manifest.entries.iter().any(|x| x.size_unknown) {
+                    println!("[host]: Receiving a stream of unknown size");
+                } else {
+                    println!(
+                        "[host]: Receiving {} files, {} bytes",
+                        manifest.file_count, manifest.total_bytes
+                    );
+                }
+                if !result.existing.is_empty() {
+                    println!(
+                        "[host]: {}",
+                        format!(
+                            "{} files exist already and will be resumed or updated",
+                            result.existing.len()
+                        )
+                        .yellow()
+                    );
+                }
+                Ok(())
+            }
+            Err(error) => {
+                println!("[host]: {} {}", "Refusing transfer:".red(), error);
+                Err(error)
+            }
+        }
+    }
+
     fn on_inbound_transfer_file(&mut self, file: &api::OpenFile) -> Result<Option<PathBuf>> {
-        let transfer = self.current_inbound_transfer.clone();
-        let rel_path = Path::new(
-            &transfer
-                .ok_or(anyhow!("No active transfer"))?
-                .file_info
-                .ok_or(anyhow!("Missing file info"))?
-                .name,
-        )
-        .join(
-            file.file_info
-                .clone()
-                .ok_or(anyhow!("Missing file info in request"))?
-                .name,
-        )
-        .clean();
-        let path = PathBuf::from(&self.work_dir).join(rel_path);
+        let (name, directory) = self
+            .inbound_transfers
+            .get(&file.transfer_id)
+            .ok_or(anyhow!("No active transfer"))?;
+        let rel_path = Path::new(name)
+            .join(
+                file.file_info
+                    .clone()
+                    .ok_or(anyhow!("Missing file info in request"))?
+                    .name,
+            )
+            .clean();
+        let path = directory.join(rel_path);
         std::fs::create_dir_all(path.parent().expect("Cannot handle root directory"))?;
         Ok(Some(path))
     }
 
     fn on_outbound_transfer_request(
         &mut self,
-        _request: &api::ReceiveRequest,
+        request: &api::ReceiveRequest,
         client: &mut ShiftFileClient<'a>,
     ) -> Result<()> {
         let path = Path::new(&self.work_dir);
+        if !request.paths.is_empty() || !request.patterns.is_empty() {
+            match resolve_selection(path, request) {
+                Ok(items) => {
+                    client.send_selection(path, &items, Box::new(|_, _, _| {}))?;
+                }
+                Err(error) => {
+                    println!("[host]: {} {}", "Not sending:".red(), error);
+                    client.refuse(request, &error)?;
+                }
+            }
+            return Ok(());
+        }
         let item = path.read_dir()?.next();
         match item {
             Some(item) => {
@@ -193,8 +379,85 @@ impl<'a> ShiftFileClientDelegate<'a> for App<'a> {
         Ok(())
     }
 
-    fn on_transfer_closed(&mut self) {
-        self.current_inbound_transfer = None;
+    fn browse_root(&mut self) -> Option<PathBuf> {
+        Some(PathBuf::from(&self.work_dir))
+    }
+
+    fn on_file_verified(&mut self, verification: &api::FileVerification) {
+        if verification.verified {
+            println!("[host]: {} {}", "Verified".green(), verification.name);
+        } else {
+            println!(
+                "[host]: {} {}",
+                "Integrity check failed:".red(),
+                verification.name
+            );
+        }
+    }
+
+    fn on_file_rejected(&mut self, file: &OpenFile) {
+        println!(
+            "[host]: {} {}",
+            "Skipped by the receiver:".yellow(),
+            file.info.name
+        );
+    }
+
+    fn on_peer_error(&mut self, error: &api::Error) {
+        println!("[host]: {} {}", "Transfer failed:".red(), error);
+    }
+
+    fn on_warning(&mut self, message: &str) {
+        println!("[host]: {}", message.yellow());
+    }
+
+    fn on_tick(&mut self, client: &mut ShiftFileClient<'a>) -> Result<()> {
+        self.transferring
+            .store(client.has_active_transfers(), Ordering::Relaxed);
+        if self.pause_requested.swap(false, Ordering::Relaxed) && client.has_active_transfers() {
+            match client.toggle_pause() {
+                Ok(true) => println!(
+                    "[host]: {}",
+                    "Transfers paused, press Ctrl-P to resume".yellow()
+                ),
+                Ok(false) => println!("[host]: {}", "Transfers resumed".green()),
+                Err(error) => println!("[host]: {} {}", "Cannot pause:".red(), error),
+            }
+        }
+        Ok(())
+    }
+
+    fn on_transfer_paused(&mut self, _transfer_id: u32) {
+        println!("[host]: {}", "Transfer paused by the peer".yellow());
+    }
+
+    fn on_transfer_resumed(&mut self, _transfer_id: u32) {
+        println!("[host]: {}", "Transfer resumed by the peer".green());
+    }
+
+    fn on_transfer_closed(&mut self, transfer_id: u32) {
+        self.inbound_transfers.remove(&transfer_id);
+    }
+
+    fn on_disconnected(&mut self, reason: &DisconnectReason) {
+        if matches!(
+            reason,
+            DisconnectReason::IdleTimeout | DisconnectReason::TransferTimeout
+        ) {
+            println!("[host]: {}", reason.to_string().red());
+        }
+        let abandoned = self.transport_counters.abandoned();
+        if abandoned > self.abandoned_frames {
+            println!(
+                "[host]: {}",
+                format!(
+                    "Malformed frames shown as output: {}",
+                    abandoned - self.abandoned_frames
+                )
+                .yellow()
+            );
+            self.abandoned_frames = abandoned;
+        }
     }
 
     fn on_disconnect(&mut self) -> Result<()> {